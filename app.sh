@@ -23,4 +23,10 @@ redis-server --port 6000 --daemonize yes --save "" --appendonly no
 redis-server --port 6001 --daemonize yes --save "" --appendonly no
 
 echo "starting server"
-/app/target/release/nearsay-server
\ No newline at end of file
+/app/target/release/nearsay-server &
+server_pid=$!
+
+# forward docker's SIGTERM so the server can shut down gracefully before mongod and redis are stopped
+trap 'kill -TERM $server_pid' TERM INT
+wait $server_pid
+wait $server_pid
\ No newline at end of file
@@ -1,3 +1,4 @@
+use geoutils::Location;
 use mongodb::bson::{doc, Document};
 use serde::{Serialize, Deserialize};
 
@@ -7,6 +8,12 @@ pub const WORLD_MAX_BOUND: f64 = 180.0;
 
 pub const MAX_TILE_LAYER: usize = 19;
 
+pub fn meters_between(lng1: f64, lat1: f64, lng2: f64, lat2: f64) -> f64 {
+    let loc1 = Location::new(lat1, lng1);
+    let loc2 = Location::new(lat2, lng2);
+    loc1.distance_to(&loc2).unwrap_or_else(|_| loc1.haversine_distance_to(&loc2) ).meters()
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Rect { pub top: f64, pub bottom: f64, pub left: f64, pub right: f64 }
 
@@ -19,12 +26,12 @@ impl Rect {
     /// - within world bounds
     pub fn valid_as_view(&self) -> bool {
         (self.top >= self.bottom && self.right >= self.left) &&     
-        (self.top > self.bottom || self.right > self.right)  &&         // either width/height must be >= 1
+        (self.top > self.bottom || self.right > self.left)  &&         // either width/height must be >= 1
         self.within_world_bounds()
     }
     
     pub fn within_world_bounds(&self) -> bool {
-        return self.left >= -WORLD_BOUND_X && self.right <= WORLD_BOUND_X && self.bottom >= -WORLD_BOUND_Y && self.top <= WORLD_BOUND_Y; 
+        self.left >= -WORLD_BOUND_X && self.right <= WORLD_BOUND_X && self.bottom >= -WORLD_BOUND_Y && self.top <= WORLD_BOUND_Y
     }
     
     pub fn as_geo_json(&self) -> Document {
@@ -0,0 +1,140 @@
+use std::io::Cursor;
+
+use image::{error::ImageError, DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use nearsay_server::NearsayError;
+use tracing::error;
+
+pub const MAX_ATTACHMENTS_PER_POST: usize = 4;
+
+/// images with a longer side are rejected before being decoded
+const MAX_IMAGE_SIDE: u32 = 8192;
+
+/// an uploaded image, re-encoded without its metadata
+pub struct ProcessedImage {
+    pub content_type: &'static str,
+    /// file extension of `image` and `thumbnail`
+    pub ext: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub image: Vec<u8>,
+    pub thumbnail: Vec<u8>,
+}
+
+/// decodes a jpeg, png, or webp, then re-encodes it and a thumbnail no bigger than `thumbnail_size` on each side.
+///
+/// re-encoding drops every piece of metadata, including EXIF location data. the EXIF orientation is applied to the pixels first so the image isn't sideways afterwards
+pub fn process_image(bytes: &[u8], thumbnail_size: u32) -> Result<ProcessedImage, NearsayError> {
+    let format = image::guess_format(bytes).map_err(|_| NearsayError::validation("file", "must be a jpeg, png, or webp image"))?;
+
+    // webp is stored as png, since `image` can only encode lossless webp
+    let (out_format, content_type, ext) = match format {
+        ImageFormat::Jpeg => (ImageFormat::Jpeg, "image/jpeg", "jpg"),
+        ImageFormat::Png | ImageFormat::WebP => (ImageFormat::Png, "image/png", "png"),
+        _ => return Err(NearsayError::validation("file", "must be a jpeg, png, or webp image")),
+    };
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_SIDE);
+    limits.max_image_height = Some(MAX_IMAGE_SIDE);
+
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits);
+
+    let mut img = reader.into_decoder()
+        .and_then(|mut decoder| {
+            let orientation = decoder.orientation()?;
+            let mut img = DynamicImage::from_decoder(decoder)?;
+            img.apply_orientation(orientation);
+            Ok(img)
+        })
+        .map_err(|img_err| match img_err {
+            ImageError::Limits(_) => NearsayError::validation("file", format!("must be at most {MAX_IMAGE_SIDE}px on each side")),
+            _ => NearsayError::validation("file", "couldn't be decoded"),
+        })?;
+
+    // jpegs can't have an alpha channel
+    if out_format == ImageFormat::Jpeg {
+        img = DynamicImage::ImageRgb8(img.to_rgb8());
+    }
+
+    // don't scale up images that are already small enough
+    let thumbnail = match img.width() > thumbnail_size || img.height() > thumbnail_size {
+        true => img.thumbnail(thumbnail_size, thumbnail_size),
+        false => img.clone(),
+    };
+
+    Ok(ProcessedImage {
+        content_type,
+        ext,
+        width: img.width(),
+        height: img.height(),
+        image: encode(&img, out_format)?,
+        thumbnail: encode(&thumbnail, out_format)?,
+    })
+}
+
+fn encode(img: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, NearsayError> {
+    let mut buf = Cursor::new(vec![]);
+    img.write_to(&mut buf, format).map_err(|img_err| {
+        error!(error = %img_err, "couldn't encode image");
+        NearsayError::Internal
+    })?;
+    Ok(buf.into_inner())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageFormat, RgbImage};
+    use nearsay_server::NearsayError;
+
+    use super::process_image;
+
+    fn encoded(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let mut buf = Cursor::new(vec![]);
+        DynamicImage::ImageRgb8(RgbImage::new(width, height)).write_to(&mut buf, format).unwrap();
+        buf.into_inner()
+    }
+
+    #[test]
+    fn strips_exif() {
+        let jpeg = encoded(40, 20, ImageFormat::Jpeg);
+
+        // an APP1 segment with an EXIF header, right after the start of image marker
+        let exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\0gps location";
+        let mut with_exif = jpeg[..2].to_vec();
+        with_exif.extend([0xFF, 0xE1]);
+        with_exif.extend(((exif.len() + 2) as u16).to_be_bytes());
+        with_exif.extend(exif);
+        with_exif.extend(&jpeg[2..]);
+
+        let processed = process_image(&with_exif, 10).unwrap();
+        assert_eq!("image/jpeg", processed.content_type);
+        for bytes in [&processed.image, &processed.thumbnail] {
+            assert!(!bytes.windows(4).any(|w| w == b"Exif"));
+            assert!(!bytes.windows(12).any(|w| w == b"gps location"));
+        }
+    }
+
+    #[test]
+    fn thumbnails_keep_aspect_ratio() {
+        let processed = process_image(&encoded(400, 100, ImageFormat::Png), 200).unwrap();
+        assert_eq!((400, 100), (processed.width, processed.height));
+
+        let thumbnail = image::load_from_memory(&processed.thumbnail).unwrap();
+        assert_eq!((200, 50), (thumbnail.width(), thumbnail.height()));
+
+        // small images aren't scaled up
+        let processed = process_image(&encoded(50, 50, ImageFormat::Png), 200).unwrap();
+        assert_eq!(50, image::load_from_memory(&processed.thumbnail).unwrap().width());
+    }
+
+    #[test]
+    fn rejects_non_images() {
+        assert!(matches!(process_image(b"not an image", 200), Err(NearsayError::Validation { field: "file", .. })));
+        assert!(matches!(process_image(&encoded(10, 10, ImageFormat::Jpeg)[..50], 200), Err(NearsayError::Validation { field: "file", .. })));
+        assert!(matches!(process_image(&encoded(8193, 1, ImageFormat::Png), 200), Err(NearsayError::Validation { field: "file", .. })));
+    }
+}
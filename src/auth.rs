@@ -1,54 +1,142 @@
+use std::marker::PhantomData;
 
-use axum::http::{header::AUTHORIZATION, HeaderMap};
-use hmac::Hmac;
+use axum::{extract::FromRequestParts, http::{header::AUTHORIZATION, request::Parts, HeaderMap}};
+use hmac::{digest::InvalidLength, Hmac, Mac};
 use jwt::{SignWithKey, VerifyWithKey};
+use nearsay_server::{current_time_ms, NearsayError};
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
+use tracing::{debug, error, warn};
 
+use crate::{config::Config, db::{gen_id, NearsayDB}, logging::record_uid, types::Role};
 
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenKind {
+    /// short-lived, sent with every request
+    Access,
+    /// long-lived, only used to get a new token pair
+    Refresh,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct JWTPayload {
     pub uid: String,
-}
+    pub kind: TokenKind,
+    pub role: Role,
 
-/// returns (jwt, csrf_token)
-pub fn create_jwt(key: &Hmac<Sha256>, uid: String) -> Result<String, ()> {
-    
-    let payload = JWTPayload { uid };
+    /// seconds since epoch
+    pub iat: u64,
+    /// seconds since epoch
+    pub exp: u64,
+    pub jti: String,
 
-    match payload.sign_with_key(key) {
-        Ok(jwt) => Ok(jwt),
-        Err(jwt_err) => {
-            eprintln!("error creating jwt: {}", jwt_err);
-            Err(())
-        },
+    /// the user's token generation when this token was issued. bumping the generation revokes every older token
+    pub gen: u64,
+}
+
+/// signing key + token lifetimes
+#[derive(Clone)]
+pub struct JWTKey {
+    key: Hmac<Sha256>,
+    access_lifetime_secs: u64,
+    refresh_lifetime_secs: u64,
+}
+impl JWTKey {
+    pub fn new(config: &Config) -> Result<Self, InvalidLength> {
+        Ok(Self {
+            key: Hmac::new_from_slice(config.jwt_secret.as_bytes())?,
+            access_lifetime_secs: config.access_token_lifetime_secs,
+            refresh_lifetime_secs: config.refresh_token_lifetime_secs,
+        })
     }
 }
 
+#[derive(Serialize, Debug)]
+pub struct TokenPair {
+    pub jwt: String,
+    pub refresh_token: String,
+}
 
-pub fn verify_password(password: &str, hash: &str) -> Result<bool, ()> {
-    match bcrypt::verify(password, hash) {
-        Err(bcrypt_err) => {
-            eprintln!("bcrypt error when authorizing user: {}", bcrypt_err);
-            Err(())
-        },
-        Ok(verified) => Ok(verified)
+fn current_time_secs() -> u64 {
+    current_time_ms() / 1000
+}
+
+fn create_payload(key: &JWTKey, uid: &str, kind: TokenKind, role: Role, gen: u64) -> JWTPayload {
+    let iat = current_time_secs();
+    let lifetime = match kind {
+        TokenKind::Access => key.access_lifetime_secs,
+        TokenKind::Refresh => key.refresh_lifetime_secs,
+    };
+
+    JWTPayload { uid: uid.to_string(), kind, role, iat, exp: iat + lifetime, jti: gen_id(), gen }
+}
+
+fn sign_jwt(key: &JWTKey, payload: &JWTPayload) -> Result<String, NearsayError> {
+    payload.sign_with_key(&key.key).map_err(|jwt_err| {
+        error!(error = %jwt_err, "couldn't sign jwt");
+        NearsayError::Internal
+    })
+}
+
+/// a signed access token for `uid` that has already expired
+#[cfg(test)]
+pub fn expired_access_token(key: &JWTKey, uid: &str) -> String {
+    let mut payload = create_payload(key, uid, TokenKind::Access, Role::Member, 0);
+    payload.exp = payload.iat - 1;
+    sign_jwt(key, &payload).unwrap()
+}
+
+/// a signed access token for the guest `uid`, issued at generation 0
+#[cfg(test)]
+pub fn guest_access_token(key: &JWTKey, uid: &str) -> String {
+    sign_jwt(key, &create_payload(key, uid, TokenKind::Access, Role::Guest, 0)).unwrap()
+}
+
+/// creates an access + refresh token for `uid`, and saves the refresh token so it can be used once.
+/// 
+/// the tokens' role is read from the user's account, or `Role::Guest` if they don't have one
+pub async fn issue_tokens(db: &mut NearsayDB, key: &JWTKey, uid: &str) -> Result<TokenPair, NearsayError> {
+    let role = db.get_role(uid).await?;
+    let gen = db.get_token_gen(uid).await?;
+    let access = create_payload(key, uid, TokenKind::Access, role, gen);
+    let refresh = create_payload(key, uid, TokenKind::Refresh, role, gen);
+
+    db.insert_refresh_token(&refresh.jti, uid, refresh.exp).await?;
+
+    Ok(TokenPair { jwt: sign_jwt(key, &access)?, refresh_token: sign_jwt(key, &refresh)? })
+}
+
+/// exchanges a refresh token for a new token pair. each refresh token can only be used once
+pub async fn refresh_tokens(db: &mut NearsayDB, key: &JWTKey, refresh_token: &str) -> Result<TokenPair, NearsayError> {
+    let payload = check_not_revoked(db, verify_jwt(key, refresh_token, TokenKind::Refresh)?).await?;
+
+    if !db.take_refresh_token(&payload.jti, &payload.uid).await? {
+        warn!(uid = payload.uid, jti = payload.jti, "refresh token was already used or revoked");
+        return Err(NearsayError::Unauthorized);
     }
+
+    issue_tokens(db, key, &payload.uid).await
+}
+
+
+pub fn verify_password(password: &str, hash: &str) -> Result<bool, NearsayError> {
+    Ok(bcrypt::verify(password, hash)?)
 }
 
 
 /// returns None if no jwt, OK(Some(JWTPayload)) if success, Ok(None) if no header, Err() otherwise
-pub fn authenticate_with_header(key: &Hmac<Sha256>, headers: &HeaderMap) -> Result<Option<JWTPayload>, ()> {
+pub async fn authenticate_with_header(db: &mut NearsayDB, key: &JWTKey, headers: &HeaderMap) -> Result<Option<JWTPayload>, NearsayError> {
     match headers.get(AUTHORIZATION) {
         None => Ok(None),
         Some(value) => {
 
-            let Ok(value) = value.to_str() else { return Err(()); };
-            
-            if !value.starts_with("Bearer ") {return Err(()); }
+            let Ok(value) = value.to_str() else { return Err(NearsayError::Unauthorized); };
 
-            match authenticate_jwt(key, &value[7..]) {
+            if !value.starts_with("Bearer ") {return Err(NearsayError::Unauthorized); }
+
+            match authenticate_jwt(db, key, &value[7..]).await {
                 Ok(payload) => Ok(Some(payload)),
                 Err(err) => Err(err)
             }
@@ -56,18 +144,210 @@ pub fn authenticate_with_header(key: &Hmac<Sha256>, headers: &HeaderMap) -> Resu
     }
 }
 
-/// if successful, returns `uid`
-pub fn authenticate_jwt(key: &Hmac<Sha256>, jwt: &str) -> Result<JWTPayload, ()> {
+/// verifies an access token and checks that it hasn't been revoked. if successful, returns its payload
+pub async fn authenticate_jwt(db: &mut NearsayDB, key: &JWTKey, jwt: &str) -> Result<JWTPayload, NearsayError> {
+    check_not_revoked(db, verify_jwt(key, jwt, TokenKind::Access)?).await
+}
+
+async fn check_not_revoked(db: &mut NearsayDB, payload: JWTPayload) -> Result<JWTPayload, NearsayError> {
+    if payload.gen != db.get_token_gen(&payload.uid).await? {
+        debug!(uid = payload.uid, jti = payload.jti, "token was revoked");
+        return Err(NearsayError::Unauthorized);
+    }
+    record_uid(&payload.uid);
+    Ok(payload)
+}
+
+
+/// a set of roles allowed to use an event or route
+pub trait AcceptedRoles {
+    const ROLES: &'static [Role];
+}
+
+pub struct Anyone;
+impl AcceptedRoles for Anyone { const ROLES: &'static [Role] = &[Role::Guest, Role::Member, Role::Moderator]; }
+
+pub struct Guests;
+impl AcceptedRoles for Guests { const ROLES: &'static [Role] = &[Role::Guest]; }
 
-    let verification_result: Result<JWTPayload, jwt::Error> = jwt.verify_with_key(key);
+/// anyone with an account
+pub struct Members;
+impl AcceptedRoles for Members { const ROLES: &'static [Role] = &[Role::Member, Role::Moderator]; }
 
-    match verification_result {
-        Err(jwt_err) => {
-            eprintln!("error authenticating jwt: {}", jwt_err);
-            Err(())
+
+/// an authenticated access token whose role is one of `R`.
+/// 
+/// fails with `Unauthorized` if the token is missing/invalid/revoked, or `Forbidden` if its role isn't accepted.
+/// axum routes take this as an extractor, socket handlers call `Auth::from_jwt` with the jwt in their data
+pub struct Auth<R: AcceptedRoles> {
+    pub payload: JWTPayload,
+    accepted: PhantomData<R>,
+}
+impl<R: AcceptedRoles> Auth<R> {
+    pub async fn from_jwt(db: &mut NearsayDB, key: &JWTKey, jwt: &str) -> Result<Self, NearsayError> {
+        let payload = authenticate_jwt(db, key, jwt).await?;
+        
+        if !R::ROLES.contains(&payload.role) {
+            return Err(NearsayError::Forbidden);
         }
-        Ok(payload) => Ok(payload),
+        
+        Ok(Self { payload, accepted: PhantomData })
+    }
+    
+    pub fn uid(&self) -> &str {
+        &self.payload.uid
+    }
+}
+
+/// requires the router to have `Extension`s of the `NearsayDB` and `JWTKey`
+impl<R: AcceptedRoles, S: Send + Sync> FromRequestParts<S> for Auth<R> {
+    type Rejection = NearsayError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let (Some(db), Some(key)) = (parts.extensions.get::<NearsayDB>(), parts.extensions.get::<JWTKey>())
+        else {
+            error!("auth extractor used without db/key extensions");
+            return Err(NearsayError::Internal);
+        };
+        let (mut db, key) = (db.clone(), key.clone());
+        
+        let jwt = parts.headers.get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(NearsayError::Unauthorized)?;
+        
+        Self::from_jwt(&mut db, &key, jwt).await
+    }
+}
+
+/// checks signature, kind, and expiry
+fn verify_jwt(key: &JWTKey, jwt: &str, kind: TokenKind) -> Result<JWTPayload, NearsayError> {
+
+    let payload: JWTPayload = jwt.verify_with_key(&key.key)?;
+
+    if payload.kind != kind {
+        debug!(expected = ?kind, got = ?payload.kind, "wrong token kind");
+        return Err(NearsayError::Unauthorized);
     }
+    if payload.exp <= current_time_secs() {
+        return Err(NearsayError::Unauthorized);
+    }
+    
+    Ok(payload)
 }
 
 
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::{header::AUTHORIZATION, Request, StatusCode}};
+    use jwt::SignWithKey;
+    use nearsay_server::NearsayError;
+    use tower::ServiceExt;
+
+    use crate::{config::Config, db::NearsayDB, endpoints::get_endpoints_router, stand_ins::{cache_token_gen, mongo_config, redis_config}, types::Role};
+
+    use super::{authenticate_jwt, create_payload, guest_access_token, issue_tokens, refresh_tokens, sign_jwt, verify_jwt, Anyone, Auth, JWTKey, Members, TokenKind};
+
+    fn key() -> JWTKey {
+        JWTKey::new(&Config { jwt_secret: "secret".to_string(), ..Config::default() }).unwrap()
+    }
+
+    #[test]
+    fn access_token_round_trip() {
+        let key = key();
+        let payload = create_payload(&key, "uid", TokenKind::Access, Role::Member, 3);
+
+        let verified = verify_jwt(&key, &sign_jwt(&key, &payload).unwrap(), TokenKind::Access).unwrap();
+        assert_eq!("uid", verified.uid);
+        assert_eq!(payload.jti, verified.jti);
+        assert_eq!(key.access_lifetime_secs, verified.exp - verified.iat);
+        assert_eq!(3, verified.gen);
+        assert_eq!(Role::Member, verified.role);
+    }
+
+    #[test]
+    fn refresh_token_is_not_an_access_token() {
+        let key = key();
+        let refresh = sign_jwt(&key, &create_payload(&key, "uid", TokenKind::Refresh, Role::Guest, 0)).unwrap();
+
+        assert!(verify_jwt(&key, &refresh, TokenKind::Access).is_err());
+        assert!(verify_jwt(&key, &refresh, TokenKind::Refresh).is_ok());
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let key = key();
+        let mut payload = create_payload(&key, "uid", TokenKind::Access, Role::Guest, 0);
+        payload.exp = payload.iat - 1;
+
+        assert!(verify_jwt(&key, &sign_jwt(&key, &payload).unwrap(), TokenKind::Access).is_err());
+    }
+
+    #[test]
+    fn token_without_claims_is_rejected() {
+        #[derive(serde::Serialize)]
+        struct OldPayload { uid: String }
+
+        let key = key();
+        let old = OldPayload { uid: "uid".to_string() }.sign_with_key(&key.key).unwrap();
+
+        assert!(verify_jwt(&key, &old, TokenKind::Access).is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis, see `stand_ins::redis_config`"]
+    async fn guests_are_forbidden_from_member_routes() {
+        let config = redis_config(10).await;
+        let mut db = NearsayDB::connect(&config).await.unwrap();
+        let key = JWTKey::new(&config).unwrap();
+        cache_token_gen(&config, "guest", 0).await;
+        cache_token_gen(&config, "member", 0).await;
+        let guest = guest_access_token(&key, "guest");
+        let member = sign_jwt(&key, &create_payload(&key, "member", TokenKind::Access, Role::Member, 0)).unwrap();
+        
+        assert!(matches!(Auth::<Members>::from_jwt(&mut db, &key, &guest).await, Err(NearsayError::Forbidden)));
+        assert!(Auth::<Anyone>::from_jwt(&mut db, &key, &guest).await.is_ok());
+        
+        // the member gets past the extractor, then fails on the db that's down
+        let router = get_endpoints_router(&db, &key);
+        for (jwt, status) in [(guest, StatusCode::FORBIDDEN), (member, StatusCode::SERVICE_UNAVAILABLE)] {
+            let req = Request::get("/me/saved").header(AUTHORIZATION, format!("Bearer {jwt}")).body(Body::empty()).unwrap();
+            assert_eq!(status, router.clone().oneshot(req).await.unwrap().status());
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis, see `stand_ins::redis_config`"]
+    async fn revoking_fails_closed() {
+        let config = redis_config(7).await;
+        let mut db = NearsayDB::connect(&config).await.unwrap();
+        let key = JWTKey::new(&config).unwrap();
+        let jwt = sign_jwt(&key, &create_payload(&key, "uid", TokenKind::Access, Role::Member, 0)).unwrap();
+        
+        cache_token_gen(&config, "uid", 0).await;
+        assert!(authenticate_jwt(&mut db, &key, &jwt).await.is_ok());
+        
+        // mongodb is down, so the generation isn't bumped. the cached one is gone though, so the token doesn't pass on it
+        assert!(db.revoke_tokens("uid").await.is_err());
+        assert!(authenticate_jwt(&mut db, &key, &jwt).await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis and mongodb, see `stand_ins::mongo_config`"]
+    async fn revoked_tokens_are_rejected() {
+        let config = mongo_config(8).await;
+        let mut db = NearsayDB::connect(&config).await.unwrap();
+        let key = JWTKey::new(&config).unwrap();
+        
+        let tokens = issue_tokens(&mut db, &key, "uid").await.unwrap();
+        assert!(authenticate_jwt(&mut db, &key, &tokens.jwt).await.is_ok());
+        
+        db.revoke_tokens("uid").await.unwrap();
+        
+        assert!(matches!(authenticate_jwt(&mut db, &key, &tokens.jwt).await, Err(NearsayError::Unauthorized)));
+        assert!(matches!(refresh_tokens(&mut db, &key, &tokens.refresh_token).await, Err(NearsayError::Unauthorized)));
+        
+        let new_tokens = issue_tokens(&mut db, &key, "uid").await.unwrap();
+        assert!(authenticate_jwt(&mut db, &key, &new_tokens.jwt).await.is_ok());
+    }
+}
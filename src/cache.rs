@@ -1,24 +1,172 @@
-use std::collections::HashSet;
-use std::time::Duration;
-use std::{error::Error, usize};
-use geoutils::Location;
-use redis::aio::MultiplexedConnection;
-use redis::{from_redis_value, AsyncCommands, Cmd, Pipeline, RedisResult};
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+use redis::{from_redis_value, AsyncCommands, Cmd, ErrorKind, Pipeline, RedisError, RedisResult};
 use redis::geo::{Coord, Unit};
-use rslock::LockManager;
-use serde::Serialize;
+use rslock::{Lock, LockManager};
+use serde::{Deserialize, Serialize};
 
-use crate::area::Rect;
-use crate::cluster::{get_cluster_radius_meters, merge_clusters, Cluster};
+use crate::area::{meters_between, Rect};
+use crate::cluster::{get_cluster_radius_meters, Cluster};
+use crate::config::Config;
+use crate::metrics::TimedConnection;
+use crate::types::Category;
 
-const MIN_CACHED_ZOOM_LEVEL: usize = 3;
-const MAX_CACHED_ZOOM_LEVEL: usize = 5;
-const CACHED_ZOOM_LEVELS: usize = MAX_CACHED_ZOOM_LEVEL - MIN_CACHED_ZOOM_LEVEL + 1;
+/// posts are clustered once over every post, in the "" layer, and again over each category's posts, in the ":{category}" layers.
+/// every key of a layer includes its name, so filtered views can be served from the cache too
+fn layer(category: Option<Category>) -> String {
+    match category {
+        None => String::new(),
+        Some(category) => format!(":{}", category.as_str()),
+    }
+}
+
+/// names of the keys of one layer in one version of the posts cache. 
+/// a rebuild fills a new version while the live one keeps being read, see `start_posts_rebuild`
+struct LayerKeys {
+    version: u64,
+    layer: String,
+}
+impl LayerKeys {
+    fn new(version: u64, category: Option<Category>) -> Self {
+        Self { version, layer: layer(category) }
+    }
+    
+    fn clusters(&self, zoom: usize) -> String {
+        format!("v{}:Z{zoom}{}", self.version, self.layer)
+    }
+    
+    /// hash of the cluster's `size`, and `sum_x` and `sum_y` of its posts' positions (see `CachedCluster`)
+    fn cluster(&self, zoom: usize, cluster_id: &str) -> String {
+        format!("v{}:cluster:Z{zoom}{}:{cluster_id}", self.version, self.layer)
+    }
+    
+    fn blurb(&self, cluster_id: &str) -> String {
+        format!("v{}:blurb{}:{cluster_id}", self.version, self.layer)
+    }
+    
+    /// set of the ids of every post in the cluster
+    fn members(&self, zoom: usize, cluster_id: &str) -> String {
+        format!("v{}:members:Z{zoom}{}:{cluster_id}", self.version, self.layer)
+    }
+    
+    /// hash of post id -> id of the cluster it's in
+    fn owners(&self, zoom: usize) -> String {
+        format!("v{}:owners:Z{zoom}{}", self.version, self.layer)
+    }
+    
+    /// hash of post id -> "x,y" of every post in the layer
+    fn positions(&self) -> String {
+        format!("v{}:pos{}", self.version, self.layer)
+    }
+}
+
+/// list of the `PostChange`s made while `version` was being rebuilt
+fn changes_key(version: u64) -> String {
+    format!("v{version}:changes")
+}
+
+/// a change to the posts cache. while a version is being rebuilt, changes are logged for it instead of written to it, 
+/// then replayed onto it by `MapCache::finish_posts_rebuild`
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+enum PostChange {
+    Add { id: String, x: f64, y: f64, blurb: String, category: Category },
+    Del { id: String, category: Category },
+    Blurb { id: String, blurb: String, category: Category },
+}
+
+/// positions are summed in 1e-7ths of a degree (about 1cm), as integers so that adding a post and taking it back out cancel exactly
+const POS_SUM_SCALE: f64 = 1e7;
+
+fn scaled(pos: (f64, f64)) -> (i64, i64) {
+    ((pos.0 * POS_SUM_SCALE).round() as i64, (pos.1 * POS_SUM_SCALE).round() as i64)
+}
+
+/// a cluster on one zoom of a layer, as stored in the cache. 
+/// keeps the sum of its posts' positions instead of its center, which the geo set only holds approximately, 
+/// so the center doesn't drift as posts come and go
+#[derive(Debug, PartialEq)]
+struct CachedCluster {
+    id: String,
+    sum: (i64, i64),
+    size: usize,
+}
+impl CachedCluster {
+    fn new(id: &str, positions: impl IntoIterator<Item = (f64, f64)>) -> Self {
+        let mut cluster = Self { id: id.to_string(), sum: (0, 0), size: 0 };
+        for pos in positions {
+            let (x, y) = scaled(pos);
+            cluster.sum = (cluster.sum.0 + x, cluster.sum.1 + y);
+            cluster.size += 1;
+        }
+        cluster
+    }
+    
+    /// the center of its posts
+    fn pos(&self) -> (f64, f64) {
+        let size = self.size.max(1) as f64;
+        (self.sum.0 as f64 / POS_SUM_SCALE / size, self.sum.1 as f64 / POS_SUM_SCALE / size)
+    }
+    
+    /// reads the fields of the cluster's hash (see `get_cluster`), erroring if any are missing
+    fn from_fields(id: String, zoom: usize, fields: ClusterFields) -> RedisResult<Self> {
+        match fields {
+            (Some(size), Some(sum_x), Some(sum_y)) if size > 0 => Ok(Self { id, sum: (sum_x, sum_y), size }),
+            _ => Err(unexpected_reply(format!("cluster {id} on zoom {zoom} is missing its size or position"))),
+        }
+    }
+}
+
+/// `size`, `sum_x` and `sum_y` of a cluster's hash
+type ClusterFields = (Option<usize>, Option<i64>, Option<i64>);
+
+/// the posts cache replied with fewer values than were asked for, or with keys that disagree, like a post without a cluster. 
+/// changes stop there instead of guessing what was meant
+fn unexpected_reply(detail: String) -> RedisError {
+    RedisError::from((ErrorKind::ResponseError, "unexpected reply from posts cache", detail))
+}
+
+/// merges the post and every nearby cluster into the biggest of them, which keeps its id so the fewest members have to move.
+/// returns (merged cluster, ids of the clusters absorbed into it)
+fn merge_nearby(post_id: &str, pos: (f64, f64), nearby: &[CachedCluster]) -> (CachedCluster, Vec<String>) {
+    let Some(biggest) = nearby.iter().reduce(|biggest, cluster| if cluster.size > biggest.size { cluster } else { biggest })
+    else { return (CachedCluster::new(post_id, [pos]), vec![]) };
+    
+    let mut merged = CachedCluster::new(&biggest.id, [pos]);
+    for cluster in nearby {
+        merged.sum = (merged.sum.0 + cluster.sum.0, merged.sum.1 + cluster.sum.1);
+        merged.size += cluster.size;
+    }
+    
+    let absorbed = nearby.iter().filter(|cluster| cluster.id != biggest.id).map(|cluster| cluster.id.clone()).collect();
+    
+    (merged, absorbed)
+}
+
+/// what's left of `cluster` once the post at `post_pos` is taken out of it, or `None` if it was the only one in it.
+/// a cluster named after the post is renamed to the smallest of its other `members`
+fn split_off(cluster: &CachedCluster, post_id: &str, post_pos: (f64, f64), members: &[String]) -> Option<CachedCluster> {
+    if cluster.size <= 1 { return None }
+    
+    let id = match cluster.id == post_id {
+        true => members.iter().filter(|&id| id != post_id).min()?.clone(),
+        false => cluster.id.clone(),
+    };
+    
+    let (x, y) = scaled(post_pos);
+    
+    Some(CachedCluster { id, sum: (cluster.sum.0 - x, cluster.sum.1 - y), size: cluster.size - 1 })
+}
+
+fn parse_pos(pos: &str) -> Option<(f64, f64)> {
+    let (x, y) = pos.split_once(',')?;
+    Some((x.parse().ok()?, y.parse().ok()?))
+}
 
 /// `radius` in meters
-fn geoquery_radius<'a>(pipeline: &'a mut Pipeline, zoom: usize, x: f64, y: f64, radius: f64, with_coord: bool) -> &'a mut Pipeline {
+fn geoquery_radius<'a>(pipeline: &'a mut Pipeline, zoom: usize, keys: &LayerKeys, x: f64, y: f64, radius: f64, with_coord: bool) -> &'a mut Pipeline {
     let query = pipeline.cmd("GEOSEARCH")
-                .arg(format!("Z{zoom}"))
+                .arg(keys.clusters(zoom))
                 .arg("FROMLONLAT")
                 .arg(x)
                 .arg(y)
@@ -32,33 +180,67 @@ fn geoquery_radius<'a>(pipeline: &'a mut Pipeline, zoom: usize, x: f64, y: f64,
     }
 }
 
-fn get_cluster_size<'a>(pipeline: &'a mut Pipeline, zoom: usize, cluster_id: &str) -> &'a mut Pipeline {
-    pipeline.get(format!("size:Z{zoom}:{cluster_id}"))
+fn get_cluster_size<'a>(pipeline: &'a mut Pipeline, zoom: usize, keys: &LayerKeys, cluster_id: &str) -> &'a mut Pipeline {
+    pipeline.hget(keys.cluster(zoom, cluster_id), "size")
 }
-fn set_cluster_size<'a>(pipeline: &'a mut Pipeline, zoom: usize, cluster_id: &str, size: usize) -> &'a mut Pipeline {
-    pipeline.set(format!("size:Z{zoom}:{cluster_id}"), size).ignore()
+/// replies with `ClusterFields`
+fn get_cluster<'a>(pipeline: &'a mut Pipeline, zoom: usize, keys: &LayerKeys, cluster_id: &str) -> &'a mut Pipeline {
+    pipeline.cmd("HMGET").arg(keys.cluster(zoom, cluster_id)).arg(&["size", "sum_x", "sum_y"])
 }
-fn del_cluster_size<'a>(pipeline: &'a mut Pipeline, zoom: usize, cluster_id: &str) -> &'a mut Pipeline {
-    pipeline.del(format!("size:Z{zoom}:{cluster_id}")).ignore()
+
+/// places the cluster at the center of its posts, and saves its size and sums
+fn add_cluster<'a>(pipeline: &'a mut Pipeline, zoom: usize, keys: &LayerKeys, cluster: &CachedCluster) -> &'a mut Pipeline {
+    let (x, y) = cluster.pos();
+    pipeline.geo_add(keys.clusters(zoom), (Coord::lon_lat(x, y), &cluster.id)).ignore()
+        .hset_multiple(keys.cluster(zoom, &cluster.id), &[("size", cluster.size as i64), ("sum_x", cluster.sum.0), ("sum_y", cluster.sum.1)]).ignore()
+}
+/// note: doesn't delete shared `blurb` value, or the `owners` of its members!
+fn del_cluster<'a>(pipeline: &'a mut Pipeline, zoom: usize, keys: &LayerKeys, cluster_id: &str) -> &'a mut Pipeline {
+    pipeline.zrem(keys.clusters(zoom), cluster_id).ignore()
+        .del(keys.members(zoom, cluster_id)).ignore()
+        .del(keys.cluster(zoom, cluster_id)).ignore()
 }
 
-fn add_cluster<'a>(pipeline: &'a mut Pipeline, zoom: usize, cluster_id: &str, x: f64, y: f64) -> &'a mut Pipeline {
-    pipeline.geo_add(format!("Z{zoom}"), (Coord::lon_lat(x, y), cluster_id)).ignore()
+fn get_members<'a>(pipeline: &'a mut Pipeline, zoom: usize, keys: &LayerKeys, cluster_id: &str) -> &'a mut Pipeline {
+    pipeline.smembers(keys.members(zoom, cluster_id))
+}
+/// also points each post at the cluster
+fn add_members<'a, S: AsRef<str>>(pipeline: &'a mut Pipeline, zoom: usize, keys: &LayerKeys, cluster_id: &str, post_ids: &[S]) -> &'a mut Pipeline {
+    let post_ids: Vec<&str> = post_ids.iter().map(AsRef::as_ref).collect();
+    let owners: Vec<(&str, &str)> = post_ids.iter().map(|&post_id| (post_id, cluster_id)).collect();
+    
+    pipeline.sadd(keys.members(zoom, cluster_id), &post_ids).ignore()
+        .hset_multiple(keys.owners(zoom), &owners).ignore()
 }
-/// note: doesn't delete shared `blurb` value!
-fn del_cluster<'a>(mut pipeline: &'a mut Pipeline, zoom: usize, cluster_id: &str) -> &'a mut Pipeline {
-    pipeline = pipeline.zrem(format!("Z{zoom}"), cluster_id).ignore();
-    del_cluster_size(pipeline, zoom, cluster_id)
+fn rem_member<'a>(pipeline: &'a mut Pipeline, zoom: usize, keys: &LayerKeys, cluster_id: &str, post_id: &str) -> &'a mut Pipeline {
+    pipeline.srem(keys.members(zoom, cluster_id), post_id).ignore()
 }
 
-fn get_blurb<'a>(pipeline: &'a mut Pipeline, cluster_id: &str) -> &'a mut Pipeline {
-    pipeline.get(format!("blurb:{cluster_id}"))
+fn get_owner<'a>(pipeline: &'a mut Pipeline, zoom: usize, keys: &LayerKeys, post_id: &str) -> &'a mut Pipeline {
+    pipeline.hget(keys.owners(zoom), post_id)
 }
-fn set_blurb<'a>(pipeline: &'a mut Pipeline, cluster_id: &str, blurb: &str) -> &'a mut Pipeline {
-    pipeline.set(format!("blurb:{cluster_id}"), blurb).ignore()
+fn del_owner<'a>(pipeline: &'a mut Pipeline, zoom: usize, keys: &LayerKeys, post_id: &str) -> &'a mut Pipeline {
+    pipeline.hdel(keys.owners(zoom), post_id).ignore()
 }
-fn del_blurb<'a>(pipeline: &'a mut Pipeline, post_id: &str) -> &'a mut Pipeline {
-    pipeline.del(format!("blurb:{post_id}")).ignore()
+
+fn get_post_pos<'a>(pipeline: &'a mut Pipeline, keys: &LayerKeys, post_id: &str) -> &'a mut Pipeline {
+    pipeline.hget(keys.positions(), post_id)
+}
+fn set_post_pos<'a>(pipeline: &'a mut Pipeline, keys: &LayerKeys, post_id: &str, x: f64, y: f64) -> &'a mut Pipeline {
+    pipeline.hset(keys.positions(), post_id, format!("{x},{y}")).ignore()
+}
+fn del_post_pos<'a>(pipeline: &'a mut Pipeline, keys: &LayerKeys, post_id: &str) -> &'a mut Pipeline {
+    pipeline.hdel(keys.positions(), post_id).ignore()
+}
+
+fn get_blurb<'a>(pipeline: &'a mut Pipeline, keys: &LayerKeys, cluster_id: &str) -> &'a mut Pipeline {
+    pipeline.get(keys.blurb(cluster_id))
+}
+fn set_blurb<'a>(pipeline: &'a mut Pipeline, keys: &LayerKeys, cluster_id: &str, blurb: &str) -> &'a mut Pipeline {
+    pipeline.set(keys.blurb(cluster_id), blurb).ignore()
+}
+fn del_blurb<'a>(pipeline: &'a mut Pipeline, keys: &LayerKeys, post_id: &str) -> &'a mut Pipeline {
+    pipeline.del(keys.blurb(post_id)).ignore()
 }
 
 fn get_avatar<'a>(pipeline: &'a mut Pipeline, uid: &str) -> &'a mut Pipeline {
@@ -83,16 +265,38 @@ fn del_username<'a>(pipeline: &'a mut Pipeline, uid: &str) -> &'a mut Pipeline {
 
 fn set_socket<'a>(pipeline: &'a mut Pipeline, socket_id: &str, uid: &str) -> &'a mut Pipeline {
     pipeline.set(format!("socket:{socket_id}"), uid).ignore()
+        .set(format!("usersocket:{uid}"), socket_id).ignore()
 }
-fn del_socket<'a>(pipeline: &'a mut Pipeline, socket_id: &str) -> &'a mut Pipeline {
+fn del_socket<'a>(pipeline: &'a mut Pipeline, socket_id: &str, uid: &str) -> &'a mut Pipeline {
     pipeline.del(format!("socket:{socket_id}")).ignore()
+        .del(format!("usersocket:{uid}")).ignore()
 }
 
-fn meters_between(lng1: f64, lat1: f64, lng2: f64, lat2: f64) -> f64 {
-    let loc1 = Location::new(lat1, lng1);
-    let loc2 = Location::new(lat2, lng2);
-    loc1.distance_to(&loc2).unwrap_or_else(|_| loc1.haversine_distance_to(&loc2) ).meters()
-}
+pub(crate) const POSTS_LOCK_RESOURCE: &[u8] = b"posts";
+/// how long a posts cache lock is held before it expires on its own, unless it's extended
+const POSTS_LOCK_TTL: Duration = Duration::from_millis(1000);
+/// how long to keep retrying a posts cache lock before giving up
+const POSTS_LOCK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// version of the posts cache that's read from
+const LIVE_VERSION_KEY: &str = "version";
+/// version of the posts cache that's being rebuilt, if any
+const BUILDING_VERSION_KEY: &str = "building";
+/// last version number handed out
+const VERSION_COUNTER_KEY: &str = "versions";
+/// `MapCache::schema` of the live version
+const SCHEMA_KEY: &str = "schema";
+/// bumped whenever what's stored per cluster changes, so caches built before are rebuilt
+const CLUSTER_SCHEMA: u32 = 3;
+/// how many keys are scanned per batch when deleting an old version
+const VERSION_DELETE_BATCH: usize = 1000;
+/// how many clusters are written per pipeline by `MapCache::bulk_add_clusters`
+const BULK_LOAD_BATCH: usize = 10_000;
+/// how many logged changes are replayed per batch by `MapCache::finish_posts_rebuild`
+const CHANGE_REPLAY_BATCH: usize = 1000;
+
+/// how long a user's token generation stays in the users cache after being read from mongo
+const TOKEN_GEN_CACHE_SECS: u64 = 24 * 60 * 60;
 
 
 fn geosearch_cmd(key: &str, within: &Rect) -> Cmd {
@@ -127,188 +331,405 @@ fn geosearch_cmd(key: &str, within: &Rect) -> Cmd {
 }
 
 
+/// a held posts cache lock, see `MapCache::lock_posts`
+struct PostsLock {
+    lock_manager: LockManager,
+    lock: Lock,
+}
+impl PostsLock {
+    /// holds the lock for another `POSTS_LOCK_TTL`. changes extend it before each layer they write, 
+    /// since that takes longer the more zooms are cached
+    async fn extend(&mut self) -> RedisResult<()> {
+        self.lock = self.lock_manager.extend(&self.lock, POSTS_LOCK_TTL).await
+            .map_err(|lock_err| RedisError::from((ErrorKind::TryAgain, "couldn't extend posts cache lock", lock_err.to_string())))?;
+        Ok(())
+    }
+    
+    async fn unlock(self) {
+        self.lock_manager.unlock(&self.lock).await;
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MapCache {
-    posts_cache: MultiplexedConnection,
-    users_cache: MultiplexedConnection,
+    posts_cache: TimedConnection,
+    users_cache: TimedConnection,
+    
+    /// used for locking the posts cache
+    posts_redis_url: String,
+    min_cached_zoom: usize,
+    max_cached_zoom: usize,
 }
 impl MapCache {
 
-    pub async fn new() -> Result<Self, Box<dyn Error>> {
+    pub async fn new(config: &Config) -> RedisResult<Self> {
         Ok(
             Self {
-                posts_cache:  redis::Client::open("redis://localhost:6000")?.get_multiplexed_async_connection().await?,
-                users_cache:  redis::Client::open("redis://localhost:6001")?.get_multiplexed_async_connection().await?
+                posts_cache:  TimedConnection::new(redis::Client::open(config.posts_redis_url.as_str())?.get_multiplexed_async_connection().await?, "posts"),
+                users_cache:  TimedConnection::new(redis::Client::open(config.users_redis_url.as_str())?.get_multiplexed_async_connection().await?, "users"),
+                posts_redis_url: config.posts_redis_url.clone(),
+                min_cached_zoom: config.min_cached_zoom,
+                max_cached_zoom: config.max_cached_zoom,
             }
         )
     }
     
-    pub async fn add_post_pt(&mut self, cluster_id: &str, x: f64, y: f64, blurb: &str) -> Result<(), Box<dyn Error>> {
-        let lock_manager = LockManager::new(vec!["redis://localhost:6000"]);
+    fn cached_zoom_levels(&self) -> usize {
+        self.max_cached_zoom - self.min_cached_zoom + 1
+    }
+    
+    /// locks the posts cache for writing, retrying until `POSTS_LOCK_TIMEOUT` has passed.
+    /// adds and deletes share one lock, since both move posts between clusters
+    async fn lock_posts(&self) -> RedisResult<PostsLock> {
+        let lock_manager = LockManager::new(vec![self.posts_redis_url.as_str()]);
+        let deadline = Instant::now() + POSTS_LOCK_TIMEOUT;
         
-        let lock = loop {
-            if let Ok(lock) = lock_manager
-                .lock("add post pt".as_bytes(), Duration::from_millis(1000))
-                .await
-            {
-                break lock;
+        loop {
+            match lock_manager.lock(POSTS_LOCK_RESOURCE, POSTS_LOCK_TTL).await {
+                Ok(lock) => return Ok(PostsLock { lock_manager, lock }),
+                Err(_) if Instant::now() < deadline => continue,
+                Err(lock_err) => return Err(RedisError::from((ErrorKind::TryAgain, "couldn't lock posts cache", lock_err.to_string()))),
             }
-        };
-        
-        // get ids and positions of nearby clusters
-        let mut pipe_geoquery = &mut redis::pipe();
-        for zoom in MIN_CACHED_ZOOM_LEVEL..=MAX_CACHED_ZOOM_LEVEL {
-            pipe_geoquery = geoquery_radius(pipe_geoquery, zoom, x, y, get_cluster_radius_meters(zoom), true);
         }
+    }
+    
+    /// the live version of the posts cache and the one being rebuilt, whichever exist
+    async fn posts_versions(&mut self) -> RedisResult<(Option<u64>, Option<u64>)> {
+        redis::pipe()
+            .get(LIVE_VERSION_KEY)
+            .get(BUILDING_VERSION_KEY)
+            .query_async(&mut self.posts_cache).await
+    }
+    
+    /// makes the change to the live version, and logs it for the version being rebuilt
+    async fn change_posts(&mut self, change: PostChange) -> RedisResult<()> {
+        let mut lock = self.lock_posts().await?;
         
-        // nearby_clusters[x] = (id, pos) of each nearby cluster on zoom x
-        let nearby_clusters: [ Vec<(String, (f64, f64))> ; CACHED_ZOOM_LEVELS] = pipe_geoquery.query_async(&mut self.posts_cache).await.unwrap();
-        let mut nearby_clusters_ids = HashSet::new();
+        let res = self.change_posts_locked(&change, &mut lock).await;
         
-        let mut pipe_nearby = &mut redis::pipe();
+        lock.unlock().await;
         
-        let mut zooms_new_cluster_was_merged_on = [false; CACHED_ZOOM_LEVELS];
+        res
+    }
+    
+    async fn change_posts_locked(&mut self, change: &PostChange, lock: &mut PostsLock) -> RedisResult<()> {
+        let (live, building) = self.posts_versions().await?;
         
-        // for each zoom level...
-        for (i, nearby_clusters_in_zoom) in nearby_clusters.iter().enumerate() {
-            let zoom = i + MIN_CACHED_ZOOM_LEVEL;
+        if let Some(live) = live {
+            self.apply_change(live, change, Some(lock)).await?;
+        }
+        if let Some(building) = building {
+            let change = serde_json::to_string(change)
+                .map_err(|json_err| RedisError::from((ErrorKind::TypeError, "couldn't log posts cache change", json_err.to_string())))?;
+            self.posts_cache.rpush::<_, _, ()>(changes_key(building), change).await?;
+        }
+        
+        Ok(())
+    }
+    
+    /// changes the layer of every post and the layer of the post's category. 
+    /// `lock` is extended before each, if the change is made while holding it
+    async fn apply_change(&mut self, version: u64, change: &PostChange, mut lock: Option<&mut PostsLock>) -> RedisResult<()> {
+        let (id, category) = match change {
+            PostChange::Add { id, category, .. } | PostChange::Del { id, category } | PostChange::Blurb { id, category, .. } => (id, *category),
+        };
+        
+        for layer in [LayerKeys::new(version, None), LayerKeys::new(version, Some(category))] {
+            if let Some(lock) = lock.as_deref_mut() {
+                lock.extend().await?;
+            }
             
-            // get sizes of nearby clusters + delete them
-            for (nearby_id, _) in nearby_clusters_in_zoom {
-                pipe_nearby = get_cluster_size(pipe_nearby, zoom, &nearby_id);
-                pipe_nearby = del_cluster(pipe_nearby, zoom, &nearby_id);
-                nearby_clusters_ids.insert(nearby_id);
-                zooms_new_cluster_was_merged_on[i] = true;
+            match change {
+                PostChange::Add { x, y, blurb, .. } => self.add_post_pt_to_layer(&layer, id, *x, *y, blurb).await?,
+                PostChange::Del { .. } => self.del_post_from_layer(&layer, id).await?,
+                PostChange::Blurb { blurb, .. } => {
+                    redis::cmd("SET").arg(layer.blurb(id)).arg(blurb).arg("XX").exec_async(&mut self.posts_cache).await?;
+                },
             }
         }
-
-        let nearby_cluster_sizes: Vec<usize> = pipe_nearby.query_async(&mut self.posts_cache).await.unwrap();
-        let mut sizes_i = 0;
         
-        let mut pipe_save = &mut redis::pipe(); // saves new cluster + its size, gets cluster sizes of nearby clusters after merging
+        Ok(())
+    }
+    
+    /// adds the post to the layer of every post and the layer of its category
+    pub async fn add_post_pt(&mut self, post_id: &str, x: f64, y: f64, blurb: &str, category: Category) -> RedisResult<()> {
+        self.change_posts(PostChange::Add { id: post_id.to_string(), x, y, blurb: blurb.to_string(), category }).await
+    }
+    
+    /// writes the position and blurb of every post of a layer straight into `version` (which `start_posts_rebuild` returned),
+    /// `BULK_LOAD_BATCH` at a time. doesn't lock, nothing else reads or writes `version` yet
+    pub async fn bulk_add_pts(&mut self, version: u64, category: Option<Category>, pts: &[Cluster]) -> RedisResult<()> {
+        let layer = LayerKeys::new(version, category);
         
-        // for each zoom level...
-        for (zoom, nearby_clusters_in_zoom) in nearby_clusters.iter().enumerate() {
-            let zoom = zoom + MIN_CACHED_ZOOM_LEVEL;
-            
-            // create a new cluster
-            let mut new_x = x;
-            let mut new_y = y;
-            let mut new_size = 1;
-            
-            // merge all nearby clusters into the new cluster
-            for (_, (nearby_x, nearby_y)) in nearby_clusters_in_zoom {
-                (new_x, new_y, new_size) = merge_clusters(new_x, new_y, new_size, *nearby_x, *nearby_y, nearby_cluster_sizes[sizes_i]);
-                sizes_i += 1;
+        for batch in pts.chunks(BULK_LOAD_BATCH) {
+            let mut p = &mut redis::pipe();
+            for pt in batch {
+                p = set_post_pos(p, &layer, &pt.id, pt.x(), pt.y());
+                if let Some(blurb) = &pt.blurb {
+                    p = set_blurb(p, &layer, &pt.id, blurb);
+                }
             }
-            
-            // save new cluster and its size to redis
-            pipe_save = add_cluster(pipe_save, zoom, cluster_id, new_x, new_y);
-            pipe_save = set_cluster_size(pipe_save, zoom, cluster_id, new_size);
+            p.exec_async(&mut self.posts_cache).await?;
+        }
+        
+        Ok(())
+    }
+    
+    /// writes the clusters of one zoom of a layer, made in memory by `cluster::cluster_with_members` over `pts`, 
+    /// like `bulk_add_pts` does
+    pub async fn bulk_add_clusters(&mut self, version: u64, category: Option<Category>, zoom: usize, clusters: &[(Cluster, Vec<usize>)], pts: &[Cluster]) -> RedisResult<()> {
+        let layer = LayerKeys::new(version, category);
+        
+        for batch in clusters.chunks(BULK_LOAD_BATCH) {
+            let mut p = &mut redis::pipe();
+            for (cluster, members) in batch {
+                let cached = CachedCluster::new(&cluster.id, members.iter().map(|&i| pts[i].pos));
+                let members: Vec<&str> = members.iter().map(|&i| pts[i].id.as_str()).collect();
+                
+                p = add_cluster(p, zoom, &layer, &cached);
+                p = add_members(p, zoom, &layer, &cluster.id, &members);
+            }
+            p.exec_async(&mut self.posts_cache).await?;
+        }
+        
+        Ok(())
+    }
+    
+    /// merges the post with the clusters within each zoom's radius. 
+    /// adding a post that's already in the layer only replaces its blurb, so it's never counted twice
+    async fn add_post_pt_to_layer(&mut self, layer: &LayerKeys, post_id: &str, x: f64, y: f64, blurb: &str) -> RedisResult<()> {
+        if self.posts_cache.hexists(layer.positions(), post_id).await? {
+            return set_blurb(&mut redis::pipe(), layer, post_id, blurb).exec_async(&mut self.posts_cache).await
+        }
+        
+        // get ids of nearby clusters
+        let mut pipe_geoquery = &mut redis::pipe();
+        for zoom in self.min_cached_zoom..=self.max_cached_zoom {
+            pipe_geoquery = geoquery_radius(pipe_geoquery, zoom, layer, x, y, get_cluster_radius_meters(zoom), false);
         }
         
-        // get the size of each deleted cluster on each zoom
-        for id in &nearby_clusters_ids {
-            for zoom in MIN_CACHED_ZOOM_LEVEL..=MAX_CACHED_ZOOM_LEVEL {
-                pipe_save = get_cluster_size(pipe_save, zoom, id);
+        // nearby_clusters[x] = ids of the nearby clusters on zoom x
+        let mut nearby_clusters: Vec<Vec<String>> = pipe_geoquery.query_async(&mut self.posts_cache).await?;
+        if nearby_clusters.len() != self.cached_zoom_levels() {
+            return Err(unexpected_reply(format!("{} geoqueries for {} zooms", nearby_clusters.len(), self.cached_zoom_levels())))
+        }
+        
+        // zooms with a radius of 0 aren't clustered, not even posts in the same spot
+        for (i, nearby_clusters_in_zoom) in nearby_clusters.iter_mut().enumerate() {
+            if get_cluster_radius_meters(i + self.min_cached_zoom) <= 0.0 {
+                nearby_clusters_in_zoom.clear();
             }
         }
         
-        sizes_i = 0;
+        // clusters merged on a zoom are merged on every zoom below it too, so each cluster stays inside one cluster 
+        // of the zoom below, like `cluster::cluster_zooms` makes them. clusters are named after one of their posts, 
+        // so what has to be merged on a zoom is whatever the nearby clusters of the zooms above it are in there
+        let above: Vec<Vec<&str>> = (0..nearby_clusters.len())
+            .map(|i| {
+                let mut ids: Vec<&str> = nearby_clusters[i + 1..].iter().flatten().map(String::as_str).collect();
+                ids.sort_unstable();
+                ids.dedup();
+                ids
+            })
+            .collect();
         
-        let nearby_cluster_sizes: Vec<Option<usize>> = pipe_save.query_async(&mut self.posts_cache).await.unwrap();
+        let mut pipe_owners = &mut redis::pipe();
+        for (i, ids) in above.iter().enumerate().filter(|(_, ids)| !ids.is_empty()) {
+            pipe_owners = pipe_owners.cmd("HMGET").arg(layer.owners(i + self.min_cached_zoom)).arg(ids);
+        }
         
-        let mut pipe_blurbs = &mut redis::pipe();
+        let owners: Vec<Vec<Option<String>>> = pipe_owners.query_async(&mut self.posts_cache).await?;
+        let mut owners = owners.into_iter();
         
-        for deleted_cluster_id in &nearby_clusters_ids {
-            
-            // a blurb is required if the cluster is a single on any zoom
-            let mut blurb_required = false; 
-            for _ in 0..CACHED_ZOOM_LEVELS {
-                if let Some(1) = nearby_cluster_sizes[sizes_i] {
-                    blurb_required = true;
+        // to_merge[x] = ids of the clusters the post is merged with on zoom x
+        let mut to_merge = vec![];
+        for (i, (nearby_clusters_in_zoom, ids)) in nearby_clusters.iter().zip(&above).enumerate() {
+            let mut clusters = nearby_clusters_in_zoom.clone();
+            if !ids.is_empty() {
+                let owners_in_zoom = owners.next().filter(|owners_in_zoom| owners_in_zoom.len() == ids.len())
+                    .ok_or_else(|| unexpected_reply(format!("missing owners on zoom {}", i + self.min_cached_zoom)))?;
+                
+                for (id, owner) in ids.iter().zip(owners_in_zoom) {
+                    let owner = owner.ok_or_else(|| unexpected_reply(format!("post {id} isn't in a cluster on zoom {}", i + self.min_cached_zoom)))?;
+                    if !clusters.contains(&owner) {
+                        clusters.push(owner);
+                    }
                 }
-                sizes_i += 1;
             }
-            if !blurb_required {
-                pipe_blurbs = del_blurb(pipe_blurbs, deleted_cluster_id);
+            to_merge.push(clusters);
+        }
+        
+        // get their sizes and positions
+        let mut pipe_clusters = &mut redis::pipe();
+        for (i, clusters) in to_merge.iter().enumerate() {
+            for id in clusters {
+                pipe_clusters = get_cluster(pipe_clusters, i + self.min_cached_zoom, layer, id);
             }
         }
         
-        // save blurb if new cluster didn't do a merge on any zoom
-        for merged_on_zoom in zooms_new_cluster_was_merged_on {
-            if !merged_on_zoom {
-                pipe_blurbs = set_blurb(pipe_blurbs, cluster_id, blurb);
-                break;
+        let cluster_fields: Vec<ClusterFields> = pipe_clusters.query_async(&mut self.posts_cache).await?;
+        if cluster_fields.len() != to_merge.iter().map(Vec::len).sum::<usize>() {
+            return Err(unexpected_reply("missing clusters to merge with".to_string()))
+        }
+        let mut cluster_fields = cluster_fields.into_iter();
+        
+        // on each zoom, the post and every cluster to merge with become one
+        let mut merges: Vec<(CachedCluster, Vec<String>)> = vec![];
+        for (i, clusters) in to_merge.into_iter().enumerate() {
+            let mut nearby = vec![];
+            for (id, fields) in clusters.into_iter().zip(&mut cluster_fields) {
+                nearby.push(CachedCluster::from_fields(id, i + self.min_cached_zoom, fields)?);
             }
+            merges.push(merge_nearby(post_id, (x, y), &nearby));
         }
         
-        pipe_blurbs.exec_async(&mut self.posts_cache).await.unwrap();
+        // get the members of absorbed clusters, they're moved into the merged cluster
+        let mut pipe_members = &mut redis::pipe();
+        for (i, (_, absorbed)) in merges.iter().enumerate() {
+            for absorbed_id in absorbed {
+                pipe_members = get_members(pipe_members, i + self.min_cached_zoom, layer, absorbed_id);
+            }
+        }
         
-        // Unlock the lock
-        lock_manager.unlock(&lock).await;
-
-        Ok(())
+        let absorbed_members: Vec<Vec<String>> = pipe_members.query_async(&mut self.posts_cache).await?;
+        if absorbed_members.len() != merges.iter().map(|(_, absorbed)| absorbed.len()).sum::<usize>() {
+            return Err(unexpected_reply("missing members of absorbed clusters".to_string()))
+        }
+        let mut absorbed_members = absorbed_members.into_iter();
+        
+        let mut pipe_save = &mut redis::pipe();
+        
+        for (i, (merged, absorbed)) in merges.iter().enumerate() {
+            let zoom = i + self.min_cached_zoom;
+            
+            let mut moved = vec![post_id.to_string()];
+            for absorbed_id in absorbed {
+                pipe_save = del_cluster(pipe_save, zoom, layer, absorbed_id);
+                moved.extend(absorbed_members.next().into_iter().flatten());
+            }
+            
+            pipe_save = add_cluster(pipe_save, zoom, layer, merged);
+            pipe_save = add_members(pipe_save, zoom, layer, &merged.id, &moved);
+        }
+        
+        // every post keeps its blurb, so any cluster that's split back down to it can show it
+        pipe_save = set_post_pos(pipe_save, layer, post_id, x, y);
+        pipe_save = set_blurb(pipe_save, layer, post_id, blurb);
+        
+        pipe_save.exec_async(&mut self.posts_cache).await
     }
     
-    pub async fn del_post(&mut self, post_id: &str) -> RedisResult<()> {
+    pub async fn del_post(&mut self, post_id: &str, category: Category) -> RedisResult<()> {
+        self.change_posts(PostChange::Del { id: post_id.to_string(), category }).await
+    }
+    
+    /// takes the post out of the cluster it's in on every zoom, fixing the cluster's size and position. 
+    /// unlike adding, each zoom can be done on its own: taking a post out can't move a cluster out of the one it's in below
+    async fn del_post_from_layer(&mut self, layer: &LayerKeys, post_id: &str) -> RedisResult<()> {
+        // get the cluster the post is in on each zoom, and where the post is
+        let mut pipe_owners = &mut redis::pipe();
+        for zoom in self.min_cached_zoom..=self.max_cached_zoom {
+            pipe_owners = get_owner(pipe_owners, zoom, layer, post_id);
+        }
+        pipe_owners = get_post_pos(pipe_owners, layer, post_id);
         
-        let lock_manager = LockManager::new(vec!["redis://localhost:6000"]);
-        let lock = loop {
-            if let Ok(lock) = lock_manager
-                .lock("delete post".as_bytes(), Duration::from_millis(1000))
-                .await
-            {
-                break lock;
-            }
+        let mut owners: Vec<Option<String>> = pipe_owners.query_async(&mut self.posts_cache).await?;
+        if owners.len() != self.cached_zoom_levels() + 1 {
+            return Err(unexpected_reply(format!("{} owners for {} zooms", owners.len().saturating_sub(1), self.cached_zoom_levels())))
+        }
+        
+        let Some(post_pos) = owners.pop().flatten()
+        else { 
+            // not in this layer, only make sure the blurb is gone
+            return del_blurb(&mut redis::pipe(), layer, post_id).exec_async(&mut self.posts_cache).await 
         };
+        let post_pos = parse_pos(&post_pos).ok_or_else(|| unexpected_reply(format!("post {post_id} is at {post_pos}")))?;
         
-        let mut pipe_sizes = &mut redis::pipe();
+        let owners = owners.into_iter().enumerate()
+            .map(|(i, owner)| owner.ok_or_else(|| unexpected_reply(format!("post {post_id} isn't in a cluster on zoom {}", i + self.min_cached_zoom))))
+            .collect::<RedisResult<Vec<String>>>()?;
         
-        // get sizes of each cluster with this id
-        for zoom in MIN_CACHED_ZOOM_LEVEL..=MAX_CACHED_ZOOM_LEVEL {
-            pipe_sizes = get_cluster_size(pipe_sizes, zoom, post_id);
+        // get the size and position of each of those clusters, and the members of the ones named after the post, which get renamed
+        let mut pipe_clusters = &mut redis::pipe();
+        for (i, owner) in owners.iter().enumerate() {
+            let zoom = i + self.min_cached_zoom;
+            
+            pipe_clusters = get_cluster(pipe_clusters, zoom, layer, owner);
+            if owner == post_id {
+                pipe_clusters = get_members(pipe_clusters, zoom, layer, owner);
+            }
         }
         
-        let cluster_sizes: [Option<usize>; CACHED_ZOOM_LEVELS] = pipe_sizes.query_async(&mut self.posts_cache).await.unwrap();
+        let cluster_values: Vec<redis::Value> = pipe_clusters.query_async(&mut self.posts_cache).await?;
+        let mut cluster_values = cluster_values.iter();
+        let mut next_value = || cluster_values.next().ok_or_else(|| unexpected_reply("missing clusters the post is in".to_string()));
+        
+        // (zoom, old cluster id, what's left of the cluster, the cluster's members if it's renamed)
+        let mut splits = vec![];
+        for (i, owner) in owners.into_iter().enumerate() {
+            let zoom = i + self.min_cached_zoom;
+            
+            let fields: ClusterFields = from_redis_value(next_value()?)?;
+            let members: Vec<String> = match owner == post_id {
+                true => from_redis_value(next_value()?)?,
+                false => vec![],
+            };
+            
+            let cluster = CachedCluster::from_fields(owner, zoom, fields)?;
+            let remaining = split_off(&cluster, post_id, post_pos, &members);
+            
+            splits.push((zoom, cluster.id, remaining, members));
+        }
         
         let mut pipe_del = &mut redis::pipe();
         
-        // delete clusters with a size of 1
-        for (i, size) in cluster_sizes.iter().enumerate() {
-            if Some(1) == *size {
-                pipe_del = del_cluster(pipe_del, i + MIN_CACHED_ZOOM_LEVEL, post_id);
+        for (zoom, old_id, remaining, members) in splits {
+            pipe_del = del_owner(pipe_del, zoom, layer, post_id);
+            
+            let Some(remaining) = remaining else {
+                pipe_del = del_cluster(pipe_del, zoom, layer, &old_id);
+                continue
+            };
+            
+            if remaining.id == old_id {
+                pipe_del = rem_member(pipe_del, zoom, layer, &old_id, post_id);
             }
+            else {
+                let members: Vec<&str> = members.iter().map(String::as_str).filter(|&id| id != post_id).collect();
+                pipe_del = del_cluster(pipe_del, zoom, layer, &old_id);
+                pipe_del = add_members(pipe_del, zoom, layer, &remaining.id, &members);
+            }
+            
+            pipe_del = add_cluster(pipe_del, zoom, layer, &remaining);
         }
         
-        // regardless of whether clusters were deleted on all zoom levels, delete the blurb
-        pipe_del = del_blurb(pipe_del, post_id);
+        pipe_del = del_post_pos(pipe_del, layer, post_id);
+        pipe_del = del_blurb(pipe_del, layer, post_id);
         
-        pipe_del.exec_async(&mut self.posts_cache).await.unwrap();
-        
-        lock_manager.unlock(&lock).await;
-        
-        Ok(())
+        pipe_del.exec_async(&mut self.posts_cache).await
     }
     
-    /// returns `(cluster_id, cluster)`
-    pub async fn geoquery_post_pts(&mut self, zoom: usize, within: &Rect) -> Result<Vec<Cluster>, ()> {
-        if zoom < MIN_CACHED_ZOOM_LEVEL || MAX_CACHED_ZOOM_LEVEL < zoom { return Err(()) }
+    /// only posts of `category` if it's given. returns `None` if `zoom` isn't cached, or no version of the cache is live yet
+    pub async fn geoquery_post_pts(&mut self, zoom: usize, within: &Rect, category: Option<Category>) -> RedisResult<Option<Vec<Cluster>>> {
+        if !(self.min_cached_zoom..=self.max_cached_zoom).contains(&zoom) { return Ok(None) }
+        
+        let Some(version) = self.posts_cache.get(LIVE_VERSION_KEY).await? else { return Ok(None) };
+        let layer = LayerKeys::new(version, category);
 
         let search_results: Vec<(String, (f64, f64))> = 
-            geosearch_cmd(&format!("Z{zoom}"), within)
-            .query_async(&mut self.posts_cache).await.map_err(|_| ())?;
+            geosearch_cmd(&layer.clusters(zoom), within)
+            .query_async(&mut self.posts_cache).await?;
 
         let mut p = &mut redis::pipe();
         
         // for each cluster found, get its size and blurb
         for (cluster_id, _) in &search_results {
-            p = get_cluster_size(p, zoom, cluster_id);
-            p = get_blurb(p, cluster_id);
+            p = get_cluster_size(p, zoom, &layer, cluster_id);
+            p = get_blurb(p, &layer, cluster_id);
         }
         
         // [size, blurb, size, blurb, size, blurb, ...]
-        let sizes_and_blurbs: Vec<redis::Value> = p.query_async(&mut self.posts_cache).await.unwrap();
+        let sizes_and_blurbs: Vec<redis::Value> = p.query_async(&mut self.posts_cache).await?;
         
         let mut res = Vec::with_capacity(search_results.len());
         
@@ -316,31 +737,151 @@ impl MapCache {
         for (i, (cluster_id, pos)) in search_results.iter().enumerate() {
             
             // only attach size if its not 1
-            let size: Option<usize> = match from_redis_value(&sizes_and_blurbs[i * 2]).unwrap() {
+            let size: Option<usize> = match from_redis_value(&sizes_and_blurbs[i * 2])? {
                 Some(1) => None,
                 other => other
             };
             
             // only attach blurb if not attaching size
             let blurb: Option<String> = match size {
-                None => from_redis_value(&sizes_and_blurbs[i * 2 + 1]).unwrap(),
+                None => from_redis_value(&sizes_and_blurbs[i * 2 + 1])?,
                 _ => None
             };
             
             res.push(Cluster { pos: *pos, size, id: cluster_id.to_string(), blurb });
         }
         
-        Ok(res)
+        Ok(Some(res))
+    }
+    
+    /// replaces the blurb of `post_id`, in the layers it's in
+    pub async fn update_blurb(&mut self, post_id: &str, blurb: &str, category: Category) -> RedisResult<()> {
+        self.change_posts(PostChange::Blurb { id: post_id.to_string(), blurb: blurb.to_string(), category }).await
+    }
+    
+    /// whether there's a live version of the posts cache that was built with the current zoom range, 
+    /// so it can be kept up to date from here on instead of being rebuilt
+    pub async fn is_posts_cache_current(&mut self) -> RedisResult<bool> {
+        let (live, schema): (Option<u64>, Option<String>) = redis::pipe()
+            .get(LIVE_VERSION_KEY)
+            .get(SCHEMA_KEY)
+            .query_async(&mut self.posts_cache).await?;
+        
+        Ok(live.is_some() && schema == Some(self.schema()))
+    }
+    
+    /// makes `is_posts_cache_current` false, so the posts cache is rebuilt instead of reused
+    pub async fn invalidate_posts_cache(&mut self) -> RedisResult<()> {
+        self.posts_cache.del(SCHEMA_KEY).await
+    }
+    
+    /// what the cached keys depend on. a live version built with a different one has to be rebuilt
+    fn schema(&self) -> String {
+        format!("{CLUSTER_SCHEMA}:{}-{}", self.min_cached_zoom, self.max_cached_zoom)
+    }
+    
+    /// returns a new, empty version of the posts cache to fill with `bulk_add_clusters`. until `finish_posts_rebuild`, 
+    /// readers keep using the live version, and changes go to it and are logged for the new one. 
+    /// whatever's left of a rebuild that was cut short is deleted
+    pub async fn start_posts_rebuild(&mut self) -> RedisResult<u64> {
+        let abandoned: Option<u64> = self.posts_cache.get(BUILDING_VERSION_KEY).await?;
+        if let Some(abandoned) = abandoned {
+            self.del_posts_version(abandoned).await?;
+        }
+        
+        let version = self.posts_cache.incr(VERSION_COUNTER_KEY, 1).await?;
+        self.posts_cache.set::<_, _, ()>(BUILDING_VERSION_KEY, version).await?;
+        
+        Ok(version)
+    }
+    
+    /// replays the changes logged while `version` was filled, then switches readers over to it at once, 
+    /// and deletes the version they were reading. 
+    /// a change may already be in what was filled in, so replaying has to be idempotent, see `add_post_pt_to_layer`
+    pub async fn finish_posts_rebuild(&mut self, version: u64) -> RedisResult<()> {
+        loop {
+            self.replay_changes(version).await?;
+            
+            // keep writers from logging changes or picking the old version while it's being replaced
+            let lock = self.lock_posts().await?;
+            
+            let res: RedisResult<Option<(Option<u64>,)>> = async {
+                // changes logged since replaying are replayed first, without holding the lock
+                if self.posts_cache.llen::<_, usize>(changes_key(version)).await? > 0 { return Ok(None) }
+                
+                redis::pipe()
+                    .atomic()
+                    .get(LIVE_VERSION_KEY)
+                    .set(LIVE_VERSION_KEY, version).ignore()
+                    .set(SCHEMA_KEY, self.schema()).ignore()
+                    .del(BUILDING_VERSION_KEY).ignore()
+                    .query_async(&mut self.posts_cache).await
+                    .map(Some)
+            }.await;
+            
+            lock.unlock().await;
+            
+            match res? {
+                Some((Some(old), )) => return self.del_posts_version(old).await,
+                Some((None, )) => return Ok(()),
+                None => continue,
+            }
+        }
     }
     
-    pub async fn flush_all_posts(&mut self) -> RedisResult<()> {
-        redis::cmd("FLUSHALL").exec_async(&mut self.posts_cache).await
+    /// applies the changes logged for `version`, oldest first, until there are none left
+    async fn replay_changes(&mut self, version: u64) -> RedisResult<()> {
+        loop {
+            let changes: Vec<String> = self.posts_cache.lpop(changes_key(version), NonZeroUsize::new(CHANGE_REPLAY_BATCH)).await?;
+            if changes.is_empty() { return Ok(()) }
+            
+            for change in changes {
+                let change: PostChange = serde_json::from_str(&change)
+                    .map_err(|json_err| RedisError::from((ErrorKind::TypeError, "couldn't read posts cache change", json_err.to_string())))?;
+                // nothing else writes to `version` yet, so this doesn't lock
+                self.apply_change(version, &change, None).await?;
+            }
+        }
+    }
+    
+    /// deletes every key of `version`, a batch at a time so redis isn't blocked
+    async fn del_posts_version(&mut self, version: u64) -> RedisResult<()> {
+        let mut cursor = 0;
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(format!("v{version}:*"))
+                .arg("COUNT")
+                .arg(VERSION_DELETE_BATCH)
+                .query_async(&mut self.posts_cache).await?;
+            
+            if !keys.is_empty() {
+                redis::cmd("UNLINK").arg(&keys).exec_async(&mut self.posts_cache).await?;
+            }
+            
+            if next == 0 { return Ok(()) }
+            cursor = next;
+        }
     }
     
     pub async fn user_exists(&mut self, uid: &str) -> RedisResult<bool> {
         self.users_cache.exists(format!("avatar:{uid}")).await
     }
     
+    pub async fn ping_posts(&mut self) -> RedisResult<()> {
+        redis::cmd("PING").exec_async(&mut self.posts_cache).await
+    }
+    
+    pub async fn ping_users(&mut self) -> RedisResult<()> {
+        redis::cmd("PING").exec_async(&mut self.users_cache).await
+    }
+    
+    /// number of users (including guests) on the map
+    pub async fn count_users(&mut self) -> RedisResult<i64> {
+        self.users_cache.zcard("users").await
+    }
+    
     pub async fn get_username(&mut self, uid: &str) -> RedisResult<Option<String>> {
         self.users_cache.get(format!("username:{uid}")).await
     }
@@ -357,17 +898,17 @@ impl MapCache {
         else { Ok( Some( ( from_redis_value(&pos)?, from_redis_value(&avatar)? ) ) ) }
     }
     
-    /// returns old position of user
-    pub async fn set_user_pos(&mut self, uid: &str, x: f64, y: f64) -> Result<(f64, f64), ()> {
+    /// returns old position of user, or `None` if they aren't in the cache
+    pub async fn set_user_pos(&mut self, uid: &str, x: f64, y: f64) -> RedisResult<Option<(f64, f64)>> {
             
-        let old_pos = match self.get_pos_and_avatar(uid).await {
-            Err(_) | Ok(None) => return Err(()),    // user must already exist in cache
-            Ok(Some((old_pos, _))) => old_pos,
+        let old_pos = match self.get_pos_and_avatar(uid).await? {
+            None => return Ok(None),    // user must already exist in cache
+            Some((old_pos, _)) => old_pos,
         };
         
-        let _: () = self.users_cache.geo_add("users", (Coord::lon_lat(x, y), uid)).await.map_err(|e| eprintln!("when setting pos: {e}"))?;
+        let _: () = self.users_cache.geo_add("users", (Coord::lon_lat(x, y), uid)).await?;
         
-        Ok(old_pos)
+        Ok(Some(old_pos))
     }
     
     pub async fn edit_user_if_exists(&mut self, uid: &str, avatar: &Option<usize>, username: &Option<String>) -> RedisResult<()> {
@@ -380,7 +921,7 @@ impl MapCache {
             p = set_avatar(p, uid, *avatar);
         }
         if let Some(username) = username {
-            p = set_username(p, uid, &username);
+            p = set_username(p, uid, username);
         }
         
         let _:() = p.query_async(&mut self.users_cache).await?;
@@ -403,18 +944,46 @@ impl MapCache {
         Ok(())
     }
     
+    pub async fn get_token_gen(&mut self, uid: &str) -> RedisResult<Option<u64>> {
+        self.users_cache.get(format!("tokengen:{uid}")).await
+    }
+    
+    /// doesn't overwrite a generation that's already cached, so a stale read can't undo a revocation
+    pub async fn fill_token_gen(&mut self, uid: &str, gen: u64) -> RedisResult<()> {
+        redis::cmd("SET")
+            .arg(format!("tokengen:{uid}"))
+            .arg(gen)
+            .arg("NX")
+            .arg("EX")
+            .arg(TOKEN_GEN_CACHE_SECS)
+            .exec_async(&mut self.users_cache).await
+    }
+    
+    pub async fn set_token_gen(&mut self, uid: &str, gen: u64) -> RedisResult<()> {
+        self.users_cache.set_ex(format!("tokengen:{uid}"), gen, TOKEN_GEN_CACHE_SECS).await
+    }
+    
+    pub async fn del_token_gen(&mut self, uid: &str) -> RedisResult<()> {
+        self.users_cache.del(format!("tokengen:{uid}")).await
+    }
+    
     pub async fn get_uid_from_socket(&mut self, socket_id: &str) -> RedisResult<Option<String>> {
         let uid: Option<String> = self.users_cache.get(format!("socket:{socket_id}")).await?;
         Ok(uid)
     }
     
+    /// id of the socket `uid` is on the map with
+    pub async fn get_socket_of_user(&mut self, uid: &str) -> RedisResult<Option<String>> {
+        self.users_cache.get(format!("usersocket:{uid}")).await
+    }
+    
     pub async fn del_user(&mut self, uid: &str, socket_id: &str) -> RedisResult<()> {
         let mut p = &mut redis::pipe();
         
-        p = p.zrem("users", &uid).ignore(); // delete user from geomap
-        p = del_avatar(p, &uid);
-        p = del_username(p, &uid);
-        p = del_socket(p, socket_id);
+        p = p.zrem("users", uid).ignore(); // delete user from geomap
+        p = del_avatar(p, uid);
+        p = del_username(p, uid);
+        p = del_socket(p, socket_id, uid);
         
         let _: () = p.query_async(&mut self.users_cache).await?;
         
@@ -428,7 +997,7 @@ impl MapCache {
         }
     }
     
-    pub async fn geoquery_users(&mut self, within: &Rect) -> Result<Vec<UserPOI>, Box<dyn Error>> {
+    pub async fn geoquery_users(&mut self, within: &Rect) -> RedisResult<Vec<UserPOI>> {
         let search_results: Vec<(String, (f64, f64))> =  geosearch_cmd("users", within).query_async(&mut self.users_cache).await?;
         let mut p = &mut redis::pipe();
         
@@ -466,4 +1035,277 @@ pub struct UserPOI {
     pub pos: (f64, f64),
     pub avatar: usize,
     pub username: Option<String>
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use redis::AsyncCommands;
+
+    use crate::{area::Rect, cluster::{cluster_zooms, Cluster, MAX_ZOOM_LEVEL, MIN_ZOOM_LEVEL}, stand_ins::{failing_config, redis_config}, types::Category};
+
+    use super::{layer, merge_nearby, parse_pos, scaled, split_off, CachedCluster, ClusterFields, LayerKeys, MapCache, PostChange, LIVE_VERSION_KEY};
+
+    #[test]
+    fn layer_names() {
+        assert_eq!("", layer(None));
+        assert_eq!(":lost-and-found", layer(Some(Category::LostAndFound)));
+    }
+
+    #[test]
+    fn keys_are_versioned() {
+        let keys = LayerKeys::new(3, Some(Category::Event));
+        
+        assert_eq!("v3:Z5:event", keys.clusters(5));
+        assert_eq!("v3:cluster:Z5:event:post", keys.cluster(5, "post"));
+        assert_eq!("v3:blurb:event:post", keys.blurb("post"));
+        assert_eq!("v4:blurb:post", LayerKeys::new(4, None).blurb("post"));
+        assert_eq!("v3:members:Z5:event:post", keys.members(5, "post"));
+        assert_eq!("v3:owners:Z5:event", keys.owners(5));
+        assert_eq!("v3:pos:event", keys.positions());
+    }
+
+    /// `size` posts all at `pos`
+    fn cached(id: &str, pos: (f64, f64), size: usize) -> CachedCluster {
+        CachedCluster::new(id, vec![pos; size])
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn merges_into_the_biggest_nearby_cluster() {
+        assert_eq!((cached("a", (1.0, 2.0), 1), vec![]), merge_nearby("a", (1.0, 2.0), &[]));
+        
+        let (merged, absorbed) = merge_nearby("d", (0.0, 0.0), &[cached("a", (4.0, 0.0), 1), cached("b", (0.0, 4.0), 2)]);
+        assert_eq!(cached("b", (1.0, 2.0), 4), merged);
+        assert_eq!(ids(&["a"]), absorbed);
+    }
+
+    #[test]
+    fn merge_then_delete() {
+        let (a, _) = merge_nearby("a", (0.0, 0.0), &[]);
+        let (ab, _) = merge_nearby("b", (2.0, 0.0), &[a]);
+        let (abc, absorbed) = merge_nearby("c", (4.0, 0.0), &[ab]);
+        assert_eq!(cached("a", (2.0, 0.0), 3), abc);
+        assert!(absorbed.is_empty());
+        
+        // the cluster is named after "a", so it's renamed when "a" goes
+        let bc = split_off(&abc, "a", (0.0, 0.0), &ids(&["c", "a", "b"])).unwrap();
+        assert_eq!(cached("b", (3.0, 0.0), 2), bc);
+        
+        let b = split_off(&bc, "c", (4.0, 0.0), &[]).unwrap();
+        assert_eq!(cached("b", (2.0, 0.0), 1), b);
+        
+        assert_eq!(None, split_off(&b, "b", (2.0, 0.0), &ids(&["b"])));
+    }
+
+    #[test]
+    fn taking_posts_out_doesnt_move_the_center() {
+        let posts: Vec<(String, (f64, f64))> = (0..100).map(|i| (format!("p{i}"), (i as f64 * 0.0731 - 3.3, 51.0 + i as f64 / 7.0))).collect();
+        
+        let mut cluster = CachedCluster::new("p0", [posts[0].1]);
+        for (id, pos) in &posts[1..] {
+            (cluster, _) = merge_nearby(id, *pos, &[cluster]);
+        }
+        for (id, pos) in posts[1..].iter().rev() {
+            cluster = split_off(&cluster, id, *pos, &[]).unwrap();
+        }
+        
+        assert_eq!(CachedCluster::new("p0", [posts[0].1]), cluster);
+        assert_eq!(scaled(posts[0].1), scaled(cluster.pos()));
+    }
+
+    #[test]
+    fn renamed_cluster_without_other_members_is_dropped() {
+        assert_eq!(None, split_off(&cached("a", (1.0, 0.0), 2), "a", (0.0, 0.0), &ids(&["a"])));
+    }
+
+    #[test]
+    fn positions_round_trip() {
+        assert_eq!(Some((-0.1, 51.123456789)), parse_pos(&format!("{},{}", -0.1, 51.123456789)));
+        assert_eq!(None, parse_pos("1.0"));
+        assert_eq!(None, parse_pos("a,b"));
+    }
+
+    /// a cache with an empty live version, in database `db` of the test redis
+    async fn live_cache(db: u8) -> MapCache {
+        let mut cache = MapCache::new(&redis_config(db).await).await.unwrap();
+        let version = cache.start_posts_rebuild().await.unwrap();
+        cache.finish_posts_rebuild(version).await.unwrap();
+        cache
+    }
+
+    /// the clusters around (0, 0) on `zoom`, and how many posts they hold in all
+    async fn clusters_near_origin(cache: &mut MapCache, zoom: usize, category: Option<Category>) -> (Vec<Cluster>, usize) {
+        let view = Rect { top: 10.0, bottom: -10.0, left: -10.0, right: 10.0 };
+        let clusters = cache.geoquery_post_pts(zoom, &view, category).await.unwrap().unwrap();
+        let posts = clusters.iter().map(Cluster::size).sum();
+        (clusters, posts)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis, see `stand_ins::redis_config`"]
+    async fn adding_a_post_twice_counts_it_once() {
+        let mut cache = live_cache(1).await;
+        
+        cache.add_post_pt("a", 0.0, 0.0, "a", Category::General).await.unwrap();
+        cache.add_post_pt("b", 0.001, 0.0, "b", Category::General).await.unwrap();
+        cache.add_post_pt("b", 0.001, 0.0, "b again", Category::General).await.unwrap();
+        
+        for zoom in MIN_ZOOM_LEVEL..=MAX_ZOOM_LEVEL {
+            assert_eq!(2, clusters_near_origin(&mut cache, zoom, None).await.1);
+            assert_eq!(2, clusters_near_origin(&mut cache, zoom, Some(Category::General)).await.1);
+        }
+        
+        let (clusters, _) = clusters_near_origin(&mut cache, MAX_ZOOM_LEVEL, None).await;
+        assert!(clusters.iter().any(|cluster| cluster.id == "b" && cluster.blurb.as_deref() == Some("b again")));
+    }
+
+    fn pt(id: &str, x: f64) -> Cluster {
+        Cluster { pos: (x, 0.0), size: None, id: id.to_string(), blurb: Some(id.to_string()) }
+    }
+
+    /// what `NearsayDB::ready_posts_cache` does with the posts it read from mongodb
+    async fn bulk_load(cache: &mut MapCache, version: u64, category: Option<Category>, pts: &[Cluster]) {
+        cache.bulk_add_pts(version, category, pts).await.unwrap();
+        for (zoom, clusters) in (MIN_ZOOM_LEVEL..).zip(cluster_zooms(pts, MIN_ZOOM_LEVEL, MAX_ZOOM_LEVEL)) {
+            cache.bulk_add_clusters(version, category, zoom, &clusters, pts).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis, see `stand_ins::redis_config`"]
+    async fn changes_during_a_rebuild_are_replayed() {
+        let mut cache = live_cache(2).await;
+        cache.add_post_pt("a", 0.0, 0.0, "a", Category::General).await.unwrap();
+        cache.add_post_pt("b", 0.001, 0.0, "b", Category::General).await.unwrap();
+        
+        let version = cache.start_posts_rebuild().await.unwrap();
+        
+        // "a" is read from mongodb and deleted before it's written, 
+        // "c" is posted after mongodb was read, and "d" just before, so it's read too
+        cache.del_post("a", Category::General).await.unwrap();
+        cache.add_post_pt("c", 0.002, 0.0, "c", Category::General).await.unwrap();
+        cache.add_post_pt("d", 0.003, 0.0, "d", Category::General).await.unwrap();
+        cache.update_blurb("b", "b edited", Category::General).await.unwrap();
+        
+        let pts = [pt("a", 0.0), pt("b", 0.001), pt("d", 0.003)];
+        bulk_load(&mut cache, version, None, &pts).await;
+        bulk_load(&mut cache, version, Some(Category::General), &pts).await;
+        
+        // the live version had every change
+        assert_eq!(3, clusters_near_origin(&mut cache, MIN_ZOOM_LEVEL, None).await.1);
+        
+        cache.finish_posts_rebuild(version).await.unwrap();
+        
+        for zoom in MIN_ZOOM_LEVEL..=MAX_ZOOM_LEVEL {
+            assert_eq!(3, clusters_near_origin(&mut cache, zoom, None).await.1);
+            assert_eq!(3, clusters_near_origin(&mut cache, zoom, Some(Category::General)).await.1);
+        }
+        
+        let (mut clusters, _) = clusters_near_origin(&mut cache, MAX_ZOOM_LEVEL, None).await;
+        clusters.sort_by(|a, b| a.id.cmp(&b.id));
+        let ids_and_blurbs: Vec<(&str, Option<&str>)> = clusters.iter().map(|cluster| (cluster.id.as_str(), cluster.blurb.as_deref())).collect();
+        assert_eq!(vec![("b", Some("b edited")), ("c", Some("c")), ("d", Some("d"))], ids_and_blurbs);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis, see `stand_ins::redis_config`"]
+    async fn zooms_stay_nested_as_posts_come_and_go() {
+        let mut cache = live_cache(3).await;
+        
+        // spread over a couple degrees, so they're clustered differently on each zoom
+        let posts: Vec<(String, f64, f64)> = (0..60).map(|i| (format!("p{i}"), (i as f64 * 0.37) % 2.0, (i as f64 * 0.61) % 2.0)).collect();
+        for (i, (id, x, y)) in posts.iter().enumerate() {
+            cache.add_post_pt(id, *x, *y, id, if i % 2 == 0 { Category::General } else { Category::Event }).await.unwrap();
+        }
+        for (i, (id, _, _)) in posts.iter().enumerate().filter(|(i, _)| i % 3 == 0) {
+            cache.del_post(id, if i % 2 == 0 { Category::General } else { Category::Event }).await.unwrap();
+        }
+        
+        let positions: HashMap<&String, (f64, f64)> = posts.iter().map(|(id, x, y)| (id, (*x, *y))).collect();
+        let version: u64 = cache.posts_cache.get(LIVE_VERSION_KEY).await.unwrap();
+        for (category, posts_in_layer) in [(None, 40), (Some(Category::General), 20)] {
+            let layer = LayerKeys::new(version, category);
+            
+            let mut owners = vec![];
+            for zoom in MIN_ZOOM_LEVEL..=MAX_ZOOM_LEVEL {
+                let owners_in_zoom: HashMap<String, String> = cache.posts_cache.hgetall(layer.owners(zoom)).await.unwrap();
+                assert_eq!(posts_in_layer, owners_in_zoom.len());
+                assert_eq!(posts_in_layer, clusters_near_origin(&mut cache, zoom, category).await.1);
+                
+                // each cluster is named after one of its posts, and holds how many there are and where they are exactly
+                let mut members: HashMap<&String, Vec<(f64, f64)>> = HashMap::new();
+                for (post, owner) in &owners_in_zoom {
+                    members.entry(owner).or_default().push(positions[post]);
+                }
+                for (owner, members) in members {
+                    assert_eq!(Some(owner), owners_in_zoom.get(owner));
+                    let fields: ClusterFields = cache.posts_cache.hget(layer.cluster(zoom, owner), &["size", "sum_x", "sum_y"]).await.unwrap();
+                    assert_eq!(CachedCluster::new(owner, members), CachedCluster::from_fields(owner.clone(), zoom, fields).unwrap());
+                }
+                
+                owners.push(owners_in_zoom);
+            }
+            
+            // posts in the same cluster are in the same cluster on every zoom below it too
+            for (below, above) in owners.iter().zip(&owners[1..]) {
+                let mut parents: HashMap<&String, &String> = HashMap::new();
+                for (post, owner) in above {
+                    assert_eq!(&below[post], *parents.entry(owner).or_insert(&below[post]), "{post} left its cluster's parent");
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis, see `stand_ins::redis_config`"]
+    async fn inconsistent_clusters_are_errors() {
+        let mut cache = live_cache(4).await;
+        cache.add_post_pt("a", 0.0, 0.0, "a", Category::General).await.unwrap();
+        cache.add_post_pt("b", 0.001, 0.0, "b", Category::General).await.unwrap();
+        
+        let version: u64 = cache.posts_cache.get(LIVE_VERSION_KEY).await.unwrap();
+        let layer = LayerKeys::new(version, None);
+        
+        // a post that isn't in a cluster can't be taken out of it
+        let _: () = cache.posts_cache.hdel(layer.owners(MIN_ZOOM_LEVEL), "b").await.unwrap();
+        assert!(cache.del_post("b", Category::General).await.is_err());
+        
+        // and a cluster without a size can't be merged with
+        let _: () = cache.posts_cache.hdel(layer.cluster(MIN_ZOOM_LEVEL, "a"), "size").await.unwrap();
+        assert!(cache.add_post_pt("c", 0.002, 0.0, "c", Category::General).await.is_err());
+    }
+
+    #[test]
+    fn changes_are_logged_as_json() {
+        let change = PostChange::Del { id: "post".to_string(), category: Category::LostAndFound };
+        let logged = serde_json::to_string(&change).unwrap();
+        
+        assert_eq!(r#"{"op":"del","id":"post","category":"lost-and-found"}"#, logged);
+        assert_eq!(change, serde_json::from_str(&logged).unwrap());
+    }
+
+    #[tokio::test]
+    async fn redis_errors_are_returned() {
+        let mut cache = MapCache::new(&failing_config().await).await.unwrap();
+        let view = Rect { top: 10.0, bottom: -10.0, left: -10.0, right: 10.0 };
+        
+        assert!(cache.geoquery_post_pts(4, &view, None).await.is_err());
+        assert!(cache.geoquery_post_pts(4, &view, Some(Category::Event)).await.is_err());
+        assert!(cache.geoquery_users(&view).await.is_err());
+        assert!(cache.get_token_gen("uid").await.is_err());
+        assert!(cache.update_blurb("post", "blurb", Category::Event).await.is_err());
+        assert!(cache.is_posts_cache_current().await.is_err());
+        assert!(cache.start_posts_rebuild().await.is_err());
+        assert!(cache.bulk_add_pts(2, None, &[Cluster::new(0.0, 0.0)]).await.is_err());
+        assert!(cache.bulk_add_clusters(2, None, 4, &[(Cluster::new(0.0, 0.0), vec![0])], &[Cluster::new(0.0, 0.0)]).await.is_err());
+        
+        // can't lock the posts cache, so gives up instead of retrying forever
+        assert!(cache.add_post_pt("post", 0.0, 0.0, "blurb", Category::General).await.is_err());
+        assert!(cache.del_post("post", Category::General).await.is_err());
+    }
+}
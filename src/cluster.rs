@@ -1,20 +1,24 @@
 use std::collections::{HashSet, HashMap};
-use mongodb::bson::Document;
+use mongodb::bson::{document::ValueAccessError, Document};
 use serde::Serialize;
 
-use crate::db::gen_id;
-
 pub const MIN_ZOOM_LEVEL: usize = 3;
 pub const MAX_ZOOM_LEVEL: usize = 18;
 
+/// posts aren't clustered on `MAX_ZOOM_LEVEL`, so the radius there is 0
 pub fn get_cluster_radius_meters(zoom_level: usize) -> f64 {
     const FIFTY_PX_IN_METERS_AT_ZOOM_0: f64 = 7827151.696402048;
+    
+    if zoom_level >= MAX_ZOOM_LEVEL { return 0.0 }
 
     FIFTY_PX_IN_METERS_AT_ZOOM_0 / 2.0_f64.powf(zoom_level as f64)
 }
 
+/// see `get_cluster_radius_meters`
 pub fn get_cluster_radius_degrees(zoom_level: usize) -> f64 {
     const FIFTY_PX_IN_DEG_AT_ZOOM_0: f64 = 70.3125;
+    
+    if zoom_level >= MAX_ZOOM_LEVEL { return 0.0 }
 
     FIFTY_PX_IN_DEG_AT_ZOOM_0 / 2.0_f64.powf(zoom_level as f64)
 }
@@ -39,10 +43,12 @@ pub struct Cluster {
 }
 impl Cluster {
     
+    #[cfg(test)]
     pub fn new(x: f64, y: f64) -> Self {
-        Self { pos: (x, y), size: None, id: gen_id(), blurb: None }
+        Self { pos: (x, y), size: None, id: crate::db::gen_id(), blurb: None }
     }
     
+    #[cfg(test)]
     pub fn with_blurb(mut self, blurb: String) -> Self {
         self.blurb = Some(blurb);
         self
@@ -79,48 +85,61 @@ impl Cluster {
     
 }
 
-impl From<Document> for Cluster {
-    fn from(poi_doc: Document) -> Self {
+impl TryFrom<Document> for Cluster {
+    type Error = ValueAccessError;
+    
+    fn try_from(poi_doc: Document) -> Result<Self, Self::Error> {
+        
+        let [ref x, ref y, ..] = poi_doc.get_array("pos")?[..]
+        else { return Err(ValueAccessError::UnexpectedType) };
         
-        let [ref x, ref y] = poi_doc.get_array("pos").unwrap()[..2] 
-        else { panic!("'pos' array doesn't have enough elements") };
+        let (Some(x), Some(y)) = (x.as_f64(), y.as_f64())
+        else { return Err(ValueAccessError::UnexpectedType) };
         
-        Self {
-            pos: (x.as_f64().unwrap(), y.as_f64().unwrap()),
+        Ok(Self {
+            pos: (x, y),
             size: None,
-            id: poi_doc.get_str("_id").unwrap().to_string(),
-            blurb: Some(poi_doc.get_str("blurb").unwrap().to_string())
-        }
+            id: poi_doc.get_str("_id")?.to_string(),
+            blurb: Some(poi_doc.get_str("blurb")?.to_string())
+        })
     }
 }
 
 pub fn cluster(pts: &[Cluster], radius: f64) -> Vec<Cluster> {
-    if radius <= 0.0 { return pts.to_vec() }
+    cluster_with_members(pts, radius).into_iter().map(|(cluster, _)| cluster).collect()
+}
+
+/// like `cluster`, along with the indices into `pts` of every pt that ended up in each cluster
+pub fn cluster_with_members(pts: &[Cluster], radius: f64) -> Vec<(Cluster, Vec<usize>)> {
+    if radius <= 0.0 { return pts.iter().cloned().enumerate().map(|(i, pt)| (pt, vec![i])).collect() }
 
     // tile pos -> cluster
-    let mut grid: HashMap<(i32, i32), Cluster> = HashMap::new();
+    let mut grid: HashMap<(i32, i32), (Cluster, Vec<usize>)> = HashMap::new();
 
     // sort pois into grid of clusters or pois
-    for new_pt in pts {
+    for (i, new_pt) in pts.iter().enumerate() {
         let bucket = (
             (new_pt.x() / radius).floor() as i32,
             (new_pt.y() / radius).floor() as i32,
         );
 
         match grid.get_mut(&bucket) {
-            None => { grid.insert(bucket, new_pt.clone()); },
-            Some(inhabitant) => { inhabitant.absorb_cluster(new_pt); }
+            None => { grid.insert(bucket, (new_pt.clone(), vec![i])); },
+            Some((inhabitant, members)) => { 
+                inhabitant.absorb_cluster(new_pt); 
+                members.push(i);
+            }
         }
     }
 
     let mut res = vec![];
 
-    let buckets = grid.keys().map(|pos| *pos).collect::<Vec<(i32, i32)>>();
+    let buckets = grid.keys().copied().collect::<Vec<(i32, i32)>>();
     let mut visited = HashSet::new();
 
     for bucket_pos in buckets {
 
-        let mut final_item: Option<Cluster> = None;
+        let mut final_item: Option<(Cluster, Vec<usize>)> = None;
 
         cluster_grid_dfs(&mut grid, radius, bucket_pos, &mut final_item, &mut visited);
 
@@ -132,11 +151,34 @@ pub fn cluster(pts: &[Cluster], radius: f64) -> Vec<Cluster> {
     res
 }
 
+/// clusters `pts` on every zoom from `max_zoom` down to `min_zoom`, each zoom from the clusters of the one above it 
+/// (like supercluster does), so every zoom after the first only goes over clusters. 
+/// returns the clusters of each zoom, lowest zoom first, along with the indices into `pts` of their members
+pub fn cluster_zooms(pts: &[Cluster], min_zoom: usize, max_zoom: usize) -> Vec<Vec<(Cluster, Vec<usize>)>> {
+    let mut zooms = vec![];
+    let mut above = cluster_with_members(pts, get_cluster_radius_degrees(max_zoom));
+    
+    for zoom in (min_zoom..max_zoom).rev() {
+        let clusters: Vec<Cluster> = above.iter().map(|(cluster, _)| cluster.clone()).collect();
+        
+        // members of a cluster are the clusters above it, so go down to their pts
+        let zoom_clusters = cluster_with_members(&clusters, get_cluster_radius_degrees(zoom)).into_iter()
+            .map(|(cluster, members)| (cluster, members.iter().flat_map(|&i| above[i].1.iter().copied()).collect()))
+            .collect();
+        
+        zooms.push(std::mem::replace(&mut above, zoom_clusters));
+    }
+    zooms.push(above);
+    
+    zooms.reverse();
+    zooms
+}
+
 fn cluster_grid_dfs(
-    grid: &mut HashMap<(i32, i32), Cluster>,
+    grid: &mut HashMap<(i32, i32), (Cluster, Vec<usize>)>,
     radius: f64,
     bucket_pos: (i32, i32),
-    final_item: &mut Option<Cluster>,
+    final_item: &mut Option<(Cluster, Vec<usize>)>,
     visited: &mut HashSet<(i32, i32)>
 ) {
 
@@ -148,8 +190,10 @@ fn cluster_grid_dfs(
         None => { final_item.replace(grid[&bucket_pos].clone()); }, 
         
         // add current item to final cluster
-        Some(final_cluster) => {
-            final_cluster.absorb_cluster(grid.get_mut(&bucket_pos).unwrap());
+        Some((final_cluster, final_members)) => {
+            let (inhabitant, members) = &grid[&bucket_pos];
+            final_cluster.absorb_cluster(inhabitant);
+            final_members.extend(members);
         },
     }
 
@@ -164,8 +208,8 @@ fn cluster_grid_dfs(
         (x - 1, y + 1),
         (x + 1, y + 1),
     ] {
-        if let Some(adj_inhabitant) = grid.get( &adj_bucket_pos ) {
-            if adj_inhabitant.dist_to(final_item.as_ref().unwrap()) <= radius {
+        if let Some((adj_inhabitant, _)) = grid.get( &adj_bucket_pos ) {
+            if adj_inhabitant.dist_to(&final_item.as_ref().unwrap().0) <= radius {
                 cluster_grid_dfs(grid, radius, adj_bucket_pos, final_item, visited);
             }
         }
@@ -175,7 +219,7 @@ fn cluster_grid_dfs(
 
 #[cfg(test)]
 mod tests {
-    use super::{cluster, Cluster};
+    use super::{cluster, cluster_with_members, cluster_zooms, get_cluster_radius_degrees, Cluster, MAX_ZOOM_LEVEL};
 
     #[test]
     fn empty() {
@@ -204,7 +248,7 @@ mod tests {
         ];
         
         let res = cluster(pts, 1.0);
-        assert_eq!(true, res.len() > 1);
+        assert!(res.len() > 1);
     }
     
     #[test]
@@ -216,7 +260,7 @@ mod tests {
             
         let res = cluster(pts, 1.0);
         assert_eq!(1, res.len());
-        assert_eq!(true, has_cluster(&res, (1.0, 1.0), Some(2), None));
+        assert!(has_cluster(&res, (1.0, 1.0), Some(2), None));
     }
 
     #[test]
@@ -229,7 +273,7 @@ mod tests {
             
         let res = cluster(pts, 1.0);
         assert_eq!(1, res.len());
-        assert_eq!(true, has_cluster(&res, (0.9, 0.9), Some(3), None));
+        assert!(has_cluster(&res, (0.9, 0.9), Some(3), None));
     }
 
     #[test]
@@ -242,7 +286,69 @@ mod tests {
             
         let res = cluster(pts, 2.0);
         assert_eq!(2, res.len());
-        assert_eq!(true, has_cluster(&res, (0.5, 0.5), Some(2), None));
-        assert_eq!(true, has_cluster(&res, (9.0, 9.0), None, Some("blurb a")));
+        assert!(has_cluster(&res, (0.5, 0.5), Some(2), None));
+        assert!(has_cluster(&res, (9.0, 9.0), None, Some("blurb a")));
+    }
+
+    #[test]
+    fn members_are_tracked() {
+        let pts = &[
+            Cluster::new(9.0, 9.0),
+            Cluster::new(0.0, 0.0),
+            Cluster::new(1.0, 1.0),
+        ];
+        
+        let mut res = cluster_with_members(pts, 2.0);
+        res.sort_by_key(|(cluster, _)| cluster.size());
+        
+        assert_eq!(vec![0], res[0].1);
+        
+        let mut members = res[1].1.clone();
+        members.sort();
+        assert_eq!(vec![1, 2], members);
+        
+        // the merged cluster is named after one of its members
+        assert!(members.iter().any(|&i| pts[i].id == res[1].0.id));
+    }
+
+    #[test]
+    fn max_zoom_isnt_clustered() {
+        assert_eq!(0.0, get_cluster_radius_degrees(MAX_ZOOM_LEVEL));
+        
+        let pts = &[Cluster::new(1.0, 1.0), Cluster::new(1.0, 1.0)];
+        assert_eq!(2, cluster(pts, get_cluster_radius_degrees(MAX_ZOOM_LEVEL)).len());
+    }
+
+    #[test]
+    fn lower_zooms_are_built_from_higher_ones() {
+        let pts = &[
+            Cluster::new(0.0, 0.0),
+            Cluster::new(0.5, 0.0),
+            Cluster::new(2.0, 0.0),
+            Cluster::new(40.0, 0.0),
+        ];
+        
+        // radius is ~2.2 deg on 5, ~4.4 on 4, ~35 on 1
+        let zooms = cluster_zooms(pts, 1, 5);
+        assert_eq!(5, zooms.len());
+        
+        let sizes = |zoom: usize| {
+            let mut sizes: Vec<usize> = zooms[zoom - 1].iter().map(|(cluster, _)| cluster.size()).collect();
+            sizes.sort();
+            sizes
+        };
+        assert_eq!(vec![1, 3], sizes(5));
+        assert_eq!(vec![1, 3], sizes(4));
+        
+        for clusters in &zooms {
+            // every pt is in exactly one cluster, and each cluster's size is its number of members
+            let mut members: Vec<usize> = clusters.iter().flat_map(|(_, members)| members.iter().copied()).collect();
+            members.sort();
+            assert_eq!(vec![0, 1, 2, 3], members);
+            
+            for (cluster, members) in clusters {
+                assert_eq!(cluster.size(), members.len());
+            }
+        }
     }
 }
\ No newline at end of file
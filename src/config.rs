@@ -0,0 +1,450 @@
+use std::{env, fmt, fs, net::SocketAddr, path::{Path, PathBuf}, str::FromStr};
+
+use redis::IntoConnectionInfo;
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+use crate::cluster::{MAX_ZOOM_LEVEL, MIN_ZOOM_LEVEL};
+
+/// path of the config file that is read if `NEARSAY_CONFIG` isn't set
+const DEFAULT_CONFIG_PATH: &str = "nearsay.toml";
+
+/// server settings, read from (in increasing priority) defaults, an optional toml file, and env vars
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// address the http/socket.io server listens on
+    pub bind_addr: String,
+    pub jwt_secret: String,
+    pub access_token_lifetime_secs: u64,
+    pub refresh_token_lifetime_secs: u64,
+
+    pub posts_redis_url: String,
+    pub users_redis_url: String,
+
+    pub mongo_uri: String,
+    pub mongo_db_name: String,
+
+    /// number of days a new post lives before votes/views adjust it, if its author doesn't choose
+    pub post_expiry_days: u64,
+    /// bounds of the lifetime an author can choose for their post
+    pub min_post_lifetime_secs: u64,
+    pub max_post_lifetime_secs: u64,
+    /// number of characters of a post's body shown on the map
+    pub blurb_length: usize,
+
+    /// zoom levels whose clusters are kept in the posts redis cache. every zoom by default, others are clustered from mongodb on each view
+    pub min_cached_zoom: usize,
+    pub max_cached_zoom: usize,
+
+    /// cron schedule (with seconds) of the cleanup job, which deletes attachments that were never posted
+    pub cleanup_cron: String,
+
+    /// how long before it starts an event post shows up on the map
+    pub event_lead_minutes: u64,
+    /// cron schedule (with seconds) of the sweeper, which deletes expired posts (telling clients nearby) and puts event posts on the map
+    pub sweep_cron: String,
+
+    /// how much each thing counts towards a post's rank in the feed. 
+    /// score = `feed_vote_weight` * (likes - dislikes) + `feed_view_weight` * ln(1 + views) - `feed_distance_weight` * km away - `feed_age_weight` * hours old
+    pub feed_vote_weight: f64,
+    pub feed_view_weight: f64,
+    pub feed_distance_weight: f64,
+    pub feed_age_weight: f64,
+
+    /// where uploaded attachments are kept
+    pub storage: StorageKind,
+    /// directory attachments are written to when `storage` is local
+    pub attachments_dir: String,
+    /// largest upload accepted, in bytes
+    pub attachment_max_bytes: usize,
+    /// longest side of generated thumbnails, in pixels
+    pub thumbnail_size: u32,
+    /// only used when `storage` is s3
+    pub s3_bucket: String,
+    pub s3_region: String,
+    pub s3_endpoint: String,
+    pub s3_access_key: String,
+    pub s3_secret_key: String,
+    /// url the bucket's objects are publicly readable at
+    pub s3_public_url: String,
+
+    /// how long to wait for sockets and requests to finish after SIGTERM/ctrl-c before exiting anyway
+    pub shutdown_deadline_secs: u64,
+
+    /// `tracing` filter directives, e.g. "info" or "nearsay_server=debug,warn"
+    pub log_level: String,
+    pub log_format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// human readable, for development
+    Text,
+    /// one json object per line, for production
+    Json,
+}
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected \"text\" or \"json\"".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// files in `attachments_dir`
+    Local,
+    /// an s3-compatible bucket. needs the `s3` feature
+    S3,
+}
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(StorageKind::Local),
+            "s3" => Ok(StorageKind::S3),
+            _ => Err("expected \"local\" or \"s3\"".to_string()),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_addr: "0.0.0.0:21114".to_string(),
+            jwt_secret: String::new(),
+            access_token_lifetime_secs: 15 * 60,
+            refresh_token_lifetime_secs: 30 * 24 * 60 * 60,
+            posts_redis_url: "redis://localhost:6000".to_string(),
+            users_redis_url: "redis://localhost:6001".to_string(),
+            mongo_uri: "mongodb://localhost:27017".to_string(),
+            mongo_db_name: "nearsay".to_string(),
+            post_expiry_days: 7,
+            min_post_lifetime_secs: 60 * 60,
+            max_post_lifetime_secs: 30 * 24 * 60 * 60,
+            blurb_length: 25,
+            min_cached_zoom: MIN_ZOOM_LEVEL,
+            max_cached_zoom: MAX_ZOOM_LEVEL,
+            cleanup_cron: "0 0 0 * * *".to_string(),    // every day at 00:00
+            event_lead_minutes: 60,
+            sweep_cron: "0 * * * * *".to_string(),    // every minute
+            feed_vote_weight: 1.0,
+            feed_view_weight: 0.5,
+            feed_distance_weight: 1.0,
+            feed_age_weight: 0.25,
+            storage: StorageKind::Local,
+            attachments_dir: "attachments".to_string(),
+            attachment_max_bytes: 10 * 1024 * 1024,
+            thumbnail_size: 256,
+            s3_bucket: String::new(),
+            s3_region: String::new(),
+            s3_endpoint: String::new(),
+            s3_access_key: String::new(),
+            s3_secret_key: String::new(),
+            s3_public_url: String::new(),
+            shutdown_deadline_secs: 10,
+            log_level: "info".to_string(),
+            log_format: LogFormat::Text,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Env { var: &'static str, value: String, reason: String },
+
+    /// `(field, reason)` of every setting that failed validation
+    Invalid(Vec<(&'static str, String)>),
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "couldn't read config file {}: {e}", path.display()),
+            ConfigError::Parse(path, e) => write!(f, "couldn't parse config file {}: {e}", path.display()),
+            ConfigError::Env { var, value, reason } => write!(f, "env var {var}={value:?} is invalid: {reason}"),
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid config:")?;
+                for (field, reason) in problems {
+                    write!(f, "\n- {field}: {reason}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+impl std::error::Error for ConfigError {}
+
+impl Config {
+
+    /// reads the toml file at `NEARSAY_CONFIG` (or `nearsay.toml` if it exists), applies env var overrides, then validates
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match env::var_os("NEARSAY_CONFIG") {
+            Some(path) => Self::from_file(Path::new(&path))?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?,
+            None => Self::default(),
+        };
+
+        config.apply_env(|var| env::var(var).ok())?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    /// overrides settings with any `NEARSAY_*` vars returned by `get_var`
+    fn apply_env(&mut self, get_var: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+
+        fn parse<T: FromStr>(var: &'static str, value: String) -> Result<T, ConfigError>
+        where T::Err: fmt::Display
+        {
+            value.parse().map_err(|e: T::Err| ConfigError::Env { var, reason: e.to_string(), value })
+        }
+
+        // kept for compatibility with existing .env files
+        if let Some(v) = get_var("JWT_SECRET")                  { self.jwt_secret = v; }
+
+        if let Some(v) = get_var("NEARSAY_BIND_ADDR")           { self.bind_addr = v; }
+        if let Some(v) = get_var("NEARSAY_JWT_SECRET")          { self.jwt_secret = v; }
+        if let Some(v) = get_var("NEARSAY_ACCESS_TOKEN_LIFETIME_SECS")  { self.access_token_lifetime_secs = parse("NEARSAY_ACCESS_TOKEN_LIFETIME_SECS", v)?; }
+        if let Some(v) = get_var("NEARSAY_REFRESH_TOKEN_LIFETIME_SECS") { self.refresh_token_lifetime_secs = parse("NEARSAY_REFRESH_TOKEN_LIFETIME_SECS", v)?; }
+        if let Some(v) = get_var("NEARSAY_POSTS_REDIS_URL")     { self.posts_redis_url = v; }
+        if let Some(v) = get_var("NEARSAY_USERS_REDIS_URL")     { self.users_redis_url = v; }
+        if let Some(v) = get_var("NEARSAY_MONGO_URI")           { self.mongo_uri = v; }
+        if let Some(v) = get_var("NEARSAY_MONGO_DB_NAME")       { self.mongo_db_name = v; }
+        if let Some(v) = get_var("NEARSAY_POST_EXPIRY_DAYS")    { self.post_expiry_days = parse("NEARSAY_POST_EXPIRY_DAYS", v)?; }
+        if let Some(v) = get_var("NEARSAY_MIN_POST_LIFETIME_SECS")  { self.min_post_lifetime_secs = parse("NEARSAY_MIN_POST_LIFETIME_SECS", v)?; }
+        if let Some(v) = get_var("NEARSAY_MAX_POST_LIFETIME_SECS")  { self.max_post_lifetime_secs = parse("NEARSAY_MAX_POST_LIFETIME_SECS", v)?; }
+        if let Some(v) = get_var("NEARSAY_BLURB_LENGTH")        { self.blurb_length = parse("NEARSAY_BLURB_LENGTH", v)?; }
+        if let Some(v) = get_var("NEARSAY_MIN_CACHED_ZOOM")     { self.min_cached_zoom = parse("NEARSAY_MIN_CACHED_ZOOM", v)?; }
+        if let Some(v) = get_var("NEARSAY_MAX_CACHED_ZOOM")     { self.max_cached_zoom = parse("NEARSAY_MAX_CACHED_ZOOM", v)?; }
+        if let Some(v) = get_var("NEARSAY_CLEANUP_CRON")        { self.cleanup_cron = v; }
+        if let Some(v) = get_var("NEARSAY_EVENT_LEAD_MINUTES")  { self.event_lead_minutes = parse("NEARSAY_EVENT_LEAD_MINUTES", v)?; }
+        if let Some(v) = get_var("NEARSAY_SWEEP_CRON")          { self.sweep_cron = v; }
+        if let Some(v) = get_var("NEARSAY_FEED_VOTE_WEIGHT")    { self.feed_vote_weight = parse("NEARSAY_FEED_VOTE_WEIGHT", v)?; }
+        if let Some(v) = get_var("NEARSAY_FEED_VIEW_WEIGHT")    { self.feed_view_weight = parse("NEARSAY_FEED_VIEW_WEIGHT", v)?; }
+        if let Some(v) = get_var("NEARSAY_FEED_DISTANCE_WEIGHT")    { self.feed_distance_weight = parse("NEARSAY_FEED_DISTANCE_WEIGHT", v)?; }
+        if let Some(v) = get_var("NEARSAY_FEED_AGE_WEIGHT")     { self.feed_age_weight = parse("NEARSAY_FEED_AGE_WEIGHT", v)?; }
+        if let Some(v) = get_var("NEARSAY_STORAGE")             { self.storage = parse("NEARSAY_STORAGE", v)?; }
+        if let Some(v) = get_var("NEARSAY_ATTACHMENTS_DIR")     { self.attachments_dir = v; }
+        if let Some(v) = get_var("NEARSAY_ATTACHMENT_MAX_BYTES")    { self.attachment_max_bytes = parse("NEARSAY_ATTACHMENT_MAX_BYTES", v)?; }
+        if let Some(v) = get_var("NEARSAY_THUMBNAIL_SIZE")      { self.thumbnail_size = parse("NEARSAY_THUMBNAIL_SIZE", v)?; }
+        if let Some(v) = get_var("NEARSAY_S3_BUCKET")           { self.s3_bucket = v; }
+        if let Some(v) = get_var("NEARSAY_S3_REGION")           { self.s3_region = v; }
+        if let Some(v) = get_var("NEARSAY_S3_ENDPOINT")         { self.s3_endpoint = v; }
+        if let Some(v) = get_var("NEARSAY_S3_ACCESS_KEY")       { self.s3_access_key = v; }
+        if let Some(v) = get_var("NEARSAY_S3_SECRET_KEY")       { self.s3_secret_key = v; }
+        if let Some(v) = get_var("NEARSAY_S3_PUBLIC_URL")       { self.s3_public_url = v; }
+        if let Some(v) = get_var("NEARSAY_SHUTDOWN_DEADLINE_SECS")  { self.shutdown_deadline_secs = parse("NEARSAY_SHUTDOWN_DEADLINE_SECS", v)?; }
+        if let Some(v) = get_var("NEARSAY_LOG_LEVEL")           { self.log_level = v; }
+        if let Some(v) = get_var("NEARSAY_LOG_FORMAT")          { self.log_format = parse("NEARSAY_LOG_FORMAT", v)?; }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = vec![];
+
+        if let Err(e) = self.bind_addr.parse::<SocketAddr>() {
+            problems.push(("bind_addr", format!("{:?} isn't a socket address: {e}", self.bind_addr)));
+        }
+        if self.jwt_secret.is_empty() {
+            problems.push(("jwt_secret", "must be set (NEARSAY_JWT_SECRET or JWT_SECRET)".to_string()));
+        }
+        if self.access_token_lifetime_secs == 0 {
+            problems.push(("access_token_lifetime_secs", "must be at least 1".to_string()));
+        }
+        if self.refresh_token_lifetime_secs <= self.access_token_lifetime_secs {
+            problems.push(("refresh_token_lifetime_secs", format!("must be longer than access_token_lifetime_secs ({})", self.access_token_lifetime_secs)));
+        }
+        for (field, url) in [("posts_redis_url", &self.posts_redis_url), ("users_redis_url", &self.users_redis_url)] {
+            if let Err(e) = url.as_str().into_connection_info() {
+                problems.push((field, format!("{url:?} isn't a redis url: {e}")));
+            }
+        }
+        if !self.mongo_uri.starts_with("mongodb://") && !self.mongo_uri.starts_with("mongodb+srv://") {
+            problems.push(("mongo_uri", format!("{:?} must start with mongodb:// or mongodb+srv://", self.mongo_uri)));
+        }
+        if self.mongo_db_name.is_empty() {
+            problems.push(("mongo_db_name", "must not be empty".to_string()));
+        }
+        if self.post_expiry_days == 0 {
+            problems.push(("post_expiry_days", "must be at least 1".to_string()));
+        }
+        if self.min_post_lifetime_secs == 0 {
+            problems.push(("min_post_lifetime_secs", "must be at least 1".to_string()));
+        }
+        if self.min_post_lifetime_secs > self.max_post_lifetime_secs {
+            problems.push(("min_post_lifetime_secs", format!("must be <= max_post_lifetime_secs ({})", self.max_post_lifetime_secs)));
+        }
+        if self.blurb_length == 0 {
+            problems.push(("blurb_length", "must be at least 1".to_string()));
+        }
+        if self.min_cached_zoom > self.max_cached_zoom {
+            problems.push(("min_cached_zoom", format!("must be <= max_cached_zoom ({})", self.max_cached_zoom)));
+        }
+        for (field, zoom) in [("min_cached_zoom", self.min_cached_zoom), ("max_cached_zoom", self.max_cached_zoom)] {
+            if !(MIN_ZOOM_LEVEL..=MAX_ZOOM_LEVEL).contains(&zoom) {
+                problems.push((field, format!("must be within {MIN_ZOOM_LEVEL}..={MAX_ZOOM_LEVEL}")));
+            }
+        }
+        for (field, cron) in [("cleanup_cron", &self.cleanup_cron), ("sweep_cron", &self.sweep_cron)] {
+            if let Err(e) = croner::Cron::new(cron).with_seconds_required().with_dom_and_dow().parse() {
+                problems.push((field, format!("{cron:?} isn't a cron schedule with seconds: {e}")));
+            }
+        }
+        for (field, weight) in [
+            ("feed_vote_weight", self.feed_vote_weight), ("feed_view_weight", self.feed_view_weight),
+            ("feed_distance_weight", self.feed_distance_weight), ("feed_age_weight", self.feed_age_weight),
+        ] {
+            if !(weight.is_finite() && weight >= 0.0) {
+                problems.push((field, "must be a number >= 0".to_string()));
+            }
+        }
+        match self.storage {
+            StorageKind::Local if self.attachments_dir.is_empty() => problems.push(("attachments_dir", "must not be empty".to_string())),
+            StorageKind::S3 if !cfg!(feature = "s3") => problems.push(("storage", "s3 storage needs the server to be built with the `s3` feature".to_string())),
+            StorageKind::S3 => {
+                for (field, value) in [
+                    ("s3_bucket", &self.s3_bucket), ("s3_region", &self.s3_region), ("s3_endpoint", &self.s3_endpoint),
+                    ("s3_access_key", &self.s3_access_key), ("s3_secret_key", &self.s3_secret_key), ("s3_public_url", &self.s3_public_url),
+                ] {
+                    if value.is_empty() {
+                        problems.push((field, "must be set when storage is s3".to_string()));
+                    }
+                }
+            },
+            _ => {},
+        }
+        if self.attachment_max_bytes == 0 {
+            problems.push(("attachment_max_bytes", "must be at least 1".to_string()));
+        }
+        if self.thumbnail_size == 0 {
+            problems.push(("thumbnail_size", "must be at least 1".to_string()));
+        }
+        if self.shutdown_deadline_secs == 0 {
+            problems.push(("shutdown_deadline_secs", "must be at least 1".to_string()));
+        }
+        if let Err(e) = EnvFilter::try_new(&self.log_level) {
+            problems.push(("log_level", format!("{:?} isn't a log filter: {e}", self.log_level)));
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigError::Invalid(problems)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{Config, ConfigError, LogFormat, StorageKind};
+
+    fn valid() -> Config {
+        Config { jwt_secret: "secret".to_string(), ..Config::default() }
+    }
+
+    fn invalid_fields(config: &Config) -> Vec<&'static str> {
+        match config.validate() {
+            Err(ConfigError::Invalid(problems)) => problems.into_iter().map(|(field, _)| field).collect(),
+            other => panic!("expected invalid config, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn defaults_need_only_a_secret() {
+        assert!(valid().validate().is_ok());
+        assert_eq!(vec!["jwt_secret"], invalid_fields(&Config::default()));
+    }
+
+    #[test]
+    fn reports_every_invalid_field() {
+        let config = Config {
+            bind_addr: "localhost".to_string(),
+            mongo_uri: "localhost:27017".to_string(),
+            max_post_lifetime_secs: 60,
+            min_cached_zoom: 6,
+            max_cached_zoom: 5,
+            cleanup_cron: "every night".to_string(),
+            feed_age_weight: -1.0,
+            feed_view_weight: f64::NAN,
+            ..valid()
+        };
+        assert_eq!(
+            vec!["bind_addr", "mongo_uri", "min_post_lifetime_secs", "min_cached_zoom", "cleanup_cron", "feed_view_weight", "feed_age_weight"],
+            invalid_fields(&config)
+        );
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let mut config: Config = toml::from_str(r#"
+            jwt_secret = "from file"
+            blurb_length = 40
+            max_cached_zoom = 6
+        "#).unwrap();
+
+        let vars = HashMap::from([
+            ("NEARSAY_JWT_SECRET", "from env"),
+            ("NEARSAY_MAX_CACHED_ZOOM", "7"),
+        ]);
+        config.apply_env(|var| vars.get(var).map(|v| v.to_string())).unwrap();
+
+        assert_eq!("from env", config.jwt_secret);
+        assert_eq!(40, config.blurb_length);
+        assert_eq!(7, config.max_cached_zoom);
+        assert_eq!("redis://localhost:6000", config.posts_redis_url);
+    }
+
+    #[test]
+    fn unparseable_env_var() {
+        let mut config = valid();
+        let res = config.apply_env(|var| (var == "NEARSAY_BLURB_LENGTH").then(|| "long".to_string()));
+        assert!(matches!(res, Err(ConfigError::Env { var: "NEARSAY_BLURB_LENGTH", .. })));
+    }
+
+    #[test]
+    fn log_settings() {
+        let mut config: Config = toml::from_str("log_format = \"json\"").unwrap();
+        assert_eq!(LogFormat::Json, config.log_format);
+
+        config.apply_env(|var| (var == "NEARSAY_LOG_FORMAT").then(|| "text".to_string())).unwrap();
+        assert_eq!(LogFormat::Text, config.log_format);
+
+        let config = Config { log_level: "nearsay_server=loud".to_string(), ..valid() };
+        assert_eq!(vec!["log_level"], invalid_fields(&config));
+    }
+
+    #[test]
+    fn storage_settings() {
+        let config: Config = toml::from_str("storage = \"s3\"\njwt_secret = \"secret\"").unwrap();
+        assert_eq!(StorageKind::S3, config.storage);
+        
+        match cfg!(feature = "s3") {
+            true => assert_eq!(vec!["s3_bucket", "s3_region", "s3_endpoint", "s3_access_key", "s3_secret_key", "s3_public_url"], invalid_fields(&config)),
+            false => assert_eq!(vec!["storage"], invalid_fields(&config)),
+        }
+    }
+
+    #[test]
+    fn unknown_file_keys_are_rejected() {
+        assert!(toml::from_str::<Config>("redis_url = \"redis://localhost\"").is_err());
+    }
+}
@@ -1,50 +1,113 @@
 
 
-use std::time::SystemTime;
+use std::{collections::{HashMap, HashSet}, sync::{atomic::{AtomicBool, Ordering}, Arc}, time::{Duration, Instant}};
 
 use bcrypt::{hash, DEFAULT_COST};
-use chrono::Utc;
 use futures::TryStreamExt;
 use mongodb::{ 
-    bson::{doc, Document}, error::{Error as MongoError, ErrorKind, WriteError, WriteFailure}, options::{Hint, IndexOptions}, results::UpdateResult, Client, Cursor, Database, IndexModel
+    bson::{doc, to_bson, DateTime, Document}, error::{Error as MongoError, ErrorKind, WriteError, WriteFailure}, event::EventHandler, options::{ClientOptions, Hint, IndexOptions, ReturnDocument}, results::UpdateResult, Client, Cursor, Database, IndexModel
 };
-use nearsay_server::NearsayError;
-use serde::de::DeserializeOwned;
-use tokio_cron_scheduler::{Job, JobScheduler};
+use nearsay_server::{current_time_ms, NearsayError};
+use serde::{de::DeserializeOwned, Deserialize};
+use redis::RedisError;
+use tokio::sync::broadcast;
+use tokio_cron_scheduler::{Job, JobScheduler, JobSchedulerError};
+use tracing::{error, info, instrument, warn};
 
-use crate::{area::Rect, cache::{MapCache, UserPOI}, cluster::{cluster, get_cluster_radius_degrees, Cluster, MAX_ZOOM_LEVEL}, types::{get_blurb_from_body, Post, User, VoteKind, POI}};
+use crate::{area::{meters_between, Rect}, attachments::ProcessedImage, cache::{MapCache, UserPOI}, config::Config, metrics, storage::Storage, cluster::{cluster, cluster_zooms, get_cluster_radius_degrees, Cluster}, types::{get_blurb_from_body, parse_hashtags, Attachment, Category, Comment, CommentCursor, FeedCursor, FeedItem, FeedQuery, Post, PostFilter, PostLifetime, PostSummary, Privacy, Profile, Role, SavedCursor, SavedPost, SearchCursor, SearchQuery, SearchResult, User, Vote, VoteKind, COMMENT_LIFETIME_WEIGHT, DAY_MS, MAX_PROFILE_POSTS, POI, SAVE_LIFETIME_WEIGHT, VIEW_LIFETIME_WEIGHT}};
 
 
 
-/// returns # of days since the epoch
-fn today() -> u64 {
-    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap()
-        .as_secs() / 60 / 60 / 24
+/// how long an uploaded attachment can go without being posted before the cleanup deletes it
+const UNPOSTED_ATTACHMENT_LIFETIME_MS: i64 = DAY_MS;
+
+/// for converting between meters and radians
+const EARTH_RADIUS_METERS: f64 = 6_378_100.0;
+
+/// expiries below this are days since the epoch, from before they were in ms. 
+/// no post has expired on day 1,000,000, and no ms expiry is this early
+const LAST_DAY_EXPIRY: i64 = 1_000_000;
+
+/// how many swept posts can pile up before a slow `subscribe_expired_posts` receiver starts missing them
+const EXPIRED_POSTS_CAPACITY: usize = 1024;
+
+/// a post deleted by the sweeper, so clients around it can be told
+#[derive(Clone, Debug)]
+pub struct ExpiredPost {
+    pub id: String,
+    pub pos: [f64; 2],
+}
+
+/// posts that are on the map: every post that isn't timed, and event posts during their window (see `sync_event_windows`)
+fn on_map_filter() -> Document {
+    doc! { "$or": [{ "startsAt": null }, { "onMap": true }] }
 }
 
 #[derive(Clone)]
 pub struct NearsayDB {
     cache: MapCache,
     mongo_db: Database,
+    storage: Storage,
+    config: Arc<Config>,
+    
+    /// false until the first `run_sweep` has made sure the posts cache holds every post on the map
+    cache_ready: Arc<AtomicBool>,
+    /// set when a post was saved to mongodb but couldn't be written to the posts cache, see `posts_cache_write_failed`
+    posts_cache_stale: Arc<AtomicBool>,
+    /// runs the cleanup and sweep jobs. only set on the `NearsayDB` returned by `new`
+    scheduler: Option<JobScheduler>,
+    /// held while posts are swept, put on the map, or the posts cache is rebuilt, so none of those overlap
+    sweep_lock: Arc<tokio::sync::Mutex<()>>,
+    /// every post the sweeper deletes is sent here, see `subscribe_expired_posts`
+    expired_posts: broadcast::Sender<ExpiredPost>,
 }
 impl NearsayDB {
-    pub async fn new() -> Self {
-        let nearsay_db = Self { 
-            cache: MapCache::new().await.unwrap(), 
-            mongo_db: Client::with_uri_str("mongodb://localhost:27017").await.unwrap().database("nearsay")
-        };
+    /// connects, creates indexes, and starts the cleanup and sweep jobs. the posts cache is rebuilt in the background
+    pub async fn new(config: &Config) -> Result<Self, NearsayError> {
+        let mut nearsay_db = Self::connect(config).await?;
         
+        nearsay_db.create_indexes().await?;
+        nearsay_db.migrate_day_expiries().await?;
+        nearsay_db.scheduler = Some(nearsay_db.clone().start_nightly_cleanup_job().await?);
+
+        Ok(nearsay_db)
+    }
+    
+    /// opens the redis connections and mongodb client without touching any data
+    pub async fn connect(config: &Config) -> Result<Self, NearsayError> {
+        Ok(Self { 
+            cache: MapCache::new(config).await?, 
+            mongo_db: Self::mongo_client(config).await?.database(&config.mongo_db_name),
+            storage: Storage::new(config)?,
+            config: Arc::new(config.clone()),
+            cache_ready: Arc::new(AtomicBool::new(false)),
+            posts_cache_stale: Arc::new(AtomicBool::new(false)),
+            scheduler: None,
+            sweep_lock: Arc::new(tokio::sync::Mutex::new(())),
+            expired_posts: broadcast::channel(EXPIRED_POSTS_CAPACITY).0,
+        })
+    }
+    
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+    
+    async fn mongo_client(config: &Config) -> Result<Client, NearsayError> {
+        let mut options = ClientOptions::parse(&config.mongo_uri).await?;
+        options.command_event_handler = Some(EventHandler::callback(metrics::observe_mongo_command));
         
-        // create db indexes ---
-        
-        nearsay_db.mongo_db.collection::<User>("users").create_index(
+        Ok(Client::with_options(options)?)
+    }
+    
+    async fn create_indexes(&self) -> Result<(), NearsayError> {
+        self.mongo_db.collection::<User>("users").create_index(
         IndexModel::builder()
             .keys(doc! {"username": 1 })
             .options(IndexOptions::builder().unique(true).build())
             .build()
-        ).await.unwrap();
+        ).await?;
         
-        nearsay_db.mongo_db.collection::<Document>("votes").create_index(
+        self.mongo_db.collection::<Document>("votes").create_index(
             IndexModel::builder()
             .keys(doc! { "uid": 1, "postId": 1 })
             .options(IndexOptions::builder()
@@ -53,147 +116,501 @@ impl NearsayDB {
                 .build()
             )
             .build()
-        ).await.unwrap();
+        ).await?;
         
-        nearsay_db.mongo_db.collection::<Post>("posts").create_index(
+        self.mongo_db.collection::<Post>("posts").create_index(
             IndexModel::builder().keys(doc! {"pos": "2dsphere" }).build()
-        ).await.unwrap();
+        ).await?;
+        
+        // for filtered map views
+        self.mongo_db.collection::<Post>("posts").create_index(
+            IndexModel::builder()
+            .keys(doc! { "pos": "2dsphere", "category": 1, "tags": 1 })
+            .options(IndexOptions::builder().name("pos-category-tags".to_string()).build())
+            .build()
+        ).await?;
+        
+        // a user saves a post at most once, and lists their saves most recent first
+        self.mongo_db.collection::<Document>("saves").create_index(
+            IndexModel::builder()
+            .keys(doc! { "uid": 1, "postId": 1 })
+            .options(IndexOptions::builder()
+                .name("uid-and-postId".to_string())
+                .unique(true)
+                .build()
+            )
+            .build()
+        ).await?;
+        self.mongo_db.collection::<Document>("saves").create_index(
+            IndexModel::builder()
+            .keys(doc! { "uid": 1, "savedAt": -1, "postId": -1 })
+            .build()
+        ).await?;
+        
+        // for the sweeper, which runs often
+        self.mongo_db.collection::<Post>("posts").create_index(
+            IndexModel::builder().keys(doc! { "expiry": 1 }).build()
+        ).await?;
+        self.mongo_db.collection::<Post>("posts").create_index(
+            IndexModel::builder()
+            .keys(doc! { "endsAt": 1 })
+            .options(IndexOptions::builder().sparse(true).build())
+            .build()
+        ).await?;
+        
+        // for `sync_event_windows`, which runs often
+        self.mongo_db.collection::<Post>("posts").create_index(
+            IndexModel::builder()
+            .keys(doc! { "onMap": 1, "startsAt": 1, "endsAt": 1 })
+            .options(IndexOptions::builder()
+                .name("event-windows".to_string())
+                .partial_filter_expression(doc! { "startsAt": { "$exists": true } })
+                .build()
+            )
+            .build()
+        ).await?;
+        
+        // for profiles, which list an author's posts most recent first
+        self.mongo_db.collection::<Post>("posts").create_index(
+            IndexModel::builder()
+            .keys(doc! { "authorId": 1, "createdAt": -1 })
+            .options(IndexOptions::builder().name("authorId-createdAt".to_string()).build())
+            .build()
+        ).await?;
+        
+        // for `search_posts`. there can only be one text index per collection
+        self.mongo_db.collection::<Post>("posts").create_index(
+            IndexModel::builder()
+            .keys(doc! { "body": "text", "tags": "text" })
+            .options(IndexOptions::builder()
+                .name("body-tags-text".to_string())
+                .weights(doc! { "body": 1, "tags": 3 })
+                .build()
+            )
+            .build()
+        ).await?;
+        
+        // comment pages are the replies to one parent (or top-level comments) of a post, oldest first
+        self.mongo_db.collection::<Comment>("comments").create_index(
+            IndexModel::builder()
+            .keys(doc! { "postId": 1, "parentId": 1, "createdAt": 1, "_id": 1 })
+            .build()
+        ).await?;
         
-        nearsay_db.clone().start_nightly_cleanup_job().await;
+        // only the first comment of each member on a post extends its expiry, so they're marked once
+        self.mongo_db.collection::<Document>("commenters").create_index(
+            IndexModel::builder()
+            .keys(doc! { "postId": 1, "uid": 1 })
+            .options(IndexOptions::builder()
+                .name("postId-and-uid".to_string())
+                .unique(true)
+                .build()
+            )
+            .build()
+        ).await?;
+        
+        // remove refresh tokens once they expire
+        self.mongo_db.collection::<Document>("refresh_tokens").create_index(
+            IndexModel::builder()
+            .keys(doc! { "expiresAt": 1 })
+            .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+            .build()
+        ).await?;
+        
+        Ok(())
+    }
 
-        nearsay_db
+    /// `expiry` used to be a number of days since the epoch. those posts expire at the end of that day
+    async fn migrate_day_expiries(&self) -> Result<(), NearsayError> {
+        let migrated = 
+            self.mongo_db.collection::<Post>("posts")
+            .update_many(
+                doc! { "expiry": { "$lt": LAST_DAY_EXPIRY } },
+                vec![doc! { "$set": { "expiry": { "$multiply": [{ "$add": [{ "$toLong": "$expiry" }, 1_i64] }, DAY_MS] } } }]
+            )
+            .await?;
+        
+        if migrated.modified_count > 0 {
+            info!(posts = migrated.modified_count, "migrated post expiries from days to ms");
+        }
+        Ok(())
     }
 
-    pub async fn get<T>(&self, collection: &str, id: &str) -> Result<Option<T>, ()> 
+    pub async fn get<T>(&self, collection: &str, id: &str) -> Result<Option<T>, NearsayError> 
     where T: Send + Sync + DeserializeOwned
     {
-        self.mongo_db.collection::<T>(collection)
+        Ok(
+            self.mongo_db.collection::<T>(collection)
             .find_one( doc!{ "_id": id } )
-            .await
-            .map_err(|e| eprintln!("error getting item {e}"))
+            .await?
+        )
     }
 
-    async fn delete(&self, collection: &str, id: &str) -> Result<(), ()> {
-        match 
-            self.mongo_db.collection::<Document>(collection)
+    async fn delete(&self, collection: &str, id: &str) -> Result<(), NearsayError> {
+        self.mongo_db.collection::<Document>(collection)
             .delete_one( doc!{ "_id": id } )
-            .await
-        {
-            Err(mongo_err) => {
-                eprintln!("error deleting item {}", mongo_err);
-                Err(())
-            },
-            Ok(_) => Ok(())
-        }
+            .await?;
+        Ok(())
     }
 
-    async fn start_nightly_cleanup_job(&mut self) {
+    /// returns the scheduler running the job
+    async fn start_nightly_cleanup_job(&mut self) -> Result<JobScheduler, NearsayError> {
+        
+        fn sched_err(sched_err: JobSchedulerError) -> NearsayError {
+            error!(error = %sched_err, "job scheduler error");
+            NearsayError::Internal
+        }
 
-        let sched = JobScheduler::new().await.unwrap();
+        let sched = JobScheduler::new().await.map_err(sched_err)?;
         
-        self.run_nightly_cleanup().await.unwrap();
+        // get the posts cache ready now instead of waiting for the first scheduled sweep.
+        // done in the background so health checks can be answered meanwhile
+        let mut db_clone = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = db_clone.run_sweep().await {
+                error!(error = %e, "startup sweep failed, posts cache stays unready until the next sweep");
+            }
+        });
         
         let db_clone = self.clone();
         sched.add(
-            // run every day at 00:00
-            Job::new_async("0 0 0 * * *", 
+            Job::new_async(self.config.cleanup_cron.as_str(), 
                 move |_, _| {
                     let mut db_clone = db_clone.clone();
                     Box::pin(
                         async move {
-                            db_clone.run_nightly_cleanup().await.unwrap()
+                            // try again at the next scheduled time
+                            if let Err(e) = db_clone.run_nightly_cleanup().await {
+                                error!(error = %e, "nightly cleanup failed");
+                            }
                         } 
                     )
                 }
-            ).unwrap()
-        ).await.unwrap();
+            ).map_err(sched_err)?
+        ).await.map_err(sched_err)?;
+        
+        let db_clone = self.clone();
+        sched.add(
+            Job::new_async(self.config.sweep_cron.as_str(), 
+                move |_, _| {
+                    let mut db_clone = db_clone.clone();
+                    Box::pin(
+                        async move {
+                            if let Err(e) = db_clone.run_sweep().await {
+                                error!(error = %e, "sweep failed");
+                            }
+                        } 
+                    )
+                }
+            ).map_err(sched_err)?
+        ).await.map_err(sched_err)?;
 
-        sched.start().await.unwrap();
+        sched.start().await.map_err(sched_err)?;
+        
+        Ok(sched)
+    }
+    
+    /// stops the cleanup and sweep jobs from running again. a run that's already started isn't interrupted
+    pub async fn stop_cleanup_job(&mut self) {
+        if let Some(mut sched) = self.scheduler.take() {
+            if let Err(e) = sched.shutdown().await {
+                error!(error = %e, "couldn't stop job scheduler");
+            }
+        }
+    }
+    
+    /// deletes attachments that were uploaded but never posted. expired posts are left to the sweeper
+    #[instrument(name = "nightly_cleanup", skip_all)]
+    async fn run_nightly_cleanup(&mut self) -> Result<(), NearsayError> {
+        let started = Instant::now();
+        info!("running nightly cleanup");
+        
+        let deleted_attachments = self.delete_attachments(
+            doc! { "postId": null, "uploadedAt": { "$lt": current_time_ms() as i64 - UNPOSTED_ATTACHMENT_LIFETIME_MS } }
+        ).await?;
+        
+        metrics::CLEANUP_SECONDS.observe(started.elapsed().as_secs_f64());
+        
+        info!(
+            deleted_attachments,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "nightly cleanup done"
+        );
+        
+        Ok(())
+    }
+    
+    /// gets the posts cache ready if it isn't, deletes expired posts, and updates which event posts are on the map.
+    /// 
+    /// there's no periodic full rebuild of the posts cache to catch it drifting from mongodb: every change to posts goes through 
+    /// `NearsayDB`, and one that can't be written to the cache marks it stale (see `posts_cache_write_failed`). 
+    /// what's left is the cache losing its keys (a flushed or restarted redis), or another server invalidating it, 
+    /// which is checked for here each sweep
+    #[instrument(name = "sweep", skip_all)]
+    async fn run_sweep(&mut self) -> Result<(), NearsayError> {
+        let sweep_lock = self.sweep_lock.clone();
+        let _sweep_lock = sweep_lock.lock().await;
+        
+        if self.is_cache_ready() && !self.cache.is_posts_cache_current().await? {
+            warn!("posts cache was lost or invalidated, rebuilding it");
+            self.cache_ready.store(false, Ordering::Release);
+        }
+        if !self.is_cache_ready() {
+            self.ready_posts_cache().await?;
+        }
+        
+        let (deleted_posts, deleted_attachments) = self.delete_expired_posts().await?;
+        if deleted_posts > 0 {
+            info!(deleted_posts, deleted_attachments, "swept expired posts");
+        }
+        
+        self.sync_event_windows(true).await
+    }
+    
+    /// a posts cache that was fully built before (e.g. by a previous run of the server) with the same zoom range is used as is. 
+    /// changes are saved to mongodb first, and one that can't be written to the cache after that marks it stale 
+    /// (see `posts_cache_write_failed`), so a cache that's still current hasn't missed any. 
+    /// otherwise a new version is built from mongodb next to it, and swapped in once it's done. `sweep_lock` has to be held
+    async fn ready_posts_cache(&mut self) -> Result<(), NearsayError> {
+        // cleared first, so a write that fails during the rebuild marks the new version stale too
+        let stale = self.posts_cache_stale.swap(false, Ordering::AcqRel);
+        
+        if !stale && self.cache.is_posts_cache_current().await? {
+            info!("posts cache is already built");
+            self.cache_ready.store(true, Ordering::Release);
+            return Ok(())
+        }
+        
+        let res = self.rebuild_posts_cache().await;
+        if res.is_err() {
+            self.posts_cache_stale.fetch_or(stale, Ordering::AcqRel);
+        }
+        res
     }
-    async fn run_nightly_cleanup(&mut self) -> Result<(), mongodb::error::Error> {
+    
+    /// builds a new version of the posts cache from mongodb, see `ready_posts_cache`
+    async fn rebuild_posts_cache(&mut self) -> Result<(), NearsayError> {
+        let started = Instant::now();
+        let version = self.cache.start_posts_rebuild().await?;
+        info!(version, "rebuilding posts cache");
+        
+        // the cache is rebuilt from `onMap`, so bring it up to date first
+        self.sync_event_windows(false).await?;
+        
+        #[derive(Deserialize)]
+        struct CachedPost {
+            _id: String,
+            pos: [f64; 2],
+            body: String,
+            #[serde(default)]
+            category: Category,
+        }
+        
+        // every post is clustered in the layer of all posts, and again in the layer of its category.
+        // posts added, deleted or edited from here on are logged, then replayed onto the new version by `finish_posts_rebuild`
+        let mut all_pts = vec![];
+        let mut category_pts: HashMap<Category, Vec<Cluster>> = HashMap::new();
+        
+        let mut all_posts = 
+            self.mongo_db.collection::<CachedPost>("posts")
+            .find(on_map_filter())
+            .projection(doc! { "pos": 1, "body": 1, "category": 1 })
+            .await?;
+        
+        while let Some(post) = all_posts.try_next().await? {
+            let pt = Cluster { 
+                pos: (post.pos[0], post.pos[1]), 
+                size: None, 
+                id: post._id, 
+                blurb: Some(get_blurb_from_body(&post.body, self.config.blurb_length)),
+            };
+            category_pts.entry(post.category).or_default().push(pt.clone());
+            all_pts.push(pt);
+        }
+        let cached_posts = all_pts.len();
+        
+        let layers = [(None, all_pts)].into_iter().chain(category_pts.into_iter().map(|(category, pts)| (Some(category), pts)));
+        for (category, pts) in layers {
+            self.cache.bulk_add_pts(version, category, &pts).await?;
+            
+            let zooms = cluster_zooms(&pts, self.config.min_cached_zoom, self.config.max_cached_zoom);
+            for (zoom, clusters) in (self.config.min_cached_zoom..).zip(&zooms) {
+                self.cache.bulk_add_clusters(version, category, zoom, clusters, &pts).await?;
+            }
+        }
+        
+        self.cache.finish_posts_rebuild(version).await?;
+        
+        // otherwise it's rebuilt again next sweep
+        if !self.posts_cache_stale.load(Ordering::Acquire) {
+            self.cache_ready.store(true, Ordering::Release);
+        }
+        
+        info!(
+            version,
+            cached_posts,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "posts cache rebuilt"
+        );
+        
+        Ok(())
+    }
+    
+    /// deletes posts past their expiry (or events past their end) one at a time with `delete_post`, 
+    /// and lets `expired_posts` subscribers know about each. returns (# of posts, # of attachments) deleted. 
+    /// `sweep_lock` has to be held
+    async fn delete_expired_posts(&mut self) -> Result<(u64, u64), NearsayError> {
+        let now = current_time_ms() as i64;
         
-        println!("running nightly cleanup at: {}", Utc::now());
+        #[derive(Deserialize)]
+        struct Expired {
+            _id: String,
+            pos: [f64; 2],
+            #[serde(default)]
+            category: Category,
+        }
         
-        let delete_old_posts_res = 
-            self.mongo_db.collection::<Document>("posts")
-            .delete_many(doc! { "expiry": {"$lt": today() as i32} })
+        // events are over when they end, no matter their expiry
+        let expired: Vec<Expired> = 
+            self.mongo_db.collection::<Expired>("posts")
+            .find(doc! { "$or": [
+                { "expiry": { "$lte": now } },
+                { "endsAt": { "$lte": now } },
+            ]})
+            .projection(doc! { "pos": 1, "category": 1 })
+            .await?
+            .try_collect()
             .await?;
-        println!("- delete old posts result: {:?}", delete_old_posts_res);
         
-        self.cache.flush_all_posts().await.unwrap();
-        println!("- cleared posts in map cache");
+        let mut deleted_posts = 0;
+        let mut deleted_attachments = 0;
+        
+        // anything left after an error is picked up by the next sweep
+        for post in expired {
+            deleted_attachments += self.delete_post(&post._id, post.category).await?;
+            deleted_posts += 1;
+            metrics::SWEEP_DELETED_POSTS.inc();
+            
+            // no one listening isn't an error
+            let _ = self.expired_posts.send(ExpiredPost { id: post._id, pos: post.pos });
+        }
+        
+        Ok((deleted_posts, deleted_attachments))
+    }
+    
+    /// called when a change to a post was saved to mongodb but couldn't be written to the posts cache. 
+    /// the change isn't undone, the cache is what's wrong: it stops being read from, and is rebuilt from mongodb on the next sweep. 
+    /// the schema key is deleted too, so a restart doesn't reuse it either
+    async fn posts_cache_write_failed(&mut self, cache_err: RedisError) {
+        error!(error = %cache_err, "couldn't write to posts cache, rebuilding it on the next sweep");
+        
+        self.posts_cache_stale.store(true, Ordering::Release);
+        self.cache_ready.store(false, Ordering::Release);
+        
+        if let Err(e) = self.cache.invalidate_posts_cache().await {
+            error!(error = %e, "couldn't invalidate posts cache");
+        }
+    }
+    
+    /// posts deleted by the sweeper from here on
+    pub fn subscribe_expired_posts(&self) -> broadcast::Receiver<ExpiredPost> {
+        self.expired_posts.subscribe()
+    }
+    
+    /// as if the sweeper had deleted `post`
+    #[cfg(test)]
+    pub fn send_expired_post(&self, post: ExpiredPost) {
+        let _ = self.expired_posts.send(post);
+    }
+    
+    /// puts event posts on the map once they're within `event_lead_minutes` of starting. 
+    /// they're taken off by `delete_expired_posts` when they end. `sweep_lock` has to be held
+    async fn sync_event_windows(&mut self, update_cache: bool) -> Result<(), NearsayError> {
+        let now = current_time_ms() as i64;
+        let lead_ms = self.config.event_lead_minutes as i64 * 60 * 1000;
+        let posts = self.mongo_db.collection::<Post>("posts");
         
-        let mut all_posts = self.mongo_db.collection::<Post>("posts").find(doc! {}).await?;
+        let mut starting = posts.find(doc! { "startsAt": { "$lte": now + lead_ms }, "endsAt": { "$gt": now }, "onMap": false }).await?;
+        let mut shown = 0;
+        while let Some(post) = starting.try_next().await? {
+            if update_cache {
+                self.cache.add_post_pt(&post._id, post.pos[0], post.pos[1], &get_blurb_from_body(&post.body, self.config.blurb_length), post.category).await?;
+            }
+            posts.update_one(doc! { "_id": &post._id }, doc! { "$set": { "onMap": true } }).await?;
+            shown += 1;
+        }
         
-        while let Some(post) = all_posts.try_next().await.unwrap() {
-            self.cache.add_post_pt(&post._id, post.pos[0], post.pos[1], &get_blurb_from_body(&post.body)).await.unwrap();
+        if shown > 0 {
+            info!(shown, "put event posts on the map");
         }
-        println!("- added all posts back into cache");
         
         Ok(())
     }
 
-    pub async fn get_user_from_username(&self, username: &str) -> Result<Option<User>, ()> {
-        match 
+    pub async fn get_user_from_username(&self, username: &str) -> Result<Option<User>, NearsayError> {
+        Ok(
             self.mongo_db.collection::<User>("users")
             .find_one(doc! {"username": username})
             .hint(Hint::Name("username_1".to_string()))
-            .await
-        {
-            Err(mongo_err) => {
-                eprintln!("mongodb error when getting user: {}", &mongo_err);
-                Err(())
-            },
-            Ok(user) => Ok(user),
-        }
+            .await?
+        )
     }
     
-    pub async fn get_cache_username(&mut self, uid: &str) -> Result<Option<String>, ()> {
-        self.cache.get_username(uid).await.map_err(|e| eprintln!("when getting cached username {e}"))
+    pub async fn get_cache_username(&mut self, uid: &str) -> Result<Option<String>, NearsayError> {
+        Ok(self.cache.get_username(uid).await?)
     }
-    pub async fn get_cache_pos_and_avatar(&mut self, uid: &str) -> Result<Option<((f64, f64), usize)>, ()> {
-        self.cache.get_pos_and_avatar(uid).await.map_err(|e| eprintln!("when getting guest: {e}"))
+    pub async fn get_cache_pos_and_avatar(&mut self, uid: &str) -> Result<Option<((f64, f64), usize)>, NearsayError> {
+        Ok(self.cache.get_pos_and_avatar(uid).await?)
     }
     
-    pub async fn add_user_to_cache(&mut self, uid: &str, socket_id: &str, pos: &[f64], avatar: usize, username: Option<&str>) -> Result<(), ()> {
-        self.cache.add_user(uid, socket_id, pos[0], pos[1], avatar, username).await
-        .map_err(|e| eprintln!("when adding user to cache: {e}"))
+    pub async fn add_user_to_cache(&mut self, uid: &str, socket_id: &str, pos: &[f64], avatar: usize, username: Option<&str>) -> Result<(), NearsayError> {
+        self.cache.add_user(uid, socket_id, pos[0], pos[1], avatar, username).await?;
+        self.touch_last_seen(uid).await;
+        Ok(())
     }
     
-    pub async fn delete_user_from_cache(&mut self, uid: Option<&str>, socket_id: &str) -> Result<(), ()> {
+    pub async fn delete_user_from_cache(&mut self, uid: Option<&str>, socket_id: &str) -> Result<(), NearsayError> {
         match uid {
-            Some(uid) => self.cache.del_user(uid, socket_id).await,
-            None => self.cache.del_user_from_socket(socket_id).await,
+            Some(uid) => {
+                self.cache.del_user(uid, socket_id).await?;
+                self.touch_last_seen(uid).await;
+            },
+            None => self.cache.del_user_from_socket(socket_id).await?,
+        }
+        Ok(())
+    }
+    
+    /// sets `lastSeen` of the account with this `uid` (guests don't have one) to now.
+    /// failing isn't worth stopping the user from entering/leaving the map over, so it's only logged
+    async fn touch_last_seen(&self, uid: &str) {
+        let res = self.mongo_db.collection::<User>("users")
+            .update_one(doc! { "_id": uid }, doc! { "$set": { "lastSeen": current_time_ms() as i64 } })
+            .await;
+        if let Err(e) = res {
+            warn!(uid, error = %e, "couldn't update last seen");
         }
-        .map_err(|e| eprintln!("when deleting user from cache: {e}"))
     }
     
-    pub async fn get_uid_from_socket(&mut self, socket_id: &str) -> Result<Option<String>, ()> {
-        self.cache.get_uid_from_socket(socket_id).await
-        .map_err(|e| eprintln!("when getting uid from socket: {e}"))
+    pub async fn get_socket_of_user(&mut self, uid: &str) -> Result<Option<String>, NearsayError> {
+        Ok(self.cache.get_socket_of_user(uid).await?)
+    }
+    
+    pub async fn get_uid_from_socket(&mut self, socket_id: &str) -> Result<Option<String>, NearsayError> {
+        Ok(self.cache.get_uid_from_socket(socket_id).await?)
     }
 
     pub async fn insert_user(&mut self, uid: &str, username: &str, password: &str, avatar: usize) -> Result<(), NearsayError> {
 
         // check if username is taken
-        match
+        let taken = 
             self.mongo_db.collection::<User>("users")
             .count_documents(doc! {"username": username })
             .limit(1)
-            .await 
-        {
-            Ok(count) => if count != 0 { return Err(NearsayError::UsernameTaken) },
-            Err(e) => {
-                eprintln!("=when checking if username taken: {e}");
-                return Err(NearsayError::ServerError)
-            },
-        }
+            .await? != 0;
+        if taken { return Err(NearsayError::conflict("username", "username is taken")) }
 
         // hash password (again) to store in db
-        let userhash = hash(password, DEFAULT_COST).map_err(|e| {
-            eprintln!("when hashing password again: {e}");
-            NearsayError::ServerError
-        })?;
+        let userhash = hash(password, DEFAULT_COST)?;
         
         // insert user data into db
         self.mongo_db.collection("users")
@@ -203,25 +620,91 @@ impl NearsayDB {
                     "username": username,
                     "avatar": avatar as i32,
                     "hash": userhash,
+                    "joinedAt": current_time_ms() as i64,
+                    "lastSeen": current_time_ms() as i64,
                 }
-            ).await
-            .map_err(|e| {
-                eprintln!("mongodb error when adding new user: {e}");
-                NearsayError::ServerError
-            })?;
+            ).await?;
             
-        self.cache.edit_user_if_exists(uid, &Some(avatar), &Some(username.to_string())).await.map_err(|e| {
-            eprintln!("when updating cache while adding new user: {e}");
-            NearsayError::ServerError
-        })
+        self.cache.edit_user_if_exists(uid, &Some(avatar), &Some(username.to_string())).await?;
+        
+        Ok(())
+    }
+    
+    /// role of the account with this `uid`, or `Role::Guest` if there isn't one
+    pub async fn get_role(&self, uid: &str) -> Result<Role, NearsayError> {
+        Ok(self.get::<User>("users", uid).await?.map(|user| user.role).unwrap_or(Role::Guest))
+    }
+    
+    /// current token generation of `uid`. tokens issued with an older generation are revoked
+    pub async fn get_token_gen(&mut self, uid: &str) -> Result<u64, NearsayError> {
+        if let Some(gen) = self.cache.get_token_gen(uid).await? {
+            return Ok(gen);
+        }
+        
+        let gen = self.mongo_db.collection::<Document>("token_generations")
+            .find_one(doc! { "_id": uid })
+            .await?
+            .and_then(|doc| doc.get_i64("gen").ok())
+            .unwrap_or(0) as u64;
+        
+        self.cache.fill_token_gen(uid, gen).await?;
+        
+        Ok(gen)
+    }
+    
+    /// revokes every token issued to `uid` so far. 
+    /// the cached generation is deleted before it's bumped, so if anything fails, tokens are checked against mongodb instead of passing
+    pub async fn revoke_tokens(&mut self, uid: &str) -> Result<(), NearsayError> {
+        self.cache.del_token_gen(uid).await?;
+        
+        let updated = self.mongo_db.collection::<Document>("token_generations")
+            .find_one_and_update(doc! { "_id": uid }, doc! { "$inc": { "gen": 1_i64 } })
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .await?;
+        
+        // a token checked since the delete may have cached the old generation again
+        let gen = updated.and_then(|doc| doc.get_i64("gen").ok()).unwrap_or(0) as u64;
+        if let Err(cache_err) = self.cache.set_token_gen(uid, gen).await {
+            if let Err(e) = self.cache.del_token_gen(uid).await {
+                error!(uid, error = %e, "couldn't clear revoked token generation");
+            }
+            return Err(cache_err.into());
+        }
+        
+        self.mongo_db.collection::<Document>("refresh_tokens")
+            .delete_many(doc! { "uid": uid })
+            .await?;
+        
+        Ok(())
+    }
+    
+    /// `expiry` is in seconds since epoch
+    pub async fn insert_refresh_token(&self, jti: &str, uid: &str, expiry: u64) -> Result<(), NearsayError> {
+        self.mongo_db.collection::<Document>("refresh_tokens")
+            .insert_one(doc! {
+                "_id": jti,
+                "uid": uid,
+                "expiresAt": DateTime::from_millis(expiry as i64 * 1000),
+            })
+            .await?;
+        Ok(())
+    }
+    
+    /// deletes the refresh token, returning whether it existed
+    pub async fn take_refresh_token(&self, jti: &str, uid: &str) -> Result<bool, NearsayError> {
+        let res = self.mongo_db.collection::<Document>("refresh_tokens")
+            .delete_one(doc! { "_id": jti, "uid": uid })
+            .await?;
+        Ok(res.deleted_count == 1)
     }
     
     /// returns old position of user
-    pub async fn set_user_pos(&mut self, uid: &str, pos: &[f64]) -> Result<(f64, f64), ()> {
-        self.cache.set_user_pos(uid, pos[0], pos[1]).await
+    pub async fn set_user_pos(&mut self, uid: &str, pos: &[f64]) -> Result<(f64, f64), NearsayError> {
+        self.cache.set_user_pos(uid, pos[0], pos[1]).await?.ok_or(NearsayError::NotFound("user"))
     }
 
-    pub async fn edit_user(&mut self, uid: &str, avatar: &Option<usize>, username: &Option<String>) -> Result<(), NearsayError> {
+    pub async fn edit_user(&mut self, uid: &str, avatar: &Option<usize>, username: &Option<String>, privacy: &Option<Privacy>, notify_on_save: Option<bool>) -> Result<(), NearsayError> {
         
         let mut update = doc! {};
         if let Some(avatar) = avatar {
@@ -230,6 +713,13 @@ impl NearsayDB {
         if let Some(username) = username {
             update.insert("username", username);
         }
+        if let Some(privacy) = privacy {
+            update.insert("privacy", to_bson(privacy)?);
+        }
+        if let Some(notify_on_save) = notify_on_save {
+            update.insert("notifyOnSave", notify_on_save);
+        }
+        if update.is_empty() { return Ok(()) }
         
         self.mongo_db.collection::<User>("users")
             .update_one(
@@ -238,100 +728,283 @@ impl NearsayDB {
             )
             .await
             .map_err(|e| match *e.kind {
-                ErrorKind::Write(WriteFailure::WriteError(WriteError {code, ..})) if code == 11000 => NearsayError::UsernameTaken,
-                other => {
-                    eprintln!("error updating user: {}", other);
-                    NearsayError::ServerError
-                }
+                ErrorKind::Write(WriteFailure::WriteError(WriteError {code: 11000, ..})) => NearsayError::conflict("username", "username is taken"),
+                _ => e.into()
             })?;
         
-        self.cache.edit_user_if_exists(uid, avatar, username).await.map_err(|_| NearsayError::ServerError)?;
+        self.cache.edit_user_if_exists(uid, avatar, username).await?;
         
         Ok(())
     }
 
-    pub async fn delete_user(&mut self, uid: &str, socket_id: Option<&str>) -> Result<(), ()> {
+    pub async fn delete_user(&mut self, uid: &str, socket_id: Option<&str>) -> Result<(), NearsayError> {
         if let Some(socket_id) = socket_id {
             self.delete_user_from_cache(Some(uid), socket_id).await?;
         }
         
+        self.revoke_tokens(uid).await?;
+        
         self.delete("users", uid).await?;
 
         // delete user's votes
-        match 
-            self.mongo_db.collection::<Document>("votes")
+        self.mongo_db.collection::<Document>("votes")
             .delete_many(doc! { "uid": uid })
-            .await
-        {
-            Ok(_) => Ok(()),
-            Err(e) => Err(eprintln!("err deleting user votes: {e}"))
-        }
+            .await?;
+        
+        self.mongo_db.collection::<Document>("saves")
+            .delete_many(doc! { "uid": uid })
+            .await?;
+        
+        Ok(())
     }
 
-    pub async fn delete_post(&mut self, post_id: &str) -> Result<(), ()> {
-        self.cache.del_post(post_id).await.map_err(|e| {
-            eprintln!("when deleting post from cache: {e}")
-        })?;
+    /// deletes the post from the cache and mongodb along with its votes, comments, saves and attachments.
+    /// returns the # of attachments deleted
+    pub async fn delete_post(&mut self, post_id: &str, category: Category) -> Result<u64, NearsayError> {
+        self.cache.del_post(post_id, category).await?;
         
         self.delete("posts", post_id).await?;
 
         // delete post votes
-        match 
-            self.mongo_db.collection::<Document>("votes")
+        self.mongo_db.collection::<Document>("votes")
             .delete_many(doc! { "postId": post_id })
-            .await
-        {
-            Ok(_) => Ok(()),
-            Err(_) => Err(())
+            .await?;
+        
+        self.mongo_db.collection::<Document>("comments")
+            .delete_many(doc! { "postId": post_id })
+            .await?;
+        self.mongo_db.collection::<Document>("commenters")
+            .delete_many(doc! { "postId": post_id })
+            .await?;
+        
+        self.mongo_db.collection::<Document>("saves")
+            .delete_many(doc! { "postId": post_id })
+            .await?;
+        
+        self.delete_attachments(doc! { "postId": post_id }).await
+    }
+    
+    /// stores the image and its thumbnail, to be posted later by `uid`
+    pub async fn insert_attachment(&self, uid: &str, image: ProcessedImage) -> Result<Attachment, NearsayError> {
+        let id = gen_id();
+        let key = format!("{id}.{}", image.ext);
+        let thumbnail_key = format!("{id}-thumb.{}", image.ext);
+        
+        self.storage.put(&key, &image.image, image.content_type).await?;
+        self.storage.put(&thumbnail_key, &image.thumbnail, image.content_type).await?;
+        
+        let attachment = Attachment {
+            _id: id,
+            uid: uid.to_string(),
+            postId: None,
+            contentType: image.content_type.to_string(),
+            width: image.width,
+            height: image.height,
+            url: self.storage.url(&key),
+            thumbnailUrl: self.storage.url(&thumbnail_key),
+            key,
+            thumbnailKey: thumbnail_key,
+            uploadedAt: current_time_ms() as i64,
+        };
+        
+        self.mongo_db.collection::<Attachment>("attachments").insert_one(&attachment).await?;
+        
+        Ok(attachment)
+    }
+    
+    /// deletes matching attachments from storage and mongodb, returning how many there were.
+    /// files that fail to delete are logged and skipped, so one bad file doesn't block the rest
+    async fn delete_attachments(&self, filter: Document) -> Result<u64, NearsayError> {
+        let attachments: Vec<Attachment> = 
+            self.mongo_db.collection::<Attachment>("attachments")
+            .find(filter.clone())
+            .await?
+            .try_collect()
+            .await?;
+        
+        for attachment in &attachments {
+            for key in [&attachment.key, &attachment.thumbnailKey] {
+                if let Err(e) = self.storage.delete(key).await {
+                    warn!(key, error = %e, "couldn't delete attachment file");
+                }
+            }
+        }
+        
+        self.mongo_db.collection::<Attachment>("attachments").delete_many(filter).await?;
+        
+        Ok(attachments.len() as u64)
+    }
+
+    /// marks the author's unposted uploads in `attachment_ids` as posted to `post_id`, returning them in the order they were given. 
+    /// claimed in one update that only matches unposted ones, so two posts can't both get the same upload. 
+    /// if any can't be claimed, none are
+    async fn claim_attachments(&self, author_id: &str, attachment_ids: &[String], post_id: &str) -> Result<Vec<Attachment>, NearsayError> {
+        let claimed = self.mongo_db.collection::<Attachment>("attachments")
+            .update_many(
+                doc! { "_id": { "$in": attachment_ids }, "uid": author_id, "postId": null },
+                doc! { "$set": { "postId": post_id } }
+            )
+            .await?;
+        
+        if claimed.modified_count != attachment_ids.len() as u64 {
+            self.unclaim_attachments(post_id).await;
+            return Err(NearsayError::NotFound("attachment"))
+        }
+        
+        let found = async {
+            self.mongo_db.collection::<Attachment>("attachments")
+                .find(doc! { "postId": post_id })
+                .await?
+                .try_collect::<Vec<Attachment>>()
+                .await
+        }.await;
+        let mut attachments = match found {
+            Ok(attachments) => attachments,
+            Err(mongo_err) => {
+                self.unclaim_attachments(post_id).await;
+                return Err(mongo_err.into())
+            },
+        };
+        
+        attachments.sort_by_key(|attachment| attachment_ids.iter().position(|id| *id == attachment._id));
+        Ok(attachments)
+    }
+    
+    /// puts back attachments claimed for a post that wasn't made. failing is only logged, 
+    /// those uploads stay claimed by a post that doesn't exist and have to be uploaded again
+    async fn unclaim_attachments(&self, post_id: &str) {
+        let res = self.mongo_db.collection::<Attachment>("attachments")
+            .update_many(doc! { "postId": post_id }, doc! { "$set": { "postId": null } })
+            .await;
+        if let Err(e) = res {
+            warn!(post_id, error = %e, "couldn't unclaim attachments");
         }
     }
 
-    /// returns (post id, blurb)
-    pub async fn insert_post(&mut self, author_id: Option<&str>, pos: &[f64], body: &str) -> Result<(String, String), ()> {
+    /// returns (post id, blurb). `attachment_ids` have to be unposted uploads of the author.
+    /// 
+    /// events that haven't started yet aren't put on the map until they're about to
+    pub async fn insert_post(&mut self, author_id: Option<&str>, pos: &[f64], body: &str, category: Category, lifetime: PostLifetime, attachment_ids: &[String]) -> Result<(String, String), NearsayError> {
+        let now = current_time_ms() as i64;
+        
+        let (on_map, expiry) = match lifetime {
+            PostLifetime::Default => (true, now + self.config.post_expiry_days as i64 * DAY_MS),
+            PostLifetime::Secs(secs) => {
+                let (min, max) = (self.config.min_post_lifetime_secs, self.config.max_post_lifetime_secs);
+                if !(min..=max).contains(&secs) {
+                    return Err(NearsayError::validation("lifetime_secs", format!("must be within {min}..={max}")));
+                }
+                (true, now + secs as i64 * 1000)
+            },
+            PostLifetime::Event(event) => (event.is_visible(now, self.config.event_lead_minutes as i64 * 60 * 1000), event.ends_at),
+        };
         
         let post_id = gen_id();
         
-        if let Err(mongo_err) = self.mongo_db.collection("posts").insert_one(doc! {
+        // the same upload given twice is attached once
+        let mut attachment_ids = attachment_ids.to_vec();
+        let mut seen = HashSet::new();
+        attachment_ids.retain(|id| seen.insert(id.clone()));
+        
+        let mut attachments: Vec<Attachment> = vec![];
+        if !attachment_ids.is_empty() {
+            let Some(author_id) = author_id else { return Err(NearsayError::Forbidden) };
+            attachments = self.claim_attachments(author_id, &attachment_ids, &post_id).await?;
+        }
+        
+        let mut post = doc! {
             "_id": post_id.clone(),
             "pos": pos,
 
             "authorId": author_id,
             "body": body,
+            "category": category.as_str(),
+            "tags": parse_hashtags(body),
             "likes": 0,
             "dislikes": 0,
             "views": 0,
-            "expiry": (today() + 7) as i64,
-        }).await {
-            eprintln!("error inserting new post: {}", mongo_err);
-            return Err(());
+            "createdAt": now,
+            "comments": 0,
+            "saves": 0,
+            "onMap": on_map,
+            "expiry": expiry,
+            "attachments": to_bson(&attachments)?,
+        };
+        // left out of untimed posts to keep them out of the "event-windows" index
+        if let PostLifetime::Event(event) = lifetime {
+            post.insert("startsAt", event.starts_at);
+            post.insert("endsAt", event.ends_at);
+        }
+        if let Err(mongo_err) = self.mongo_db.collection("posts").insert_one(post).await {
+            self.unclaim_attachments(&post_id).await;
+            return Err(mongo_err.into());
         }
 
-        let blurb = get_blurb_from_body(body);
+        let blurb = get_blurb_from_body(body, self.config.blurb_length);
+        
+        // events that haven't started yet are put on the map by `sync_event_windows`.
+        // the post is saved either way, so a cache error doesn't fail the request (it'd be retried as a duplicate)
+        if on_map {
+            if let Err(cache_err) = self.cache.add_post_pt(&post_id, pos[0], pos[1], &blurb, category).await {
+                self.posts_cache_write_failed(cache_err).await;
+            }
+        }
         
-        self.cache.add_post_pt(&post_id, pos[0], pos[1], &blurb).await.unwrap();
-            // .map_err(|e| eprintln!("when adding post pt: {e}"))?;
+        metrics::POSTS_CREATED.inc();
         
         Ok((post_id, blurb))
     }
     
 
-    pub async fn get_vote(&self, uid: &str, post_id: &str) -> Result<VoteKind, ()> {
-        match 
+    /// replaces the body, keeping the old one in `edits`; only the author can edit. returns (edited post, new blurb)
+    pub async fn edit_post(&mut self, post_id: &str, author_id: &str, body: &str) -> Result<(Post, String), NearsayError> {
+        let post = 
+            self.mongo_db.collection::<Post>("posts")
+            .find_one_and_update(
+                doc! { "_id": post_id, "authorId": author_id },
+                vec![doc! {
+                    "$set": {
+                        "edits": { "$concatArrays": [
+                            { "$ifNull": ["$edits", []] },
+                            [{ "body": "$body", "editedAt": current_time_ms() as i64 }]
+                        ]},
+                        "body": { "$literal": body },
+                        "tags": { "$literal": parse_hashtags(body) },
+                    }
+                }]
+            )
+            .return_document(ReturnDocument::After)
+            .await?;
+        
+        let Some(post) = post else {
+            // only looked into when nothing was edited
+            let exists = self.mongo_db.collection::<Document>("posts").count_documents(doc! { "_id": post_id }).limit(1).await? > 0;
+            return Err(if exists { NearsayError::Forbidden } else { NearsayError::NotFound("post") })
+        };
+        
+        let blurb = get_blurb_from_body(body, self.config.blurb_length);
+        
+        if let Err(cache_err) = self.cache.update_blurb(post_id, &blurb, post.category).await {
+            self.posts_cache_write_failed(cache_err).await;
+        }
+        
+        Ok((post, blurb))
+    }
+
+    pub async fn get_vote(&self, uid: &str, post_id: &str) -> Result<VoteKind, NearsayError> {
+        let vote = 
             self.mongo_db.collection::<Document>("votes")
             .find_one( doc!{ "postId": post_id, "uid": uid } )
             .hint(Hint::Name("postId-and-uid".to_string()))
-            .await
-        {
-            Err(mongo_err) => {
-                eprintln!("error getting vote {}", mongo_err);
-                Err(())
-            },
-            Ok(None) => Ok(VoteKind::None),
-            Ok(Some(document)) => Ok(VoteKind::from_str(document.get_str("kind").unwrap()))
+            .await?;
+        
+        match vote {
+            None => Ok(VoteKind::None),
+            Some(document) => Ok(Vote::try_from(document)?.kind)
         }
     }
 
-    pub async fn insert_vote(&self, uid: &str, post_id: &str, vote: VoteKind) -> Result<(), ()> {
+    pub async fn insert_vote(&self, uid: &str, post_id: &str, vote: VoteKind) -> Result<(), NearsayError> {
         let prev_vote = self.get_vote(uid, post_id).await?;
 
         if vote == prev_vote { return Ok(()) }
@@ -352,131 +1025,795 @@ impl NearsayDB {
         };
 
         // update counters in posts
-        if let Err(mongo_err) =  
+        let updated = 
             self.mongo_db.collection::<Post>("posts")
             .update_one(
                 doc! {"_id": post_id},
-                doc! {
-                    "$inc": {
-                        "likes": delta_likes,
-                        "dislikes": delta_dislikes,
-                        "expiry": vote.get_lifetime_weight() - prev_vote.get_lifetime_weight()
+                vec![doc! {
+                    "$set": {
+                        "likes": { "$add": ["$likes", delta_likes] },
+                        "dislikes": { "$add": ["$dislikes", delta_dislikes] },
+                        "expiry": self.extended_expiry_expr(vote.get_lifetime_weight() - prev_vote.get_lifetime_weight()),
                     }
-                }
-            ).await
-        {
-            eprintln!("error updating like/dislike/expiry for post on vote: {}", mongo_err);
-            return Err(());
-        }
+                }]
+            ).await?;
+        if updated.matched_count == 0 { return Err(NearsayError::NotFound("post")) }
 
         // update votes collection
-        match vote {
-            VoteKind::None => match 
+        match &vote {
+            VoteKind::None => {
                 self.mongo_db.collection::<Document>("votes")
                     .delete_one(doc! { "postId": post_id, "uid": uid } )
-                    .await
-            {
-                Err(mongo_err) => {
-                    eprintln!("error deleting vote {}", mongo_err);
-                    Err(())
-                },
-                Ok(_) => Ok(()),
+                    .await?;
             }
-
-            other => match 
+            other => {
                 self.mongo_db.collection::<Document>("votes")
                     .update_one(
                         doc! { "postId": post_id, "uid": uid },
                         doc! { "$set": { "kind": other.as_str() } }
                     )
                     .upsert(true)
-                    .await
-            {
-                Err(mongo_err) => {
-                    eprintln!("error updating vote {}", mongo_err);
-                    Err(())
-                },
-                Ok(_) => Ok(()),
+                    .await?;
             }
+        }
+        
+        metrics::VOTES.with_label_values(&[&vote.as_str()]).inc();
+        
+        Ok(())
+    }
+
+    /// returns the comment and the position of its post. 
+    /// the first comment of each member on a post keeps the post alive, like voting does
+    pub async fn insert_comment(&self, author_id: Option<&str>, author_is_member: bool, post_id: &str, parent_id: Option<&str>, body: &str) -> Result<(Comment, [f64; 2]), NearsayError> {
+        
+        // replies have to be to a comment on the same post
+        if let Some(parent_id) = parent_id {
+            let parent = self.get::<Comment>("comments", parent_id).await?;
+            if !matches!(parent, Some(parent) if parent.postId == post_id && !parent.deleted) {
+                return Err(NearsayError::NotFound("comment"));
+            }
+        }
+        
+        let comment = Comment {
+            _id: gen_id(),
+            postId: post_id.to_string(),
+            parentId: parent_id.map(str::to_string),
+            authorId: author_id.map(str::to_string),
+            body: body.to_string(),
+            createdAt: current_time_ms() as i64,
+            replies: 0,
+            deleted: false,
+        };
+        
+        self.mongo_db.collection("comments").insert_one(doc! {
+            "_id": &comment._id,
+            "postId": &comment.postId,
+            "parentId": &comment.parentId,
+            
+            "authorId": &comment.authorId,
+            "body": &comment.body,
+            "createdAt": comment.createdAt,
+            "replies": 0,
+        }).await?;
+        
+        // the marker outlives the member's comments, so deleting and reposting doesn't count as first again
+        let first_by_member = match author_id {
+            Some(author_id) if author_is_member => {
+                let marked = self.mongo_db.collection::<Document>("commenters")
+                    .update_one(
+                        doc! { "postId": post_id, "uid": author_id },
+                        doc! { "$setOnInsert": { "postId": post_id, "uid": author_id } }
+                    )
+                    .upsert(true)
+                    .await;
+                match marked {
+                    // a concurrent upsert won the race
+                    Err(e) if matches!(*e.kind, ErrorKind::Write(WriteFailure::WriteError(WriteError { code: 11000, .. }))) => false,
+                    res => res?.upserted_id.is_some(),
+                }
+            },
+            _ => false,
+        };
+        
+        let post = 
+            self.mongo_db.collection::<Post>("posts")
+            .find_one_and_update(
+                doc! { "_id": post_id },
+                vec![doc! { "$set": {
+                    "comments": { "$add": [{ "$ifNull": ["$comments", 0] }, 1] },
+                    "expiry": self.extended_expiry_expr(if first_by_member { COMMENT_LIFETIME_WEIGHT } else { 0 }),
+                } }]
+            )
+            .await?;
+        let Some(post) = post else {
+            self.mongo_db.collection::<Comment>("comments").delete_one(doc! { "_id": &comment._id }).await?;
+            if first_by_member {
+                self.mongo_db.collection::<Document>("commenters").delete_one(doc! { "postId": post_id, "uid": author_id }).await?;
+            }
+            return Err(NearsayError::NotFound("post"));
+        };
+        
+        if let Some(parent_id) = parent_id {
+            self.mongo_db.collection::<Comment>("comments")
+                .update_one(doc! { "_id": parent_id }, doc! { "$inc": { "replies": 1 } })
+                .await?;
+        }
+        
+        Ok((comment, post.pos))
+    }
+    
+    /// returns the position of the comment's post, if it still exists
+    pub async fn delete_comment(&self, comment: &Comment) -> Result<Option<[f64; 2]>, NearsayError> {
+        let comments = self.mongo_db.collection::<Comment>("comments");
+        
+        if comment.replies > 0 {
+            comments.update_one(
+                doc! { "_id": &comment._id },
+                doc! { "$set": { "deleted": true, "body": "", "authorId": null } }
+            ).await?;
+        }
+        else {
+            comments.delete_one(doc! { "_id": &comment._id }).await?;
             
+            if let Some(parent_id) = &comment.parentId {
+                comments.update_one(doc! { "_id": parent_id }, doc! { "$inc": { "replies": -1 } }).await?;
+            }
         }
+        
+        let post = 
+            self.mongo_db.collection::<Post>("posts")
+            .find_one_and_update(doc! { "_id": &comment.postId }, doc! { "$inc": { "comments": -1 } })
+            .await?;
+        
+        Ok(post.map(|post| post.pos))
+    }
+    
+    /// up to `limit` replies to `parent_id` (or top-level comments if `None`) on `post_id`, oldest first, starting after `after`
+    pub async fn get_comments(&self, post_id: &str, parent_id: Option<&str>, after: Option<&CommentCursor>, limit: usize) -> Result<Vec<Comment>, NearsayError> {
+        let mut filter = doc! { "postId": post_id, "parentId": parent_id };
+        
+        if let Some(after) = after {
+            filter.insert("$or", vec![
+                doc! { "createdAt": { "$gt": after.created_at } },
+                doc! { "createdAt": after.created_at, "_id": { "$gt": &after.id } },
+            ]);
+        }
+        
+        Ok(
+            self.mongo_db.collection::<Comment>("comments")
+            .find(filter)
+            .sort(doc! { "createdAt": 1, "_id": 1 })
+            .limit(limit as i64)
+            .await?
+            .try_collect()
+            .await?
+        )
+    }
+    
+    /// the accounts of `uids` that exist, by id
+    pub async fn get_users(&self, uids: &[&str]) -> Result<HashMap<String, User>, NearsayError> {
+        Ok(
+            self.mongo_db.collection::<User>("users")
+            .find(doc! { "_id": { "$in": uids } })
+            .await?
+            .map_ok(|user| (user._id.clone(), user))
+            .try_collect()
+            .await?
+        )
+    }
 
+    /// saves the post for `uid`, extending its lifetime. 
+    /// returns the post's (author id, number of saves), or None if `uid` already saved it
+    pub async fn save_post(&self, uid: &str, post_id: &str) -> Result<Option<(Option<String>, usize)>, NearsayError> {
+        let inserted = self.mongo_db.collection::<Document>("saves")
+            .insert_one(doc! { "uid": uid, "postId": post_id, "savedAt": current_time_ms() as i64 })
+            .await;
+        match inserted {
+            Err(e) if matches!(*e.kind, ErrorKind::Write(WriteFailure::WriteError(WriteError { code: 11000, .. }))) => return Ok(None),
+            res => { res?; },
+        }
+        
+        let post = 
+            self.mongo_db.collection::<Post>("posts")
+            .find_one_and_update(
+                doc! { "_id": post_id },
+                vec![doc! { "$set": {
+                    "saves": { "$add": [{ "$ifNull": ["$saves", 0] }, 1] },
+                    "expiry": self.extended_expiry_expr(SAVE_LIFETIME_WEIGHT),
+                } }]
+            )
+            .return_document(ReturnDocument::After)
+            .await?;
+        
+        match post {
+            Some(post) => Ok(Some((post.authorId, post.saves))),
+            None => {
+                self.mongo_db.collection::<Document>("saves").delete_one(doc! { "uid": uid, "postId": post_id }).await?;
+                Err(NearsayError::NotFound("post"))
+            }
+        }
+    }
+    
+    /// undoes `save_post`. unsaving a post that isn't saved does nothing
+    pub async fn unsave_post(&self, uid: &str, post_id: &str) -> Result<(), NearsayError> {
+        let deleted = self.mongo_db.collection::<Document>("saves")
+            .delete_one(doc! { "uid": uid, "postId": post_id })
+            .await?;
+        
+        if deleted.deleted_count == 1 {
+            self.mongo_db.collection::<Post>("posts")
+                .update_one(
+                    doc! { "_id": post_id },
+                    vec![doc! { "$set": {
+                        "saves": { "$add": ["$saves", -1] },
+                        "expiry": self.extended_expiry_expr(-SAVE_LIFETIME_WEIGHT),
+                    } }]
+                )
+                .await?;
+        }
+        
+        Ok(())
+    }
+    
+    /// posts `uid` saved before `before` (ms since epoch), most recently saved first
+    pub async fn get_saved_posts(&self, uid: &str, before: Option<&SavedCursor>, limit: usize) -> Result<Vec<SavedPost>, NearsayError> {
+        let mut filter = doc! { "uid": uid };
+        if let Some(before) = before {
+            filter.insert("$or", vec![
+                doc! { "savedAt": { "$lt": before.saved_at } },
+                doc! { "savedAt": before.saved_at, "postId": { "$lt": &before.post_id } },
+            ]);
+        }
+        
+        Ok(
+            self.mongo_db.collection::<Document>("saves")
+            .aggregate(vec![
+                doc! { "$match": filter },
+                doc! { "$sort": { "savedAt": -1, "postId": -1 } },
+                doc! { "$limit": limit as i64 },
+                doc! { "$lookup": { "from": "posts", "localField": "postId", "foreignField": "_id", "as": "post" } },
+                // saves of posts that were deleted since
+                doc! { "$unwind": "$post" },
+                doc! { "$project": {
+                    "_id": "$post._id", "pos": "$post.pos", "category": "$post.category", 
+                    "likes": "$post.likes", "dislikes": "$post.dislikes", "views": "$post.views", 
+                    "comments": "$post.comments", "saves": "$post.saves", "createdAt": "$post.createdAt", 
+                    "blurb": { "$substrCP": [ "$post.body", 0, self.config.blurb_length as i32 ]},
+                    "savedAt": 1,
+                }},
+            ])
+            .with_type::<SavedPost>()
+            .await?
+            .try_collect()
+            .await?
+        )
+    }
+    
+    /// `user`'s profile. their hidden info is only included if `viewer` is them
+    pub async fn get_profile(&self, user: User, viewer: Option<&str>) -> Result<Profile, NearsayError> {
+        let is_self = viewer == Some(user._id.as_str());
+        let active = doc! { "authorId": &user._id, "expiry": { "$gt": current_time_ms() as i64 } };
+        
+        #[derive(Deserialize)]
+        struct Likes { likes: usize }
+        
+        let likes_received = 
+            self.mongo_db.collection::<Post>("posts")
+            .aggregate(vec![
+                doc! { "$match": active.clone() },
+                doc! { "$group": { "_id": null, "likes": { "$sum": "$likes" } } },
+            ])
+            .hint(Hint::Name("authorId-createdAt".to_string()))
+            .with_type::<Likes>()
+            .await?
+            .try_next()
+            .await?
+            .map_or(0, |sum| sum.likes);
+        
+        let posts = match !user.privacy.hidePosts || is_self {
+            false => None,
+            true => Some(
+                self.mongo_db.collection::<Post>("posts")
+                .aggregate(vec![
+                    doc! { "$match": active },
+                    doc! { "$sort": { "createdAt": -1 } },
+                    doc! { "$limit": MAX_PROFILE_POSTS as i64 },
+                    doc! { "$project": {
+                        "pos": 1, "category": 1, "likes": 1, "dislikes": 1, "views": 1, "comments": 1, "saves": 1, "createdAt": 1,
+                        "blurb": { "$substrCP": [ "$body", 0, self.config.blurb_length as i32 ]},
+                    }},
+                ])
+                .hint(Hint::Name("authorId-createdAt".to_string()))
+                .with_type::<PostSummary>()
+                .await?
+                .try_collect()
+                .await?
+            ),
+        };
+        
+        Ok(Profile {
+            lastSeen: (!user.privacy.hideLastSeen || is_self).then_some(user.lastSeen),
+            privacy: is_self.then_some(user.privacy),
+            id: user._id,
+            username: user.username,
+            avatar: user.avatar,
+            joinedAt: user.joinedAt,
+            likesReceived: likes_received,
+            posts,
+        })
     }
 
-    pub async fn increment_view(&self, post_id: &str) -> Result<UpdateResult, MongoError> {
+    /// aggregation expression of a post's `expiry` moved by `weight` ms, but never further than `max_post_lifetime_secs` after it was posted.
+    /// posts that already expire later than that (like events) aren't extended, and posts from before `createdAt` was recorded aren't capped
+    fn extended_expiry_expr(&self, weight: i64) -> Document {
+        extended_expiry_expr(weight, self.config.max_post_lifetime_secs as i64 * 1000)
+    }
 
-        let res = self.mongo_db.collection::<Post>("posts")
+    pub async fn increment_view(&self, post_id: &str) -> Result<UpdateResult, NearsayError> {
+        Ok(
+            self.mongo_db.collection::<Post>("posts")
             .update_one(
                 doc! { "_id": post_id }, 
-                doc! { "$inc": { 
-                    "views": 1,
-                    "expiry": 1,
-                } }
-            ).await;
-
-        match res {
-            Err(mongo_err) => {
-                eprintln!("error incrementing view: {}", mongo_err);
-                return Err(mongo_err);
-            },
-            other => other,
-        }   
+                vec![doc! { "$set": { 
+                    "views": { "$add": ["$views", 1] },
+                    "expiry": self.extended_expiry_expr(VIEW_LIFETIME_WEIGHT),
+                } }]
+            ).await?
+        )
     }
 
-    pub async fn geoquery_post_pts(&mut self, zoom: usize, within: &Rect) -> Result<Vec<Cluster>, ()> {
+    pub async fn geoquery_post_pts(&mut self, zoom: usize, within: &Rect, filter: &PostFilter) -> Result<Vec<Cluster>, NearsayError> {
         
-        if let Ok(posts) = self.cache.geoquery_post_pts(zoom, within).await {
-            return Ok(posts);
-        }
+        // zooms that aren't cached (or a failing or unready cache) fall back to clustering from mongodb.
+        // the cache has a layer per category, but not per tag
+        let source = match self.is_cache_ready() {
+            false => "cache_not_ready",
+            true if !filter.tags.is_empty() => "tag_filter",
+            true => match self.cache.geoquery_post_pts(zoom, within, filter.category).await {
+                Ok(Some(posts)) => {
+                    metrics::POST_PTS_QUERIES.with_label_values(&["cache"]).inc();
+                    return Ok(posts)
+                },
+                Ok(None) => "uncached_zoom",
+                Err(e) => {
+                    warn!(error = %e, "falling back to mongodb for post pts");
+                    "cache_error"
+                },
+            }
+        };
+        metrics::POST_PTS_QUERIES.with_label_values(&[source]).inc();
 
-        let mut post_docs = self.geoquery::<Post>("posts", within).await
-        .map_err(|e| eprintln!("when geoquery post pts{e}"))?;
+        let mut mongo_filter = filter.as_mongo_filter();
+        mongo_filter.extend(on_map_filter());
+        let mut post_docs = self.geoquery::<Post>("posts", within, mongo_filter).await?;
     
         let mut res: Vec<Cluster> = vec![];
         
-        while let Some(doc) = post_docs.try_next().await.map_err(|_| ())? {            
-            res.push(doc.into());
+        while let Some(doc) = post_docs.try_next().await? {
+            // skip malformed posts instead of failing the whole view
+            match Cluster::try_from(doc) {
+                Ok(post_pt) => res.push(post_pt),
+                Err(e) => warn!(error = %e, "skipping malformed post"),
+            }
         }
 
-        // don't cluster if zoomed all the way in
-        if zoom >= MAX_ZOOM_LEVEL { Ok(res) }
-        else { Ok(cluster(&res[..], get_cluster_radius_degrees(zoom)))  }
+        // not clustered when zoomed all the way in, the radius is 0
+        Ok(cluster(&res[..], get_cluster_radius_degrees(zoom)))
+    }
+
+    pub fn is_cache_ready(&self) -> bool {
+        self.cache_ready.load(Ordering::Acquire)
+    }
+    
+    pub async fn ping_mongo(&self) -> Result<(), NearsayError> {
+        self.mongo_db.run_command(doc! { "ping": 1 }).await?;
+        Ok(())
+    }
+    
+    pub async fn ping_posts_cache(&mut self) -> Result<(), NearsayError> {
+        Ok(self.cache.ping_posts().await?)
+    }
+    
+    pub async fn ping_users_cache(&mut self) -> Result<(), NearsayError> {
+        Ok(self.cache.ping_users().await?)
+    }
+    
+    pub async fn count_online_users(&mut self) -> Result<i64, NearsayError> {
+        Ok(self.cache.count_users().await?)
+    }
+
+    pub async fn geoquery_users(&mut self, within: &Rect) -> Result<Vec<UserPOI>, NearsayError> {
+        Ok(self.cache.geoquery_users(within).await?)
     }
 
-    pub async fn geoquery_users(&mut self, within: &Rect) -> Result<Vec<UserPOI>, ()> {
-        self.cache.geoquery_users(within).await.map_err(|e| eprintln!("when geoquery users: {e}"))
+    /// posts within `query.radius` of the query point matching `query.q`, most relevant first.
+    /// returns the page and the cursor of the next one, if there may be one
+    pub async fn search_posts(&self, query: &SearchQuery) -> Result<(Vec<SearchResult>, Option<SearchCursor>), NearsayError> {
+        let (radius, limit, after) = query.validate()?;
+        
+        #[derive(Deserialize)]
+        struct Hit {
+            _id: String,
+            pos: [f64; 2],
+            body: String,
+            #[serde(default)]
+            category: Category,
+            score: f64,
+        }
+        
+        let mut matching = doc! {
+            "$text": { "$search": &query.q },
+            "pos": { "$geoWithin": { "$centerSphere": [[query.lng, query.lat], radius / EARTH_RADIUS_METERS] } },
+        };
+        matching.extend(on_map_filter());
+        
+        // $text has to be in the first stage, and can't be used with $near, but can be with $geoWithin
+        let mut pipeline = vec![
+            doc! { "$match": matching },
+            doc! { "$addFields": { "score": { "$meta": "textScore" } } },
+        ];
+        if let Some(after) = &after {
+            pipeline.push(doc! { "$match": { "$or": [
+                { "score": { "$lt": after.score } },
+                { "score": after.score, "_id": { "$gt": &after.id } },
+            ]}});
+        }
+        pipeline.extend([
+            doc! { "$sort": { "score": -1, "_id": 1 } },
+            doc! { "$limit": limit as i64 },
+            doc! { "$project": { "pos": 1, "body": 1, "category": 1, "score": 1 } },
+        ]);
+        
+        let hits: Vec<Hit> = 
+            self.mongo_db.collection::<Post>("posts")
+            .aggregate(pipeline)
+            .with_type::<Hit>()
+            .await?
+            .try_collect()
+            .await?;
+        
+        // a full page means there may be more
+        let next = match hits.len() == limit {
+            true => hits.last().map(|last| SearchCursor { score: last.score, id: last._id.clone() }),
+            false => None,
+        };
+        
+        let results = hits.into_iter().map(|hit| SearchResult {
+            distance: meters_between(query.lng, query.lat, hit.pos[0], hit.pos[1]),
+            blurb: get_blurb_from_body(&hit.body, self.config.blurb_length),
+            id: hit._id,
+            pos: hit.pos,
+            category: hit.category,
+            score: hit.score,
+        }).collect();
+        
+        Ok((results, next))
     }
 
-    async fn geoquery<T>(&self, collection: &str, within: &Rect) -> Result<Cursor<Document>, MongoError>
+    /// posts within `query.radius` of the query point, highest score first (see `Config::feed_vote_weight`).
+    /// returns the page and the cursor of the next one, if there may be one
+    pub async fn feed_posts(&self, query: &FeedQuery) -> Result<(Vec<FeedItem>, Option<FeedCursor>), NearsayError> {
+        let (radius, limit, after) = query.validate()?;
+        let at = after.as_ref().map_or(current_time_ms() as i64, |after| after.at);
+        let config = &self.config;
+        
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        struct Hit {
+            _id: String,
+            pos: [f64; 2],
+            body: String,
+            #[serde(default)]
+            category: Category,
+            likes: usize,
+            dislikes: usize,
+            views: usize,
+            #[serde(default)]
+            comments: usize,
+            #[serde(default)]
+            createdAt: i64,
+            score: f64,
+        }
+        
+        let mut matching = doc! { "pos": { "$geoWithin": { "$centerSphere": [[query.lng, query.lat], radius / EARTH_RADIUS_METERS] } } };
+        matching.extend(on_map_filter());
+        
+        let mut pipeline = vec![
+            doc! { "$match": matching },
+            doc! { "$addFields": { "distance": distance_meters_expr(query.lng, query.lat) } },
+            doc! { "$addFields": { "score": { "$subtract": [
+                { "$add": [
+                    { "$multiply": [config.feed_vote_weight, { "$subtract": ["$likes", "$dislikes"] }] },
+                    { "$multiply": [config.feed_view_weight, { "$ln": { "$add": [1, "$views"] } }] },
+                ]},
+                { "$add": [
+                    { "$multiply": [config.feed_distance_weight, { "$divide": ["$distance", 1000] }] },
+                    // posts from before `createdAt` was recorded count as ancient
+                    { "$multiply": [config.feed_age_weight, { "$divide": [
+                        { "$max": [0, { "$subtract": [at, { "$ifNull": ["$createdAt", 0] }] }] },
+                        60 * 60 * 1000
+                    ]}]},
+                ]},
+            ]}}},
+        ];
+        if let Some(after) = &after {
+            pipeline.push(doc! { "$match": { "$or": [
+                { "score": { "$lt": after.score } },
+                { "score": after.score, "_id": { "$gt": &after.id } },
+            ]}});
+        }
+        pipeline.extend([
+            doc! { "$sort": { "score": -1, "_id": 1 } },
+            doc! { "$limit": limit as i64 },
+            doc! { "$project": { "pos": 1, "body": 1, "category": 1, "likes": 1, "dislikes": 1, "views": 1, "comments": 1, "createdAt": 1, "score": 1 } },
+        ]);
+        
+        let hits: Vec<Hit> = 
+            self.mongo_db.collection::<Post>("posts")
+            .aggregate(pipeline)
+            .with_type::<Hit>()
+            .await?
+            .try_collect()
+            .await?;
+        
+        // a full page means there may be more
+        let next = match hits.len() == limit {
+            true => hits.last().map(|last| FeedCursor { at, score: last.score, id: last._id.clone() }),
+            false => None,
+        };
+        
+        let items = hits.into_iter().map(|hit| FeedItem {
+            distance: meters_between(query.lng, query.lat, hit.pos[0], hit.pos[1]),
+            blurb: get_blurb_from_body(&hit.body, self.config.blurb_length),
+            id: hit._id,
+            pos: hit.pos,
+            category: hit.category,
+            likes: hit.likes,
+            dislikes: hit.dislikes,
+            views: hit.views,
+            comments: hit.comments,
+            createdAt: hit.createdAt,
+            score: hit.score,
+        }).collect();
+        
+        Ok((items, next))
+    }
+
+    /// `filter` is added to the `$match` stage. filtering by category or tags uses the "pos-category-tags" index
+    async fn geoquery<T>(&self, collection: &str, within: &Rect, mut filter: Document) -> Result<Cursor<Document>, MongoError>
     where T: Send + Sync + POI
     {
+        let hint = match filter.contains_key("category") || filter.contains_key("tags") {
+            false => "pos_2dsphere",
+            true => "pos-category-tags",
+        };
+        filter.insert("pos", doc! { "$geoWithin": within.as_geo_json() });
+        
         self.mongo_db.collection::<T>(collection)
             .aggregate(vec! [
-                doc! {
-                    "$match": { "pos": { "$geoWithin": within.as_geo_json() } }
-                },
-                T::get_poi_projection()
+                doc! { "$match": filter },
+                T::get_poi_projection(&self.config)
             ])
-            .hint( Hint::Name("pos_2dsphere".to_string()) )
+            .hint( Hint::Name(hint.to_string()) )
             .await
     }
 }
 
+fn extended_expiry_expr(weight: i64, max_lifetime_ms: i64) -> Document {
+    let extended = doc! { "$add": ["$expiry", weight] };
+    let cap = doc! { "$ifNull": [{ "$add": ["$createdAt", max_lifetime_ms] }, extended.clone()] };
+    doc! { "$min": [extended, { "$max": [cap, "$expiry"] }] }
+}
+
+/// aggregation expression of the haversine distance in meters between a document's `pos` and (`lng`, `lat`)
+fn distance_meters_expr(lng: f64, lat: f64) -> Document {
+    let (lng, lat) = (lng.to_radians(), lat.to_radians());
+    doc! { "$let": {
+        "vars": {
+            "lng": { "$degreesToRadians": { "$arrayElemAt": ["$pos", 0] } },
+            "lat": { "$degreesToRadians": { "$arrayElemAt": ["$pos", 1] } },
+        },
+        "in": { "$multiply": [2.0 * EARTH_RADIUS_METERS, { "$asin": { "$min": [1, { "$sqrt": { "$add": [
+            { "$pow": [{ "$sin": { "$divide": [{ "$subtract": ["$$lat", lat] }, 2] } }, 2] },
+            { "$multiply": [
+                lat.cos(), 
+                { "$cos": "$$lat" }, 
+                { "$pow": [{ "$sin": { "$divide": [{ "$subtract": ["$$lng", lng] }, 2] } }, 2] },
+            ]},
+        ]}}]}}]},
+    }}
+}
+
 pub fn gen_id() -> String {
     let mut res = [' '; 10];
     
-    for i in 0..res.len() {
-        res[i] = to_base64_symbol(rand::random::<u8>() & 63)
+    for c in &mut res {
+        *c = to_base64_symbol(rand::random::<u8>() & 63)
     }
 
     res.iter().collect()
 }
 
 fn to_base64_symbol(num: u8) -> char {
-    if      num < 10    { ('0' as u8 + num) as char }
-    else if num < 36    { (num - 10 + 'a' as u8) as char }
-    else if num < 62    { (num - 36 + 'A' as u8) as char }
+    if      num < 10    { (b'0' + num) as char }
+    else if num < 36    { (num - 10 + b'a') as char }
+    else if num < 62    { (num - 36 + b'A') as char }
     else if num == 62   { '-' }
     else if num == 63   { '_' }
     else                { panic!("cant convert num > 63 to a base64 symbol") }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use nearsay_server::NearsayError;
+
+    use std::time::Duration;
+
+    use rslock::LockManager;
+
+    use crate::{area::Rect, cache::POSTS_LOCK_RESOURCE, stand_ins::{failing_config, mongo_config, redis_config}, types::{Attachment, Category, Post, PostFilter, PostLifetime}};
+
+    use mongodb::bson::{doc, Bson, Document};
+
+    use super::{extended_expiry_expr, NearsayDB, Ordering};
+
+    /// evaluates the few aggregation operators `extended_expiry_expr` uses against `post`
+    fn eval(expr: &Bson, post: &Document) -> Option<i64> {
+        match expr {
+            Bson::String(field) => post.get_i64(field.strip_prefix('$').unwrap()).ok(),
+            Bson::Int64(n) => Some(*n),
+            Bson::Document(op) => {
+                let (name, args) = op.iter().next().unwrap();
+                let args: Vec<_> = args.as_array().unwrap().iter().map(|arg| eval(arg, post)).collect();
+                match name.as_str() {
+                    "$add" => args.into_iter().sum(),
+                    "$min" => args.into_iter().collect::<Option<Vec<_>>>()?.into_iter().min(),
+                    "$max" => args.into_iter().collect::<Option<Vec<_>>>()?.into_iter().max(),
+                    "$ifNull" => args[0].or(args[1]),
+                    other => panic!("unexpected operator {other}"),
+                }
+            },
+            other => panic!("unexpected expression {other}"),
+        }
+    }
+
+    #[test]
+    fn interactions_cant_extend_expiry_past_max_lifetime() {
+        let expr = Bson::Document(extended_expiry_expr(10, 100));
+        let shortened = Bson::Document(extended_expiry_expr(-10, 100));
+        
+        let post = doc! { "createdAt": 1000_i64, "expiry": 1050_i64 };
+        assert_eq!(eval(&expr, &post), Some(1060));
+        assert_eq!(eval(&shortened, &post), Some(1040));
+        
+        // capped at createdAt + max lifetime
+        let post = doc! { "createdAt": 1000_i64, "expiry": 1095_i64 };
+        assert_eq!(eval(&expr, &post), Some(1100));
+        let post = doc! { "createdAt": 1000_i64, "expiry": 1100_i64 };
+        assert_eq!(eval(&expr, &post), Some(1100));
+        
+        // events can end later than that, but aren't extended
+        let post = doc! { "createdAt": 1000_i64, "expiry": 5000_i64 };
+        assert_eq!(eval(&expr, &post), Some(5000));
+        assert_eq!(eval(&shortened, &post), Some(4990));
+        
+        // posts from before createdAt was recorded
+        let post = doc! { "expiry": 5000_i64 };
+        assert_eq!(eval(&expr, &post), Some(5010));
+    }
+
+    #[tokio::test]
+    async fn startup_fails_without_panicking() {
+        assert!(NearsayDB::new(&failing_config().await).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failing_cache_falls_back_to_mongo() {
+        let mut db = NearsayDB::connect(&failing_config().await).await.unwrap();
+        db.cache_ready.store(true, Ordering::Release);
+        let view = Rect { top: 10.0, bottom: -10.0, left: -10.0, right: 10.0 };
+        
+        // the cache error is logged, then mongodb is tried and fails too
+        assert!(matches!(db.geoquery_post_pts(4, &view, &PostFilter::default()).await, Err(NearsayError::UpstreamDb)));
+        assert!(matches!(db.geoquery_users(&view).await, Err(NearsayError::UpstreamCache)));
+        assert!(matches!(db.get_token_gen("uid").await, Err(NearsayError::UpstreamCache)));
+    }
+
+    /// holds the posts cache lock for longer than writes wait for it, so they fail
+    async fn hold_posts_lock(db: &NearsayDB) -> (LockManager, rslock::Lock) {
+        let lock_manager = LockManager::new(vec![db.config.posts_redis_url.as_str()]);
+        let lock = lock_manager.lock(POSTS_LOCK_RESOURCE, Duration::from_secs(10)).await.unwrap();
+        (lock_manager, lock)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis, see `stand_ins::redis_config`"]
+    async fn failed_cache_write_stops_it_being_reused() {
+        let mut db = NearsayDB::connect(&redis_config(5).await).await.unwrap();
+        let version = db.cache.start_posts_rebuild().await.unwrap();
+        db.cache.finish_posts_rebuild(version).await.unwrap();
+        db.cache_ready.store(true, Ordering::Release);
+        
+        let (lock_manager, lock) = hold_posts_lock(&db).await;
+        let cache_err = db.cache.add_post_pt("post", 0.0, 0.0, "blurb", Category::General).await.unwrap_err();
+        lock_manager.unlock(&lock).await;
+        db.posts_cache_write_failed(cache_err).await;
+        
+        assert!(!db.is_cache_ready());
+        assert!(!db.cache.is_posts_cache_current().await.unwrap());
+        
+        // it's rebuilt from mongodb instead of reused, which fails here, so it's still stale for the next sweep
+        assert!(db.ready_posts_cache().await.is_err());
+        assert!(db.posts_cache_stale.load(Ordering::Acquire));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis and mongodb, see `stand_ins::mongo_config`"]
+    async fn posts_the_cache_missed_are_put_back_by_the_next_sweep() {
+        let config = mongo_config(6).await;
+        let mut db = NearsayDB::connect(&config).await.unwrap();
+        db.create_indexes().await.unwrap();
+        db.run_sweep().await.unwrap();
+        assert!(db.is_cache_ready());
+        
+        // the post is saved, even though the cache can't be written to
+        let (lock_manager, lock) = hold_posts_lock(&db).await;
+        let (post_id, _) = db.insert_post(None, &[0.0, 0.0], "body", Category::General, PostLifetime::Default, &[]).await.unwrap();
+        lock_manager.unlock(&lock).await;
+        assert!(!db.is_cache_ready());
+        
+        db.run_sweep().await.unwrap();
+        assert!(db.is_cache_ready());
+        
+        let view = Rect { top: 10.0, bottom: -10.0, left: -10.0, right: 10.0 };
+        let clusters = db.cache.geoquery_post_pts(config.max_cached_zoom, &view, None).await.unwrap().unwrap();
+        assert!(clusters.iter().any(|cluster| cluster.id == post_id));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis and mongodb, see `stand_ins::mongo_config`"]
+    async fn uploads_are_only_posted_once() {
+        let mut db = NearsayDB::connect(&mongo_config(9).await).await.unwrap();
+        let upload = Attachment {
+            _id: "a".to_string(),
+            uid: "author".to_string(),
+            postId: None,
+            contentType: "image/png".to_string(),
+            width: 640,
+            height: 480,
+            key: "a.png".to_string(),
+            thumbnailKey: "a-thumb.png".to_string(),
+            url: "/files/a.png".to_string(),
+            thumbnailUrl: "/files/a-thumb.png".to_string(),
+            uploadedAt: 0,
+        };
+        db.mongo_db.collection::<Attachment>("attachments").insert_one(&upload).await.unwrap();
+        
+        // given twice, it's still attached once
+        let ids = ["a".to_string(), "a".to_string()];
+        let (post_id, _) = db.insert_post(Some("author"), &[0.0, 0.0], "body", Category::General, PostLifetime::Default, &ids).await.unwrap();
+        let post = db.get::<Post>("posts", &post_id).await.unwrap().unwrap();
+        assert_eq!(1, post.attachments.len());
+        
+        let again = db.insert_post(Some("author"), &[0.0, 0.0], "again", Category::General, PostLifetime::Default, &ids[..1]).await;
+        assert!(matches!(again, Err(NearsayError::NotFound("attachment"))));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis and mongodb, see `stand_ins::mongo_config`"]
+    async fn only_a_members_first_comment_extends_the_post() {
+        let mut db = NearsayDB::connect(&mongo_config(12).await).await.unwrap();
+        let (post_id, _) = db.insert_post(Some("author"), &[0.0, 0.0], "body", Category::General, PostLifetime::Default, &[]).await.unwrap();
+        let expiry = async |db: &NearsayDB| db.get::<Post>("posts", &post_id).await.unwrap().unwrap().expiry;
+        let posted = expiry(&db).await;
+        
+        let (comment, _) = db.insert_comment(Some("member"), true, &post_id, None, "first").await.unwrap();
+        let extended = expiry(&db).await;
+        assert!(extended > posted);
+        
+        // deleting and reposting isn't a first comment again
+        db.delete_comment(&comment).await.unwrap();
+        db.insert_comment(Some("member"), true, &post_id, None, "again").await.unwrap();
+        assert_eq!(extended, expiry(&db).await);
+    }
+}
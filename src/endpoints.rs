@@ -1,15 +1,34 @@
 
-use axum::{body::Body, extract::Path, http::{HeaderMap, StatusCode}, response::Response, routing::{get, post}};
-use hmac::Hmac;
-use serde::Serialize;
+use axum::{body::{to_bytes, Body}, extract::{Path, Query}, Extension, http::{HeaderMap, StatusCode}, response::Response, routing::{get, post}};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use sha2::Sha256;
-use nearsay_server::{clone_into_closure, clone_into_closure_mut};
+use nearsay_server::{clone_into_closure, clone_into_closure_mut, NearsayError};
+use tower_http::services::ServeDir;
+use tracing::{error, warn};
 
 
-use crate::{auth::{authenticate_with_header, JWTPayload}, db::NearsayDB, types::{Post, User, VoteKind}};
+use crate::{attachments::process_image, auth::{authenticate_with_header, refresh_tokens, Anyone, Auth, JWTKey, JWTPayload, Members}, config::StorageKind, db::NearsayDB, health, metrics, storage::LOCAL_URL_PREFIX, types::{CommentCursor, FeedQuery, Post, PublicAttachment, SavedCursor, SearchQuery, User, VoteKind, DEFAULT_SAVED_LIMIT, MAX_SAVED_LIMIT}};
 
 
+/// comments per page if `limit` isn't given
+const DEFAULT_COMMENTS_LIMIT: usize = 20;
+const MAX_COMMENTS_LIMIT: usize = 100;
+
+#[derive(Deserialize, Debug)]
+struct CommentsQuery {
+    /// get replies to this comment instead of top-level comments
+    parent_id: Option<String>,
+    /// `next` of the previous page
+    after: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct SavedQuery {
+    /// `next` of the previous page
+    before: Option<String>,
+    limit: Option<usize>,
+}
 
 fn json_response<T: Serialize>(status: u16, serializable: T) -> Response<Body> {
     let body = Into::<Body>::into(serde_json::to_vec(&serializable).unwrap());
@@ -21,78 +40,221 @@ fn json_response<T: Serialize>(status: u16, serializable: T) -> Response<Body> {
         .unwrap()
 }
 
-fn empty_response(status: u16) -> Response<Body> {
-    Response::builder()
-        .status(status)
-        .body(Body::empty())
-        .unwrap()
-}
+pub fn get_endpoints_router(db: &NearsayDB, key: &JWTKey) -> axum::Router {
+    let mut router = axum::Router::new();
+    
+    // s3 attachments are served by the bucket
+    if db.config().storage == StorageKind::Local {
+        router = router.nest_service(LOCAL_URL_PREFIX, ServeDir::new(&db.config().attachments_dir));
+    }
+    
+    router
 
+        // the process is up and serving requests
+        .route("/healthz", get(|| async { json_response(200, json!({ "status": "ok" })) }))
+        
+        .route("/readyz", get(
+            clone_into_closure! {
+                (db)
+                || async move {
+                    let readiness = health::readiness(&db).await;
+                    json_response(if readiness.ready { 200 } else { 503 }, readiness)
+                }
+            }
+        ))
 
-pub fn get_endpoints_router(db: &NearsayDB, key: &Hmac<Sha256>) -> axum::Router {
-    axum::Router::new()
+        .route("/metrics", get(
+            clone_into_closure_mut! {
+                (db)
+                || async move {
+                    // a stale count is better than failing the whole scrape
+                    match db.count_online_users().await {
+                        Ok(count) => metrics::USERS_ONLINE.set(count),
+                        Err(e) => warn!(error = %e, "couldn't count online users"),
+                    }
+                    
+                    Response::builder()
+                        .status(200)
+                        .header("Content-Type", "text/plain; version=0.0.4")
+                        .body(Body::from(metrics::render()))
+                        .unwrap()
+                }
+            }
+        ))
+
+        .route("/refresh-token", post(
+            clone_into_closure_mut! {
+                (db, key)
+                |refresh_token: String| async move {
+                    let tokens = refresh_tokens(&mut db, &key, &refresh_token).await?;
+                    Ok::<_, NearsayError>(json_response(200, tokens))
+                }
+            }
+        ))
 
         .route("/vote/{post_id}", post(
             clone_into_closure! {
-                (db, key)
-                |headers: HeaderMap, Path(post_id): Path<String>, vote_kind: String| async move {
-                    
-                    let Ok(Some(JWTPayload {uid, ..})) = authenticate_with_header(&key, &headers)
-                    else { return StatusCode::UNAUTHORIZED };
-                    
-                    match db.insert_vote(&uid, &post_id, VoteKind::from_str(&vote_kind)).await {
-                        Ok(_) => StatusCode::OK,
-                        Err(_) => StatusCode::INTERNAL_SERVER_ERROR
-                    }
+                (db)
+                |auth: Auth<Members>, Path(post_id): Path<String>, vote_kind: String| async move {
+                    db.insert_vote(auth.uid(), &post_id, VoteKind::from_str(&vote_kind)).await?;
+                    Ok::<_, NearsayError>(StatusCode::OK)
                 }
             }
         ))
-        .route("/posts/{post_id}", get(
+        .route("/feed", get(
             clone_into_closure! {
+                (db)
+                |Query(query): Query<FeedQuery>| async move {
+                    let (posts, next) = db.feed_posts(&query).await?;
+                    Ok::<_, NearsayError>(json_response(200, json!({ "posts": posts, "next": next.map(|next| next.to_string()) })))
+                }
+            }
+        ))
+        .route("/posts/search", get(
+            clone_into_closure! {
+                (db)
+                |Query(query): Query<SearchQuery>| async move {
+                    let (results, next) = db.search_posts(&query).await?;
+                    Ok::<_, NearsayError>(json_response(200, json!({ "results": results, "next": next.map(|next| next.to_string()) })))
+                }
+            }
+        ))
+        .route("/posts/{post_id}", get(
+            clone_into_closure_mut! {
                 (db, key)
                 |headers: HeaderMap, Path(post_id): Path<String>| async move { 
 
                     if headers.contains_key("Increment-View") {
                         if let Ok(res) = db.increment_view(&post_id).await {
-                            if res.modified_count == 0 { return empty_response(404) }
+                            if res.modified_count == 0 { return Err(NearsayError::NotFound("post")) }
                         }
                     }
-                    match db.get::<Post>("posts", &post_id).await {
-                        Err(_) => empty_response(500),
-                        Ok(None) => empty_response(404),
-                        Ok(Some(post)) => {
-                            
-                            let author_info = match &post.authorId {
-                                None => None,
-                                Some(author_id) => 
-                                    match db.get::<User>("users", &author_id).await {
-                                        Ok(Some(user)) => Some((user.avatar, user.username)),
-                                        _ => None
-                                    }
-                            };
-
-                            let mut post = json!(post);
-                            
-                            if let Some((avatar, username)) = author_info {
-                                post.as_object_mut().unwrap().insert("authorAvatar".to_string(), Value::Number(avatar.into()));
-                                post.as_object_mut().unwrap().insert("authorName".to_string(), Value::String(username));
+                    let post = db.get::<Post>("posts", &post_id).await?.ok_or(NearsayError::NotFound("post"))?;
+                    
+                    let author_info = match &post.authorId {
+                        None => None,
+                        Some(author_id) => 
+                            match db.get::<User>("users", author_id).await {
+                                Ok(Some(user)) => Some((user.avatar, user.username)),
+                                _ => None
                             }
-                            post.as_object_mut().unwrap().remove("authorId");
-
-                            let mut response_body = json! ({"post": post});
-
-                            // if authentication fails, respond with just the post anyway
-                            let Ok(Some(JWTPayload {uid, ..})) = authenticate_with_header(&key, &headers) else { return json_response(200, response_body) };
-                            
-                            // if getting vote fails, respond with just the post
-                            let Ok(vote) = db.get_vote(&uid, &post_id).await else { return json_response(200, response_body) };
-                            
-                            response_body.as_object_mut().unwrap().insert("vote".to_string(), Value::String(VoteKind::as_str(&vote)));
-                            
-                            json_response(200, response_body)
-                        },
+                    };
+
+                    let attachments: Vec<PublicAttachment> = post.attachments.iter().map(PublicAttachment::from).collect();
+                    let mut post = json!(post);
+                    
+                    if let Some((avatar, username)) = author_info {
+                        post.as_object_mut().unwrap().insert("authorAvatar".to_string(), Value::Number(avatar.into()));
+                        post.as_object_mut().unwrap().insert("authorName".to_string(), Value::String(username));
                     }
+                    post.as_object_mut().unwrap().remove("authorId");
+                    post.as_object_mut().unwrap().insert("attachments".to_string(), json!(attachments));
+
+                    let mut response_body = json! ({"post": post});
 
+                    // if authentication fails, respond with just the post anyway
+                    let Ok(Some(JWTPayload {uid, ..})) = authenticate_with_header(&mut db, &key, &headers).await else { return Ok(json_response(200, response_body)) };
+                    
+                    // if getting vote fails, respond with just the post
+                    let Ok(vote) = db.get_vote(&uid, &post_id).await else { return Ok(json_response(200, response_body)) };
+                    
+                    response_body.as_object_mut().unwrap().insert("vote".to_string(), Value::String(VoteKind::as_str(&vote)));
+                    
+                    Ok(json_response(200, response_body))
+                }
+            }
+        ))
+        .route("/posts/{post_id}/comments", get(
+            clone_into_closure! {
+                (db)
+                |Path(post_id): Path<String>, Query(CommentsQuery { parent_id, after, limit }): Query<CommentsQuery>| async move {
+                    
+                    let limit = limit.unwrap_or(DEFAULT_COMMENTS_LIMIT);
+                    if !(1..=MAX_COMMENTS_LIMIT).contains(&limit) {
+                        return Err(NearsayError::validation("limit", format!("must be within 1..={MAX_COMMENTS_LIMIT}")));
+                    }
+                    
+                    let after = match after {
+                        None => None,
+                        Some(after) => Some(after.parse::<CommentCursor>().map_err(|_| NearsayError::validation("after", "must be the `next` of a previous page"))?),
+                    };
+                    
+                    let comments = db.get_comments(&post_id, parent_id.as_deref(), after.as_ref(), limit).await?;
+                    
+                    // a full page means there may be more
+                    let next = match comments.len() == limit {
+                        true => comments.last().map(|last| CommentCursor::from(last).to_string()),
+                        false => None,
+                    };
+                    
+                    // if getting authors fails, respond with just the comments
+                    let author_ids: Vec<&str> = comments.iter().filter_map(|comment| comment.authorId.as_deref()).collect();
+                    let authors = db.get_users(&author_ids).await.unwrap_or_default();
+                    
+                    let comments: Vec<Value> = comments.iter().map(|comment| {
+                        let mut json = json!(comment);
+                        
+                        if let Some(author) = comment.authorId.as_ref().and_then(|author_id| authors.get(author_id)) {
+                            json.as_object_mut().unwrap().insert("authorAvatar".to_string(), Value::Number(author.avatar.into()));
+                            json.as_object_mut().unwrap().insert("authorName".to_string(), Value::String(author.username.clone()));
+                        }
+                        json
+                    }).collect();
+                    
+                    Ok::<_, NearsayError>(json_response(200, json!({ "comments": comments, "next": next })))
+                }
+            }
+        ))
+        .route("/me/saved", get(
+            clone_into_closure! {
+                (db)
+                |auth: Auth<Members>, Query(SavedQuery { before, limit }): Query<SavedQuery>| async move {
+                    let limit = limit.unwrap_or(DEFAULT_SAVED_LIMIT);
+                    if !(1..=MAX_SAVED_LIMIT).contains(&limit) {
+                        return Err(NearsayError::validation("limit", format!("must be within 1..={MAX_SAVED_LIMIT}")));
+                    }
+                    
+                    let before = match before {
+                        None => None,
+                        Some(before) => Some(before.parse::<SavedCursor>().map_err(|_| NearsayError::validation("before", "must be the `next` of a previous page"))?),
+                    };
+                    
+                    let saved = db.get_saved_posts(auth.uid(), before.as_ref(), limit).await?;
+                    
+                    // a full page means there may be more. saves of deleted posts are left out, so pages can be short anyway
+                    let next = match saved.len() == limit {
+                        true => saved.last().map(|last| SavedCursor::from(last).to_string()),
+                        false => None,
+                    };
+                    
+                    Ok::<_, NearsayError>(json_response(200, json!({ "posts": saved, "next": next })))
+                }
+            }
+        ))
+        // upload an image to attach to a post. the body is the image file
+        .route("/attachments", post(
+            clone_into_closure! {
+                (db)
+                |auth: Auth<Anyone>, body: Body| async move {
+                    let max_bytes = db.config().attachment_max_bytes;
+                    let bytes = to_bytes(body, max_bytes).await
+                        .map_err(|_| NearsayError::validation("file", format!("must be at most {max_bytes} bytes")))?;
+                    
+                    // decoding and encoding images is too slow to do on the async runtime
+                    let thumbnail_size = db.config().thumbnail_size;
+                    let image = tokio::task::spawn_blocking(move || process_image(&bytes, thumbnail_size)).await
+                        .map_err(|join_err| {
+                            error!(error = %join_err, "image processing panicked");
+                            NearsayError::Internal
+                        })??;
+                    
+                    let attachment = db.insert_attachment(auth.uid(), image).await?;
+                    
+                    // the uploader gets the id to post it with, but not where it's stored either
+                    let mut body = json!(PublicAttachment::from(&attachment));
+                    body["id"] = json!(attachment._id);
+                    
+                    Ok::<_, NearsayError>(json_response(201, body))
                 }
             }
         ))
@@ -102,42 +264,227 @@ pub fn get_endpoints_router(db: &NearsayDB, key: &Hmac<Sha256>) -> axum::Router
                 |Path((query_type, query)): Path<(String, String)>| async move {
                     
                     if query_type == "online" {
-                        let Ok(Some((_, avatar))) = db.get_cache_pos_and_avatar(&query).await 
-                        else { return empty_response(404) };
+                        let (_, avatar) = db.get_cache_pos_and_avatar(&query).await?.ok_or(NearsayError::NotFound("user"))?;
                         
-                        let Ok(username) = db.get_cache_username(&query).await
-                        else { return empty_response(500) };
+                        let username = db.get_cache_username(&query).await?;
                         
-                        return json_response(200, &json!({
+                        return Ok(json_response(200, json!({
                             "id": query,
                             "avatar": avatar,
                             "username": username
-                        }));
+                        })));
                     }
                     
-                    let res = 
+                    let user = 
                         if query_type == "id" {
-                            db.get::<User>("users", &query).await
+                            db.get::<User>("users", &query).await?
                         }
                         else {
-                            db.get_user_from_username(&query).await
-                        };
+                            db.get_user_from_username(&query).await?
+                        }
+                        .ok_or(NearsayError::NotFound("user"))?;
                         
-                    match res { 
-                        Err(e) => {
-                            eprintln!("in user endpoint: {e:?}");
-                            empty_response(500)
-                        },
-                        Ok(None) => empty_response(404),
-                        Ok(Some(user)) => {
-                            json_response(200, &json!({
-                                "id": user._id,
-                                "username": user.username,
-                                "avatar": user.avatar
-                            }))
-                        },
+                    Ok::<_, NearsayError>(json_response(200, json!({
+                        "id": user._id,
+                        "username": user.username,
+                        "avatar": user.avatar
+                    })))
+                }
+            }
+        ))
+        
+        .route("/users/{query_type}/{query}/profile", get(
+            clone_into_closure_mut! {
+                (db, key)
+                |headers: HeaderMap, Path((query_type, query)): Path<(String, String)>| async move {
+                    // anyone can see a profile, but only its owner sees what's hidden.
+                    // if authentication fails, it's seen like anyone else would
+                    let viewer = authenticate_with_header(&mut db, &key, &headers).await.ok().flatten().map(|payload| payload.uid);
+                    
+                    let user = match query_type.as_str() {
+                        "id" => db.get::<User>("users", &query).await?,
+                        "username" => db.get_user_from_username(&query).await?,
+                        _ => return Err(NearsayError::validation("query_type", "must be \"id\" or \"username\"")),
                     }
+                    .ok_or(NearsayError::NotFound("user"))?;
+                    
+                    let profile = db.get_profile(user, viewer.as_deref()).await?;
+                    Ok::<_, NearsayError>(json_response(200, profile))
                 }
             }
         ))
-}
\ No newline at end of file
+        
+        // for `Auth` extractors
+        .layer(Extension(db.clone()))
+        .layer(Extension(key.clone()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::{to_bytes, Body}, http::{header::AUTHORIZATION, Request, StatusCode}, Router};
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use crate::{auth::{expired_access_token, JWTKey}, db::NearsayDB, stand_ins::failing_config};
+
+    use super::get_endpoints_router;
+
+    async fn send(router: &Router, req: Request<Body>) -> (StatusCode, Value) {
+        let res = router.clone().oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn keeps_serving_when_redis_and_mongo_fail() {
+        let config = failing_config().await;
+        let db = NearsayDB::connect(&config).await.unwrap();
+        let router = get_endpoints_router(&db, &JWTKey::new(&config).unwrap());
+        
+        for _ in 0..2 {
+            let (status, body) = send(&router, Request::get("/posts/post").body(Body::empty()).unwrap()).await;
+            assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+            assert_eq!("upstream_db", body["code"]);
+            
+            let (status, body) = send(&router, Request::get("/users/online/uid").body(Body::empty()).unwrap()).await;
+            assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+            assert_eq!("upstream_cache", body["code"]);
+            
+            let (status, body) = send(&router, Request::post("/vote/post").body(Body::from("like")).unwrap()).await;
+            assert_eq!(StatusCode::UNAUTHORIZED, status);
+            assert_eq!("unauthorized", body["code"]);
+            
+            let (status, body) = send(&router, Request::get("/posts/post/comments").body(Body::empty()).unwrap()).await;
+            assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+            assert_eq!("upstream_db", body["code"]);
+            
+            let (status, body) = send(&router, Request::post("/attachments").body(Body::from("image")).unwrap()).await;
+            assert_eq!(StatusCode::UNAUTHORIZED, status);
+            assert_eq!("unauthorized", body["code"]);
+            
+            let (status, body) = send(&router, Request::get("/users/username/someone/profile").body(Body::empty()).unwrap()).await;
+            assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+            assert_eq!("upstream_db", body["code"]);
+            
+            let (status, body) = send(&router, Request::get("/me/saved").body(Body::empty()).unwrap()).await;
+            assert_eq!(StatusCode::UNAUTHORIZED, status);
+            assert_eq!("unauthorized", body["code"]);
+            
+            let (status, body) = send(&router, Request::get("/users/online/uid/profile").body(Body::empty()).unwrap()).await;
+            assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, status);
+            assert_eq!("query_type", body["details"]["field"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn profile_is_public_with_a_bad_token() {
+        let config = failing_config().await;
+        let db = NearsayDB::connect(&config).await.unwrap();
+        let key = JWTKey::new(&config).unwrap();
+        let router = get_endpoints_router(&db, &key);
+        
+        for token in [format!("Bearer {}", expired_access_token(&key, "uid")), "Bearer garbage".to_string(), "garbage".to_string()] {
+            let req = Request::get("/users/username/someone/profile").header(AUTHORIZATION, token).body(Body::empty()).unwrap();
+            
+            // gets as far as looking the user up, instead of being turned away
+            let (status, body) = send(&router, req).await;
+            assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+            assert_eq!("upstream_db", body["code"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn search_is_validated() {
+        let config = failing_config().await;
+        let db = NearsayDB::connect(&config).await.unwrap();
+        let router = get_endpoints_router(&db, &JWTKey::new(&config).unwrap());
+        
+        for (query, field) in [
+            ("q=%20&lat=0&lng=0", "q"),
+            ("q=cat&lat=91&lng=0", "lat"),
+            ("q=cat&lat=0&lng=0&radius=0", "radius"),
+            ("q=cat&lat=0&lng=0&limit=51", "limit"),
+            ("q=cat&lat=0&lng=0&after=garbage", "after"),
+        ] {
+            let (status, body) = send(&router, Request::get(format!("/posts/search?{query}")).body(Body::empty()).unwrap()).await;
+            assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, status, "{query}");
+            assert_eq!(field, body["details"]["field"], "{query}");
+        }
+        
+        let (status, body) = send(&router, Request::get("/posts/search?q=cat&lat=0&lng=0").body(Body::empty()).unwrap()).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+        assert_eq!("upstream_db", body["code"]);
+    }
+
+    #[tokio::test]
+    async fn feed_is_validated() {
+        let config = failing_config().await;
+        let db = NearsayDB::connect(&config).await.unwrap();
+        let router = get_endpoints_router(&db, &JWTKey::new(&config).unwrap());
+        
+        for (query, field) in [
+            ("lat=0&lng=-181", "lng"),
+            ("lat=0&lng=0&radius=100001", "radius"),
+            ("lat=0&lng=0&limit=0", "limit"),
+            ("lat=0&lng=0&after=yesterday", "after"),
+        ] {
+            let (status, body) = send(&router, Request::get(format!("/feed?{query}")).body(Body::empty()).unwrap()).await;
+            assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, status, "{query}");
+            assert_eq!(field, body["details"]["field"], "{query}");
+        }
+        
+        let (status, body) = send(&router, Request::get("/feed?lat=0&lng=0&after=1700000000000.1.5.id").body(Body::empty()).unwrap()).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+        assert_eq!("upstream_db", body["code"]);
+    }
+
+    #[tokio::test]
+    async fn comment_pages_are_validated() {
+        let config = failing_config().await;
+        let db = NearsayDB::connect(&config).await.unwrap();
+        let router = get_endpoints_router(&db, &JWTKey::new(&config).unwrap());
+        
+        let (status, body) = send(&router, Request::get("/posts/post/comments?limit=0").body(Body::empty()).unwrap()).await;
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, status);
+        assert_eq!("limit", body["details"]["field"]);
+        
+        let (status, body) = send(&router, Request::get("/posts/post/comments?after=garbage").body(Body::empty()).unwrap()).await;
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, status);
+        assert_eq!("after", body["details"]["field"]);
+    }
+
+    #[tokio::test]
+    async fn readiness_reports_each_dependency() {
+        let config = failing_config().await;
+        let db = NearsayDB::connect(&config).await.unwrap();
+        let router = get_endpoints_router(&db, &JWTKey::new(&config).unwrap());
+        
+        let (status, body) = send(&router, Request::get("/healthz").body(Body::empty()).unwrap()).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("ok", body["status"]);
+        
+        let (status, body) = send(&router, Request::get("/readyz").body(Body::empty()).unwrap()).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+        assert_eq!(false, body["ready"]);
+        assert_eq!(false, body["cache_ready"]);
+        for dependency in ["posts_redis", "users_redis", "mongo"] {
+            assert_eq!(false, body["dependencies"][dependency]["ok"]);
+            assert!(body["dependencies"][dependency]["latency_ms"].is_number());
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_when_redis_fails() {
+        let config = failing_config().await;
+        let db = NearsayDB::connect(&config).await.unwrap();
+        let router = get_endpoints_router(&db, &JWTKey::new(&config).unwrap());
+        
+        let res = router.oneshot(Request::get("/metrics").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        
+        let body = String::from_utf8(to_bytes(res.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        assert!(body.contains("nearsay_redis_duration_seconds_count{cache=\"users\",command=\"ZCARD\"}"));
+    }
+}
@@ -0,0 +1,94 @@
+use std::{future::Future, time::{Duration, Instant}};
+
+use nearsay_server::NearsayError;
+use serde::Serialize;
+
+use crate::db::NearsayDB;
+
+/// how long a dependency can take to answer a ping before it's reported as down
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Debug)]
+pub struct DependencyStatus {
+    pub ok: bool,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Dependencies {
+    pub posts_redis: DependencyStatus,
+    pub users_redis: DependencyStatus,
+    pub mongo: DependencyStatus,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    /// false while the posts cache is still being rebuilt after startup
+    pub cache_ready: bool,
+    pub dependencies: Dependencies,
+}
+
+/// times `ping`, giving up after `PING_TIMEOUT`
+async fn check(ping: impl Future<Output = Result<(), NearsayError>>) -> DependencyStatus {
+    let started = Instant::now();
+    let res = tokio::time::timeout(PING_TIMEOUT, ping).await;
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+    match res {
+        Ok(Ok(())) => DependencyStatus { ok: true, latency_ms, error: None },
+        Ok(Err(nearsay_err)) => DependencyStatus { ok: false, latency_ms, error: Some(nearsay_err.message()) },
+        Err(_) => DependencyStatus { ok: false, latency_ms, error: Some("timed out".to_string()) },
+    }
+}
+
+/// pings every dependency at once
+pub async fn readiness(db: &NearsayDB) -> Readiness {
+    let (mut posts_db, mut users_db) = (db.clone(), db.clone());
+
+    let (posts_redis, users_redis, mongo) = tokio::join!(
+        check(posts_db.ping_posts_cache()),
+        check(users_db.ping_users_cache()),
+        check(db.ping_mongo()),
+    );
+
+    let cache_ready = db.is_cache_ready();
+
+    Readiness {
+        ready: cache_ready && posts_redis.ok && users_redis.ok && mongo.ok,
+        cache_ready,
+        dependencies: Dependencies { posts_redis, users_redis, mongo },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use nearsay_server::NearsayError;
+
+    use super::check;
+
+    #[tokio::test]
+    async fn check_reports_errors() {
+        assert!(check(async { Ok(()) }).await.ok);
+
+        let failed = check(async { Err(NearsayError::UpstreamCache) }).await;
+        assert!(!failed.ok);
+        assert_eq!(Some("cache unavailable".to_string()), failed.error);
+    }
+
+    #[tokio::test]
+    async fn check_times_out() {
+        let hung = check(async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        }).await;
+
+        assert!(!hung.ok);
+        assert_eq!(Some("timed out".to_string()), hung.error);
+    }
+}
@@ -1,6 +1,8 @@
-use std::time::SystemTime;
+use std::{fmt, time::SystemTime};
 
-use axum::{body::Body, response::{IntoResponse, Response}};
+use axum::{body::Body, http::StatusCode, response::{IntoResponse, Response}};
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use serde_json::{json, Value};
 
 
 #[macro_export]
@@ -23,29 +25,170 @@ macro_rules! clone_into_closure_mut {
     };
 }
 
+/// every error a client can get back.
+/// 
+/// http responses and socket acks both carry it as `{ code, message, details }`, where `code` is stable for clients to match on
 #[derive(Debug)]
 pub enum NearsayError {
-    ServerError,
-    UserNotFound,
+    /// input was malformed or out of range
+    Validation { field: &'static str, reason: String },
+    /// the named thing (e.g. "user", "post") doesn't exist
+    NotFound(&'static str),
+    Conflict { field: &'static str, reason: String },
     Unauthorized,
-    UsernameTaken,
+    Forbidden,
+    RateLimited { retry_after_secs: u64 },
+
+    /// the redis cache failed. the underlying error is logged, not sent to clients
+    UpstreamCache,
+    /// mongodb failed. the underlying error is logged, not sent to clients
+    UpstreamDb,
+    /// anything else that's our fault. the underlying error is logged, not sent to clients
+    Internal,
 }
 impl NearsayError {
-    pub fn to_status_code(self) -> u16 {
+    pub fn validation(field: &'static str, reason: impl Into<String>) -> Self {
+        NearsayError::Validation { field, reason: reason.into() }
+    }
+
+    pub fn conflict(field: &'static str, reason: impl Into<String>) -> Self {
+        NearsayError::Conflict { field, reason: reason.into() }
+    }
+
+    pub fn to_status_code(&self) -> u16 {
         match self {
-            NearsayError::ServerError => 500,
-            NearsayError::UserNotFound => 404,
+            NearsayError::Validation { .. } => 422,
+            NearsayError::NotFound(_) => 404,
+            NearsayError::Conflict { .. } => 409,
             NearsayError::Unauthorized => 401,
-            NearsayError::UsernameTaken => 409,
+            NearsayError::Forbidden => 403,
+            NearsayError::RateLimited { .. } => 429,
+            NearsayError::UpstreamCache | NearsayError::UpstreamDb => 503,
+            NearsayError::Internal => 500,
         }
     }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            NearsayError::Validation { .. } => "validation",
+            NearsayError::NotFound(_) => "not_found",
+            NearsayError::Conflict { .. } => "conflict",
+            NearsayError::Unauthorized => "unauthorized",
+            NearsayError::Forbidden => "forbidden",
+            NearsayError::RateLimited { .. } => "rate_limited",
+            NearsayError::UpstreamCache => "upstream_cache",
+            NearsayError::UpstreamDb => "upstream_db",
+            NearsayError::Internal => "internal",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            NearsayError::Validation { field, reason } => format!("invalid {field}: {reason}"),
+            NearsayError::NotFound(what) => format!("{what} not found"),
+            NearsayError::Conflict { field, reason } => format!("{field} conflict: {reason}"),
+            NearsayError::Unauthorized => "missing, invalid, or revoked token".to_string(),
+            NearsayError::Forbidden => "not allowed for this account".to_string(),
+            NearsayError::RateLimited { retry_after_secs } => format!("too many requests, retry in {retry_after_secs}s"),
+            NearsayError::UpstreamCache => "cache unavailable".to_string(),
+            NearsayError::UpstreamDb => "database unavailable".to_string(),
+            NearsayError::Internal => "internal server error".to_string(),
+        }
+    }
+
+    pub fn details(&self) -> Value {
+        match self {
+            NearsayError::Validation { field, .. } | NearsayError::Conflict { field, .. } => json!({ "field": field }),
+            NearsayError::NotFound(what) => json!({ "resource": what }),
+            NearsayError::RateLimited { retry_after_secs } => json!({ "retry_after_secs": retry_after_secs }),
+            _ => Value::Null,
+        }
+    }
+}
+impl fmt::Display for NearsayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+impl std::error::Error for NearsayError {}
+
+impl Serialize for NearsayError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut body = serializer.serialize_struct("NearsayError", 3)?;
+        body.serialize_field("code", self.code())?;
+        body.serialize_field("message", &self.message())?;
+        body.serialize_field("details", &self.details())?;
+        body.end()
+    }
 }
 impl IntoResponse for NearsayError {
     fn into_response(self) -> Response {
-        Response::builder().status(self.to_status_code()).body(Body::empty()).unwrap()
+        Response::builder()
+            .status(self.to_status_code())
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_vec(&self).unwrap_or_default()))
+            .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+    }
+}
+
+impl From<mongodb::error::Error> for NearsayError {
+    fn from(mongo_err: mongodb::error::Error) -> Self {
+        tracing::error!(error = %mongo_err, "mongodb error");
+        NearsayError::UpstreamDb
+    }
+}
+impl From<redis::RedisError> for NearsayError {
+    fn from(redis_err: redis::RedisError) -> Self {
+        tracing::error!(error = %redis_err, "redis error");
+        NearsayError::UpstreamCache
+    }
+}
+impl From<bcrypt::BcryptError> for NearsayError {
+    fn from(bcrypt_err: bcrypt::BcryptError) -> Self {
+        tracing::error!(error = %bcrypt_err, "bcrypt error");
+        NearsayError::Internal
+    }
+}
+/// a stored document missing a field we rely on
+impl From<mongodb::bson::document::ValueAccessError> for NearsayError {
+    fn from(access_err: mongodb::bson::document::ValueAccessError) -> Self {
+        tracing::error!(error = %access_err, "malformed document");
+        NearsayError::Internal
+    }
+}
+impl From<mongodb::bson::ser::Error> for NearsayError {
+    fn from(ser_err: mongodb::bson::ser::Error) -> Self {
+        tracing::error!(error = %ser_err, "couldn't serialize to bson");
+        NearsayError::Internal
+    }
+}
+/// a jwt that fails to parse or verify is the client's fault
+impl From<jwt::Error> for NearsayError {
+    fn from(jwt_err: jwt::Error) -> Self {
+        tracing::debug!(error = %jwt_err, "invalid jwt");
+        NearsayError::Unauthorized
     }
 }
 
 pub fn current_time_ms() -> u64 {
     SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis().try_into().expect("current time millis doesnt fit into i64")
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::NearsayError;
+
+    #[test]
+    fn error_json_shape() {
+        assert_eq!(
+            json!({ "code": "validation", "message": "invalid zoom: too far", "details": { "field": "zoom" } }),
+            json!(NearsayError::validation("zoom", "too far"))
+        );
+        assert_eq!(
+            json!({ "code": "upstream_db", "message": "database unavailable", "details": null }),
+            json!(NearsayError::UpstreamDb)
+        );
+    }
+}
@@ -0,0 +1,33 @@
+use axum::{body::Body, http::Request};
+use socketioxide::extract::SocketRef;
+use tracing::{field::Empty, info_span, Span};
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{Config, LogFormat};
+
+/// installs the global `tracing` subscriber
+pub fn init(config: &Config) {
+    // already validated by `Config::validate`
+    let filter = EnvFilter::new(&config.log_level);
+    let fmt = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match config.log_format {
+        LogFormat::Text => fmt.init(),
+        LogFormat::Json => fmt.json().with_current_span(true).with_span_list(false).init(),
+    }
+}
+
+/// span for handling one socket event. `uid` is recorded once the sender is known
+pub fn socket_event_span(client_socket: &SocketRef, event: &'static str) -> Span {
+    info_span!("socket_event", socket_id = %client_socket.id, event, uid = Empty)
+}
+
+/// span for handling one http request. `uid` is recorded once the request is authenticated
+pub fn http_request_span(req: &Request<Body>) -> Span {
+    info_span!("http_request", method = %req.method(), path = %req.uri().path(), uid = Empty)
+}
+
+/// records the user the current socket event or http request is from
+pub fn record_uid(uid: &str) {
+    Span::current().record("uid", uid);
+}
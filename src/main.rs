@@ -1,51 +1,140 @@
-use std::env;
+use std::{future::IntoFuture, sync::Arc, time::Duration};
 
-use hmac::{Hmac, Mac};
+use auth::JWTKey;
+use config::Config;
 use db::NearsayDB;
 use endpoints::get_endpoints_router;
-use socket::on_socket_connect;
+use socket::{broadcast_expired_posts, notify_shutdown, on_socket_connect};
 use socketioxide::SocketIo;
-use tower_http::cors::CorsLayer;
+use tower_http::{cors::CorsLayer, trace::{DefaultOnResponse, TraceLayer}};
+use tokio::sync::Notify;
+use tracing::{error, info, warn, Level};
 use nearsay_server::clone_into_closure;
 
 mod area;
+mod attachments;
+mod config;
+mod health;
+mod logging;
+mod metrics;
 mod types;
 mod cache;
 mod cluster;
 mod db;
 mod endpoints;
 mod socket;
+mod storage;
 mod auth;
+#[cfg(test)]
+mod stand_ins;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    dotenvy::dotenv()?;
-    let key = Hmac::new_from_slice(env::var("JWT_SECRET").unwrap().as_bytes()).unwrap();
+    dotenvy::dotenv().ok();
+    
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(config_err) => {
+            eprintln!("{config_err}");
+            std::process::exit(1);
+        }
+    };
+    
+    logging::init(&config);
+    
+    let key = JWTKey::new(&config)?;
+
+    let mut nearsay_db = NearsayDB::new(&config).await?;
+    let (app, io) = app(&nearsay_db, &key);
+
+    let listener = tokio::net::TcpListener::bind(&config.bind_addr).await?;
+    info!(bind_addr = config.bind_addr, "listening");
+    
+    // once notified, stops accepting connections and waits for open ones to close
+    let stop_accepting = Arc::new(Notify::new());
+    let mut server = tokio::spawn(
+        axum::serve(listener, app)
+            .with_graceful_shutdown(clone_into_closure! { (stop_accepting) async move { stop_accepting.notified().await } })
+            .into_future()
+    );
+    
+    tokio::select! {
+        res = &mut server => return Ok(res??),
+        _ = shutdown_signal() => {},
+    }
+    
+    info!(deadline_secs = config.shutdown_deadline_secs, "shutting down");
+    stop_accepting.notify_one();
+    
+    let shutdown = async {
+        notify_shutdown(&io, &mut nearsay_db).await;
+        nearsay_db.stop_cleanup_job().await;
+        server.await
+    };
+    match tokio::time::timeout(Duration::from_secs(config.shutdown_deadline_secs), shutdown).await {
+        Ok(Ok(Ok(()))) => info!("shut down"),
+        Ok(Ok(Err(e))) => error!(error = %e, "server failed while shutting down"),
+        Ok(Err(e)) => error!(error = %e, "server task failed while shutting down"),
+        Err(_) => warn!("shutdown deadline passed, exiting anyway"),
+    }
+    
+    Ok(())
+}
 
-    let nearsay_db = NearsayDB::new().await;
+/// resolves on ctrl-c or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!(error = %e, "couldn't listen for ctrl-c");
+            std::future::pending::<()>().await;
+        }
+    };
+    
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => { sigterm.recv().await; },
+            Err(e) => {
+                error!(error = %e, "couldn't listen for SIGTERM");
+                std::future::pending::<()>().await;
+            },
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
 
+/// the http endpoints + socket.io server, and a handle to the socket.io server
+fn app(nearsay_db: &NearsayDB, key: &JWTKey) -> (axum::Router, SocketIo) {
     let (socketio_layer, io) = SocketIo::new_layer();
     io.ns("/", clone_into_closure! { 
         (nearsay_db, key) 
         move |client_socket| on_socket_connect(client_socket, &nearsay_db, &key) 
     });
+    broadcast_expired_posts(&io, nearsay_db);
 
-    let app = axum::Router::new()
-        .merge(get_endpoints_router(&nearsay_db, &key))
+    let router = axum::Router::new()
+        .merge(get_endpoints_router(nearsay_db, key))
+        .layer(TraceLayer::new_for_http()
+            .make_span_with(logging::http_request_span)
+            .on_response(DefaultOnResponse::new().level(Level::INFO))
+        )
         .layer(socketio_layer)
         .layer(CorsLayer::permissive());
     
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:21114").await.unwrap();
-    axum::serve(listener, app).await?;
-    
-    Ok(())
+    (router, io)
 }
 
 #[cfg(test)]
 mod tests {
     use rand::Rng;
 
-    use crate::db::NearsayDB;
+    use crate::{config::Config, db::NearsayDB, types::{Category, PostLifetime}};
 
     fn trunc_2_decimals(x: f64) -> f64 {
         (x * 100.0).round() / 100.0
@@ -53,7 +142,8 @@ mod tests {
 
     #[tokio::test]
     async fn populate_random() {
-        let mut nearsay_db = NearsayDB::new().await;
+        dotenvy::dotenv().ok();
+        let mut nearsay_db = NearsayDB::new(&Config::load().unwrap()).await.unwrap();
         
         let mut rng = rand::thread_rng();
         
@@ -63,7 +153,10 @@ mod tests {
             nearsay_db.insert_post(
                 Some("author_id"), 
                 &[x, y], 
-                &format!("blurb{}", rng.gen_range(-180.0..=180.0))
+                &format!("blurb{}", rng.gen_range(-180.0..=180.0)),
+                Category::General,
+                PostLifetime::Default,
+                &[]
             ).await.unwrap();
         }
     }
@@ -0,0 +1,145 @@
+use std::{future::Future, sync::LazyLock};
+
+use mongodb::event::command::CommandEvent;
+use prometheus::{register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge, Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder};
+use redis::{aio::{ConnectionLike, MultiplexedConnection}, Arg, Cmd, Pipeline, RedisFuture, Value};
+
+// every metric is registered with the default registry the first time it's used
+
+pub static CONNECTED_SOCKETS: LazyLock<IntGauge> = LazyLock::new(|| register_int_gauge!(
+    "nearsay_connected_sockets", "sockets currently connected"
+).unwrap());
+
+/// set from the users cache whenever metrics are scraped
+pub static USERS_ONLINE: LazyLock<IntGauge> = LazyLock::new(|| register_int_gauge!(
+    "nearsay_users_online", "users (including guests) currently on the map"
+).unwrap());
+
+pub static SOCKET_EVENTS: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "nearsay_socket_events_total", "socket events handled", &["event"]
+).unwrap());
+
+pub static SOCKET_EVENT_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| register_histogram_vec!(
+    "nearsay_socket_event_duration_seconds", "time spent handling each socket event", &["event"]
+).unwrap());
+
+/// errors acked to socket clients, by `NearsayError::code`
+pub static SOCKET_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "nearsay_socket_errors_total", "errors acked to socket clients", &["code"]
+).unwrap());
+
+pub static POSTS_CREATED: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "nearsay_posts_created_total", "posts created"
+).unwrap());
+
+pub static VOTES: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "nearsay_votes_total", "votes cast", &["kind"]
+).unwrap());
+
+/// where `NearsayDB::geoquery_post_pts` got its clusters from: "cache", or why it went to mongodb instead:
+/// "cache_not_ready", "tag_filter", "uncached_zoom", or "cache_error"
+pub static POST_PTS_QUERIES: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "nearsay_post_pts_queries_total", "post pt queries by source", &["source"]
+).unwrap());
+
+pub static REDIS_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| register_histogram_vec!(
+    "nearsay_redis_duration_seconds", "redis round trip time of commands and pipelines", &["cache", "command"]
+).unwrap());
+
+pub static MONGO_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| register_histogram_vec!(
+    "nearsay_mongo_duration_seconds", "mongodb command time", &["command", "outcome"]
+).unwrap());
+
+pub static CLEANUP_SECONDS: LazyLock<Histogram> = LazyLock::new(|| register_histogram!(
+    "nearsay_cleanup_duration_seconds", "time taken by the nightly cleanup of unposted attachments",
+    vec![1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0]
+).unwrap());
+
+pub static SWEEP_DELETED_POSTS: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "nearsay_sweep_deleted_posts_total", "expired posts deleted by the sweeper"
+).unwrap());
+
+
+/// every registered metric, in the prometheus text format
+pub fn render() -> String {
+    let mut buf = vec![];
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buf) {
+        tracing::error!(error = %e, "couldn't encode metrics");
+    }
+    String::from_utf8(buf).unwrap_or_default()
+}
+
+/// counts and times one socket event handler
+pub async fn observe_socket_event(event: &'static str, handler: impl Future<Output = ()>) {
+    SOCKET_EVENTS.with_label_values(&[event]).inc();
+    let timer = SOCKET_EVENT_SECONDS.with_label_values(&[event]).start_timer();
+    handler.await;
+    timer.observe_duration();
+}
+
+/// for `mongodb::options::ClientOptions::command_event_handler`
+pub fn observe_mongo_command(event: CommandEvent) {
+    match event {
+        CommandEvent::Succeeded(e) => MONGO_SECONDS.with_label_values(&[&e.command_name, "ok"]).observe(e.duration.as_secs_f64()),
+        CommandEvent::Failed(e) => MONGO_SECONDS.with_label_values(&[&e.command_name, "error"]).observe(e.duration.as_secs_f64()),
+        _ => {},
+    }
+}
+
+
+/// a redis connection that records the round trip time of every command and pipeline
+#[derive(Debug, Clone)]
+pub struct TimedConnection {
+    conn: MultiplexedConnection,
+    /// "posts" or "users"
+    cache: &'static str,
+}
+impl TimedConnection {
+    pub fn new(conn: MultiplexedConnection, cache: &'static str) -> Self {
+        Self { conn, cache }
+    }
+}
+impl ConnectionLike for TimedConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        let command = match cmd.args_iter().next() {
+            Some(Arg::Simple(name)) => String::from_utf8_lossy(name).to_uppercase(),
+            _ => "unknown".to_string(),
+        };
+        let timer = REDIS_SECONDS.with_label_values(&[self.cache, &command]).start_timer();
+
+        Box::pin(async move {
+            let res = self.conn.req_packed_command(cmd).await;
+            timer.observe_duration();
+            res
+        })
+    }
+
+    fn req_packed_commands<'a>(&'a mut self, cmd: &'a Pipeline, offset: usize, count: usize) -> RedisFuture<'a, Vec<Value>> {
+        let timer = REDIS_SECONDS.with_label_values(&[self.cache, "pipeline"]).start_timer();
+
+        Box::pin(async move {
+            let res = self.conn.req_packed_commands(cmd, offset, count).await;
+            timer.observe_duration();
+            res
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        self.conn.get_db()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{render, SOCKET_EVENTS};
+
+    #[test]
+    fn renders_registered_metrics() {
+        SOCKET_EVENTS.with_label_values(&["view-shift"]).inc();
+
+        let text = render();
+        assert!(text.contains("# TYPE nearsay_socket_events_total counter"));
+        assert!(text.contains("nearsay_socket_events_total{event=\"view-shift\"}"));
+    }
+}
@@ -1,12 +1,13 @@
-use hmac::Hmac;
 use mongodb::bson::doc;
+use nearsay_server::{current_time_ms, NearsayError};
 use serde::{Deserialize, Serialize};
 use nearsay_server::{clone_into_closure, clone_into_closure_mut};
 use serde_json::json;
-use sha2::Sha256;
-use socketioxide::extract::{AckSender, Data, SocketRef};
+use socketioxide::{extract::{AckSender, Data, SocketRef}, SocketIo};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, warn, Instrument};
 
-use crate::{area::{Rect, MAX_TILE_LAYER, WORLD_MAX_BOUND}, auth::{authenticate_jwt, create_jwt, verify_password, JWTPayload}, cache::UserPOI, cluster::{Cluster, MAX_ZOOM_LEVEL, MIN_ZOOM_LEVEL}, db::{gen_id, NearsayDB}, types::{Post, User}};
+use crate::{area::{Rect, MAX_TILE_LAYER, WORLD_MAX_BOUND}, attachments::MAX_ATTACHMENTS_PER_POST, auth::{issue_tokens, refresh_tokens, verify_password, Anyone, Auth, Guests, JWTKey, Members, TokenPair}, cache::UserPOI, logging::{record_uid, socket_event_span}, metrics::{self, observe_socket_event}, cluster::{Cluster, MAX_ZOOM_LEVEL, MIN_ZOOM_LEVEL}, db::{gen_id, NearsayDB}, types::{parse_hashtags, Category, Comment, EventWindow, FeedQuery, Post, PostFilter, PostLifetime, Privacy, Role, SearchQuery, User}};
 
 /// if a `uid` is given, exclude that user from returned users 
 #[derive(Deserialize, Debug)]
@@ -14,7 +15,10 @@ struct ViewShiftData {
     uid: Option<String>,
     zoom: usize,
     tile_layer: usize,
-    view: [Option<Rect>; 2]
+    view: [Option<Rect>; 2],
+    /// only return posts matching this
+    #[serde(default)]
+    filter: PostFilter,
 }
 #[derive(Serialize, Default, Debug)]
 struct ViewShiftResponse {
@@ -34,7 +38,24 @@ struct MoveData {
 struct NewPostData {
     jwt: Option<String>,
     pos: [f64; 2],
-    body: String
+    body: String,
+    #[serde(default)]
+    category: Category,
+    /// ms since epoch. event posts can have both of these, see `EventWindow`
+    starts_at: Option<i64>,
+    ends_at: Option<i64>,
+    /// how long until the post expires, see `NearsayDB::insert_post`
+    lifetime_secs: Option<u64>,
+    /// ids of attachments uploaded by the author
+    #[serde(default)]
+    attachments: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EditPostData {
+    jwt: String,
+    post_id: String,
+    body: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -44,6 +65,28 @@ struct DeletePostData {
 }
 
 
+#[derive(Deserialize, Debug)]
+struct NewCommentData {
+    jwt: Option<String>,
+    post_id: String,
+    /// id of the comment being replied to
+    parent_id: Option<String>,
+    body: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct DeleteCommentData {
+    jwt: String,
+    comment_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct SavePostData {
+    jwt: String,
+    post_id: String,
+}
+
+
 #[derive(Deserialize, Debug)]
 struct NewGuestData {
     pos: [f64; 2],
@@ -98,6 +141,18 @@ struct EditUserData {
     jwt: String,
     avatar: Option<usize>,
     username: Option<String>, 
+    privacy: Option<Privacy>,
+    notify_on_save: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SignOutAllData {
+    jwt: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RefreshTokenData {
+    refresh_token: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -107,15 +162,33 @@ struct ChatData {
     pos: [f64; 2]
 }
 
-pub fn on_socket_connect(client_socket: SocketRef, db: &NearsayDB, key: &Hmac<Sha256>) {
+/// acks the value if `Ok`, or the error's `{ code, message, details }` if `Err`
+fn ack_result<T: Serialize>(ack: AckSender, res: Result<T, NearsayError>) {
+    let sent = match res {
+        Ok(val) => ack.send(&val),
+        Err(nearsay_err) => {
+            metrics::SOCKET_ERRORS.with_label_values(&[nearsay_err.code()]).inc();
+            ack.send(&nearsay_err)
+        },
+    };
+    // the client may have disconnected before the handler finished
+    if let Err(send_err) = sent {
+        debug!(error = %send_err, "couldn't send ack");
+    }
+}
+
+pub fn on_socket_connect(client_socket: SocketRef, db: &NearsayDB, key: &JWTKey) {
+    info!(socket_id = %client_socket.id, "socket connected");
+    metrics::CONNECTED_SOCKETS.inc();
     
-    /// returns `Ok(guest jwt)`
-    async fn enter_world_as_guest(db: &mut NearsayDB, key: &Hmac<Sha256>, client_socket: SocketRef, pos: [f64; 2], avatar: usize) -> Result<String, ()> {
+    /// returns `Ok(guest tokens)`
+    async fn enter_world_as_guest(db: &mut NearsayDB, key: &JWTKey, client_socket: SocketRef, pos: [f64; 2], avatar: usize) -> Result<TokenPair, NearsayError> {
         let uid = gen_id();
+        record_uid(&uid);
         enter_world(db, client_socket, &uid, pos, avatar, None).await?;
-        create_jwt(&key, uid)
+        issue_tokens(db, key, &uid).await
     }
-    async fn enter_world(db: &mut NearsayDB, client_socket: SocketRef, uid: &str, pos: [f64; 2], avatar: usize, username: Option<&str>) -> Result<(), ()> {
+    async fn enter_world(db: &mut NearsayDB, client_socket: SocketRef, uid: &str, pos: [f64; 2], avatar: usize, username: Option<&str>) -> Result<(), NearsayError> {
         
         db.add_user_to_cache(uid, client_socket.id.as_str(), &pos, avatar, username).await?;
         
@@ -135,11 +208,11 @@ pub fn on_socket_connect(client_socket: SocketRef, db: &NearsayDB, key: &Hmac<Sh
         "enter-world-as-guest", 
         clone_into_closure_mut! {
             (db, key)
-            |client_socket: SocketRef, Data(NewGuestData { pos, avatar }), ack: AckSender| async move {
-                match enter_world_as_guest(&mut db, &key, client_socket, pos, avatar).await {
-                    Ok(jwt) => ack.send(&jwt).unwrap(),
-                    Err(()) => ack.send(&500).unwrap(),
-                }
+            |client_socket: SocketRef, Data(NewGuestData { pos, avatar }), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "enter-world-as-guest");
+                observe_socket_event("enter-world-as-guest", async move {
+                    ack_result(ack, enter_world_as_guest(&mut db, &key, client_socket, pos, avatar).await);
+                }).instrument(span)
             }
         }
     );
@@ -148,23 +221,16 @@ pub fn on_socket_connect(client_socket: SocketRef, db: &NearsayDB, key: &Hmac<Sh
         "sign-up-from-guest",
         clone_into_closure_mut! {
             (db, key)
-            |client_socket: SocketRef, Data(SignUpFromGuestData{ guest_jwt, username, password }), ack: AckSender| async move {
-                
-                let Ok(JWTPayload{uid}) = authenticate_jwt(&key, &guest_jwt)
-                else { return ack.send(&401).unwrap() };
-                
-                let ((x, y), avatar) = match db.get_cache_pos_and_avatar(&uid).await {
-                    Err(_) => return ack.send(&500).unwrap(),
-                    Ok(None) => return ack.send(&404).unwrap(),
-                    Ok(Some(vals)) => vals,
-                };
-                
-                match db.insert_user(&uid, &username, &password, avatar).await {
-                    Err(nearsay_err) => {
-                        eprintln!("{nearsay_err:?}");
-                        ack.send(&nearsay_err.to_status_code()).unwrap()
-                    },
-                    Ok(_) => {
+            |client_socket: SocketRef, Data(SignUpFromGuestData{ guest_jwt, username, password }), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "sign-up-from-guest");
+                observe_socket_event("sign-up-from-guest", async move {
+                    let res = async {
+                        let uid = Auth::<Guests>::from_jwt(&mut db, &key, &guest_jwt).await?.payload.uid;
+                        
+                        let ((x, y), avatar) = db.get_cache_pos_and_avatar(&uid).await?.ok_or(NearsayError::NotFound("user"))?;
+                        
+                        db.insert_user(&uid, &username, &password, avatar).await?;
+                        
                         broadcast_at(&client_socket, [x, y], "user-update", false, 
                             &json!({
                                 "id": uid,
@@ -173,9 +239,13 @@ pub fn on_socket_connect(client_socket: SocketRef, db: &NearsayDB, key: &Hmac<Sh
                             })
                         );
                         
-                        ack.send(&()).unwrap()
-                    },
-                }
+                        // replace guest tokens with member tokens
+                        db.revoke_tokens(&uid).await?;
+                        issue_tokens(&mut db, &key, &uid).await
+                    }.await;
+                    
+                    ack_result(ack, res);
+                }).instrument(span)
             }
         }
     );
@@ -185,22 +255,28 @@ pub fn on_socket_connect(client_socket: SocketRef, db: &NearsayDB, key: &Hmac<Sh
         "sign-up",
         clone_into_closure_mut! {
             (db, key)
-            |client_socket: SocketRef, Data(SignUpData{ username, password, avatar, pos }), ack: AckSender| async move {
-                
-                if username.len() > 50 { return ack.send(&406).unwrap() }
-                
-                let uid = gen_id();
-                let Ok(jwt) = create_jwt(&key, uid.clone()) else { return ack.send(&500).unwrap() };
-                
-                if let Err(err) = db.insert_user(&uid, &username, &password, avatar).await {
-                    return ack.send(&err.to_status_code()).unwrap();
-                }
-                
-                if let Some(pos) = pos {
-                    enter_world(&mut db, client_socket, &uid, pos, avatar, Some(&username)).await.unwrap();
-                }
-
-                ack.send(&jwt).unwrap()
+            |client_socket: SocketRef, Data(SignUpData{ username, password, avatar, pos }), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "sign-up");
+                observe_socket_event("sign-up", async move {
+                    let res = async {
+                        if username.len() > 50 { return Err(NearsayError::validation("username", "must be at most 50 characters")) }
+                        
+                        let uid = gen_id();
+                        record_uid(&uid);
+                        
+                        db.insert_user(&uid, &username, &password, avatar).await?;
+                        
+                        let tokens = issue_tokens(&mut db, &key, &uid).await?;
+                        
+                        if let Some(pos) = pos {
+                            enter_world(&mut db, client_socket, &uid, pos, avatar, Some(&username)).await?;
+                        }
+                        
+                        Ok(tokens)
+                    }.await;
+                    
+                    ack_result(ack, res);
+                }).instrument(span)
             }
         }
     );
@@ -210,42 +286,42 @@ pub fn on_socket_connect(client_socket: SocketRef, db: &NearsayDB, key: &Hmac<Sh
         "sign-in",
         clone_into_closure_mut! {
             (db, key)
-            |client_socket: SocketRef, Data(SignInData{username, password, pos, guest_jwt}), ack: AckSender| async move {
-                
-                // check if user exists
-                let user = match db.get_user_from_username(&username).await {
-                    Err(_) => return ack.send(&500).unwrap(),
-                    Ok(None) => return ack.send(&404).unwrap(),
-                    Ok(Some(user)) => user
-                };
-                
-                // verify password
-                match verify_password(&password, &user.hash[..]) {
-                    Err(_) => return ack.send(&500).unwrap(),
-                    Ok(false) => return ack.send(&401).unwrap(),
-                    Ok(true) => {},
-                }
-                
-                // if guest jwt was given, verify it before removing guest from cache
-                if let Some(guest_jwt) = guest_jwt {
-                    if let Ok(JWTPayload { uid, .. }) = authenticate_jwt(&key, &guest_jwt) {
-                        if let Ok(Some((pos, _))) = db.get_cache_pos_and_avatar(&uid).await {
-                            db.delete_user_from_cache(Some(&uid), client_socket.id.as_str()).await.unwrap();
-                            broadcast_at(&client_socket, pos.into(), "user-leave", false, &uid);
+            |client_socket: SocketRef, Data(SignInData{username, password, pos, guest_jwt}), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "sign-in");
+                observe_socket_event("sign-in", async move {
+                    let res = async {
+                        // check if user exists
+                        let user = db.get_user_from_username(&username).await?.ok_or(NearsayError::NotFound("user"))?;
+                        record_uid(&user._id);
+                        
+                        // verify password
+                        if !verify_password(&password, &user.hash[..])? {
+                            return Err(NearsayError::Unauthorized);
                         }
-                    }
-                }
-
-                // create jwt with this uid
-                let Ok(jwt) = create_jwt(&key, user._id.clone()) 
-                else { return ack.send(&500).unwrap() };
-                
-                if let Some(pos) = pos {
-                    enter_world(&mut db, client_socket, &user._id, pos, user.avatar, Some(&username)).await.unwrap();
-                }
-                
-                ack.send( &json!({ "jwt": jwt, "avatar": user.avatar })).unwrap();
-                
+                        
+                        // if guest jwt was given, verify it before removing guest from cache
+                        if let Some(guest_jwt) = guest_jwt {
+                            if let Ok(Auth { payload, .. }) = Auth::<Guests>::from_jwt(&mut db, &key, &guest_jwt).await {
+                                let uid = payload.uid;
+                                if let Some((pos, _)) = db.get_cache_pos_and_avatar(&uid).await? {
+                                    db.delete_user_from_cache(Some(&uid), client_socket.id.as_str()).await?;
+                                    broadcast_at(&client_socket, pos.into(), "user-leave", false, &uid);
+                                }
+                            }
+                        }
+                        
+                        // create tokens with this uid
+                        let TokenPair { jwt, refresh_token } = issue_tokens(&mut db, &key, &user._id).await?;
+                        
+                        if let Some(pos) = pos {
+                            enter_world(&mut db, client_socket, &user._id, pos, user.avatar, Some(&username)).await?;
+                        }
+                        
+                        Ok(json!({ "jwt": jwt, "refresh_token": refresh_token, "avatar": user.avatar }))
+                    }.await;
+                    
+                    ack_result(ack, res);
+                }).instrument(span)
             }
         }
     );
@@ -254,18 +330,56 @@ pub fn on_socket_connect(client_socket: SocketRef, db: &NearsayDB, key: &Hmac<Sh
         "sign-in-from-jwt",
         clone_into_closure_mut! {
             (db, key)
-            |client_socket: SocketRef, Data(SignInFromJWTData{jwt, pos}), ack: AckSender| async move {
-                
-                let Ok(JWTPayload { uid, .. }) = authenticate_jwt(&key, &jwt) else { return ack.send(&401).unwrap() };
-                
-                let Ok(Some(user)) = db.get::<User>("users", &uid).await else { return ack.send(&500).unwrap() };
-                
-                if let Some(pos) = pos {
-                    enter_world(&mut db, client_socket, &user._id, pos, user.avatar, Some(&user.username)).await.unwrap();
-                }
-                
-                ack.send( &json!({ "avatar": user.avatar, "username": user.username })).unwrap();
-                
+            |client_socket: SocketRef, Data(SignInFromJWTData{jwt, pos}), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "sign-in-from-jwt");
+                observe_socket_event("sign-in-from-jwt", async move {
+                    let res = async {
+                        let uid = Auth::<Members>::from_jwt(&mut db, &key, &jwt).await?.payload.uid;
+                        
+                        let user = db.get::<User>("users", &uid).await?.ok_or(NearsayError::NotFound("user"))?;
+                        
+                        if let Some(pos) = pos {
+                            enter_world(&mut db, client_socket, &user._id, pos, user.avatar, Some(&user.username)).await?;
+                        }
+                        
+                        Ok(json!({ "avatar": user.avatar, "username": user.username }))
+                    }.await;
+                    
+                    ack_result(ack, res);
+                }).instrument(span)
+            }
+        }
+    );
+
+    // exchange a refresh token for a new token pair
+    client_socket.on(
+        "refresh-token",
+        clone_into_closure_mut! {
+            (db, key)
+            |client_socket: SocketRef, Data(RefreshTokenData{ refresh_token }), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "refresh-token");
+                observe_socket_event("refresh-token", async move {
+                    ack_result(ack, refresh_tokens(&mut db, &key, &refresh_token).await);
+                }).instrument(span)
+            }
+        }
+    );
+
+    // revoke every token issued to this user, including the one used to call this
+    client_socket.on(
+        "sign-out-all",
+        clone_into_closure_mut! {
+            (db, key)
+            |client_socket: SocketRef, Data(SignOutAllData{ jwt }), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "sign-out-all");
+                observe_socket_event("sign-out-all", async move {
+                    let res = async {
+                        let uid = Auth::<Anyone>::from_jwt(&mut db, &key, &jwt).await?.payload.uid;
+                        db.revoke_tokens(&uid).await
+                    }.await;
+                    
+                    ack_result(ack, res);
+                }).instrument(span)
             }
         }
     );
@@ -274,18 +388,19 @@ pub fn on_socket_connect(client_socket: SocketRef, db: &NearsayDB, key: &Hmac<Sh
         "enter-world",
         clone_into_closure_mut! {
             (db, key)
-            |client_socket: SocketRef, Data(EnterWorldData{ jwt, pos }), ack: AckSender| async move {
-                let Ok(JWTPayload{uid}) = authenticate_jwt(&key, &jwt)
-                else { return ack.send(&401).unwrap() };
-                
-                match db.get::<User>("users", &uid).await {
-                    Err(()) => ack.send(&500).unwrap(),
-                    Ok(None) => ack.send(&404).unwrap(),
-                    Ok(Some(user)) => {
-                        enter_world(&mut db, client_socket, &uid, pos, user.avatar, Some(&user.username)).await.unwrap();
-                        ack.send(&()).unwrap();
-                    }
-                }
+            |client_socket: SocketRef, Data(EnterWorldData{ jwt, pos }), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "enter-world");
+                observe_socket_event("enter-world", async move {
+                    let res = async {
+                        let uid = Auth::<Members>::from_jwt(&mut db, &key, &jwt).await?.payload.uid;
+                        
+                        let user = db.get::<User>("users", &uid).await?.ok_or(NearsayError::NotFound("user"))?;
+                        
+                        enter_world(&mut db, client_socket, &uid, pos, user.avatar, Some(&user.username)).await
+                    }.await;
+                    
+                    ack_result(ack, res);
+                }).instrument(span)
             }
         }
     );
@@ -294,35 +409,37 @@ pub fn on_socket_connect(client_socket: SocketRef, db: &NearsayDB, key: &Hmac<Sh
         "exit-world",
         clone_into_closure_mut! {
             (db, key)
-            |client_socket: SocketRef, Data(ExitWorldData{jwt, stay_online, delete_account}), ack: AckSender| async move {
-                // get uid from jwt
-                let Ok(JWTPayload { uid }) = authenticate_jwt(&key, &jwt)
-                else { return ack.send(&500).unwrap() };
-
-                let ((x, y), avatar) = match db.get_cache_pos_and_avatar(&uid).await {
-                    Err(_) => return ack.send(&500).unwrap(),
-                    Ok(None) => return ack.send(&404).unwrap(),
-                    Ok(Some(vals)) => vals,
-                };
-                
-                let res = match delete_account {
-                    Some(true) =>   db.delete_user(&uid, Some(client_socket.id.as_str())).await,
-                    _ =>            db.delete_user_from_cache(Some(&uid),  client_socket.id.as_str()).await
-                };
-                if res.is_err() {
-                    return ack.send(&500).unwrap();
-                }
-                
-                broadcast_at(&client_socket, [x, y], "user-leave", false, &uid );
-                
-                // create a guest poi if stay_online == true
-                match stay_online {
-                    Some(true) => match enter_world_as_guest(&mut db, &key, client_socket, [x, y], avatar).await {
-                        Ok(jwt) => ack.send(&jwt).unwrap(),
-                        Err(_) => ack.send(&500).unwrap(),
-                    }
-                    _ => ack.send(&()).unwrap()
-                }
+            |client_socket: SocketRef, Data(ExitWorldData{jwt, stay_online, delete_account}), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "exit-world");
+                observe_socket_event("exit-world", async move {
+                    let res = async {
+                        // get uid from jwt
+                        let payload = Auth::<Anyone>::from_jwt(&mut db, &key, &jwt).await?.payload;
+                        let uid = payload.uid;
+                        
+                        // guests don't have an account to delete
+                        if delete_account == Some(true) && payload.role == Role::Guest {
+                            return Err(NearsayError::Forbidden);
+                        }
+                        
+                        let ((x, y), avatar) = db.get_cache_pos_and_avatar(&uid).await?.ok_or(NearsayError::NotFound("user"))?;
+                        
+                        match delete_account {
+                            Some(true) =>   db.delete_user(&uid, Some(client_socket.id.as_str())).await?,
+                            _ =>            db.delete_user_from_cache(Some(&uid),  client_socket.id.as_str()).await?
+                        };
+                        
+                        broadcast_at(&client_socket, [x, y], "user-leave", false, &uid );
+                        
+                        // create a guest poi if stay_online == true
+                        match stay_online {
+                            Some(true) => Ok(Some(enter_world_as_guest(&mut db, &key, client_socket, [x, y], avatar).await?)),
+                            _ => Ok(None)
+                        }
+                    }.await;
+                    
+                    ack_result(ack, res);
+                }).instrument(span)
             }
         }
     );
@@ -331,42 +448,75 @@ pub fn on_socket_connect(client_socket: SocketRef, db: &NearsayDB, key: &Hmac<Sh
         "view-shift",
         clone_into_closure_mut! {
             (db)
-            |client_socket: SocketRef, Data(ViewShiftData { uid, zoom, tile_layer, view}), ack: AckSender| async move {
-                
-                client_socket.leave_all().unwrap();
-                
-                if zoom < MIN_ZOOM_LEVEL || MAX_ZOOM_LEVEL < zoom { 
-                    return ack.send(&422).unwrap() 
-                }
-                
-                let mut resp = ViewShiftResponse::default();
-
-                for aligned_rect in view {
-                    if let Some(aligned_rect) = aligned_rect {
-                        if !aligned_rect.valid_as_view() { return ack.send(&422).unwrap() }
+            |client_socket: SocketRef, Data(ViewShiftData { uid, zoom, tile_layer, view, filter }), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "view-shift");
+                observe_socket_event("view-shift", async move {
+                    let res = async {
+                        if let Err(e) = client_socket.leave_all() {
+                            warn!(error = %e, "couldn't leave rooms");
+                        }
+                        
+                        if !(MIN_ZOOM_LEVEL..=MAX_ZOOM_LEVEL).contains(&zoom) { 
+                            return Err(NearsayError::validation("zoom", format!("must be within {MIN_ZOOM_LEVEL}..={MAX_ZOOM_LEVEL}")))
+                        }
                         
-                        join_rooms(&client_socket, tile_layer, &aligned_rect);
+                        let mut resp = ViewShiftResponse::default();
                         
-                        match db.geoquery_post_pts(zoom, &aligned_rect).await {
-                            Ok(post_pts) => resp.posts.extend(post_pts),
-                            Err(_) => { return ack.send(&500).unwrap() },
+                        for aligned_rect in view.into_iter().flatten() {
+                            if !aligned_rect.valid_as_view() { 
+                                return Err(NearsayError::validation("view", "must have top >= bottom, right >= left, and be within world bounds"))
+                            }
+                            
+                            join_rooms(&client_socket, tile_layer, &aligned_rect);
+                            
+                            resp.posts.extend(db.geoquery_post_pts(zoom, &aligned_rect, &filter).await?);
+                            resp.users.extend(db.geoquery_users(&aligned_rect).await?);
                         }
                         
-                        match db.geoquery_users(&aligned_rect).await {
-                            Ok(user_pts) => resp.users.extend(user_pts),
-                            Err(_) => { return ack.send(&500).unwrap() },
+                        // remove user of `uid` from result
+                        if let Some(uid) = uid {
+                            if let Some(i) = resp.users.iter().position(|u| u.id == uid) {
+                                resp.users.swap_remove(i);
+                            }
                         }
-                    }
-                }
-                
-                // remove user of `uid` from result
-                if let Some(uid) = uid {
-                    if let Some(i) = resp.users.iter().position(|u| u.id == uid) {
-                        resp.users.swap_remove(i);
-                    }
-                }
-                
-                ack.send( &json!(resp) ).unwrap();
+                        
+                        Ok(resp)
+                    }.await;
+                    
+                    ack_result(ack, res);
+                }).instrument(span)
+            }
+        }
+    );
+    
+    client_socket.on(
+        "search-posts",
+        clone_into_closure! {
+            (db)
+            |client_socket: SocketRef, Data(query): Data<SearchQuery>, ack: AckSender| {
+                let span = socket_event_span(&client_socket, "search-posts");
+                observe_socket_event("search-posts", async move {
+                    let res = db.search_posts(&query).await
+                        .map(|(results, next)| json!({ "results": results, "next": next.map(|next| next.to_string()) }));
+                    
+                    ack_result(ack, res);
+                }).instrument(span)
+            }
+        }
+    );
+    
+    client_socket.on(
+        "feed",
+        clone_into_closure! {
+            (db)
+            |client_socket: SocketRef, Data(query): Data<FeedQuery>, ack: AckSender| {
+                let span = socket_event_span(&client_socket, "feed");
+                observe_socket_event("feed", async move {
+                    let res = db.feed_posts(&query).await
+                        .map(|(posts, next)| json!({ "posts": posts, "next": next.map(|next| next.to_string()) }));
+                    
+                    ack_result(ack, res);
+                }).instrument(span)
             }
         }
     );
@@ -375,15 +525,26 @@ pub fn on_socket_connect(client_socket: SocketRef, db: &NearsayDB, key: &Hmac<Sh
         "move",
         clone_into_closure_mut! {
             (db, key)
-            |client_socket: SocketRef, Data(MoveData {jwt, pos})| async move {
-                let Ok(JWTPayload {uid, ..}) = authenticate_jwt(&key, &jwt) else { return };
-                
-                if let Ok(old_pos) = db.set_user_pos(&uid, &pos).await {
-                    broadcast_at_multiple(&client_socket, &[old_pos.into(), pos], "user-move", false, &json!({
-                        "id": uid,
-                        "pos": &pos as &[f64]
-                    }));
-                }
+            |client_socket: SocketRef, Data(MoveData {jwt, pos})| {
+                let span = socket_event_span(&client_socket, "move");
+                observe_socket_event("move", async move {
+                    let res = async {
+                        let uid = Auth::<Anyone>::from_jwt(&mut db, &key, &jwt).await?.payload.uid;
+                        
+                        let old_pos = db.set_user_pos(&uid, &pos).await?;
+                        
+                        broadcast_at_multiple(&client_socket, &[old_pos.into(), pos], "user-move", false, &json!({
+                            "id": uid,
+                            "pos": &pos as &[f64]
+                        }));
+                        
+                        Ok::<_, NearsayError>(())
+                    }.await;
+                    
+                    if let Err(nearsay_err) = res {
+                        warn!(error = %nearsay_err, "move failed");
+                    }
+                }).instrument(span)
             }
         }
     );
@@ -392,22 +553,27 @@ pub fn on_socket_connect(client_socket: SocketRef, db: &NearsayDB, key: &Hmac<Sh
         "edit-user",
         clone_into_closure_mut! {
             (db, key)
-            |client_socket: SocketRef, Data( EditUserData{ jwt, avatar, username }), ack: AckSender| async move {
-                let Ok(JWTPayload {uid, ..}) = authenticate_jwt(&key, &jwt) else { return };
-                
-                if let Err(nearsay_err) = db.edit_user(&uid, &avatar, &username).await {
-                    return ack.send(&nearsay_err.to_status_code()).unwrap();
-                }
-                
-                if let Ok(Some((pos, _))) = db.get_cache_pos_and_avatar(&uid).await {
-                    broadcast_at(&client_socket, pos.into(), "user-update", false, &json! ({
-                        "id": uid,
-                        "avatar": avatar,
-                        "username": username,
-                    }));
-                }
-
-                ack.send(&()).unwrap()
+            |client_socket: SocketRef, Data( EditUserData{ jwt, avatar, username, privacy, notify_on_save }), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "edit-user");
+                observe_socket_event("edit-user", async move {
+                    let res = async {
+                        let uid = Auth::<Members>::from_jwt(&mut db, &key, &jwt).await?.payload.uid;
+                        
+                        db.edit_user(&uid, &avatar, &username, &privacy, notify_on_save).await?;
+                        
+                        if let Some((pos, _)) = db.get_cache_pos_and_avatar(&uid).await? {
+                            broadcast_at(&client_socket, pos.into(), "user-update", false, &json! ({
+                                "id": uid,
+                                "avatar": avatar,
+                                "username": username,
+                            }));
+                        }
+                        
+                        Ok(())
+                    }.await;
+                    
+                    ack_result(ack, res);
+                }).instrument(span)
             }
         }
     );
@@ -416,28 +582,121 @@ pub fn on_socket_connect(client_socket: SocketRef, db: &NearsayDB, key: &Hmac<Sh
         "post",
         clone_into_closure_mut! {
             (db, key)
-            |client_socket: SocketRef, Data(NewPostData {jwt, pos, body})| async move {
-                let author_id_owned;
-                let author_id = match jwt {
-                    None => None,
-                    Some(jwt) => match authenticate_jwt(&key, &jwt) {
-                        Err(()) => return,
-                        Ok(JWTPayload {uid, ..}) => {
-                            author_id_owned = uid;
-                            Some(&author_id_owned[..])
+            |client_socket: SocketRef, Data(NewPostData {jwt, pos, body, category, starts_at, ends_at, lifetime_secs, attachments}), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "post");
+                observe_socket_event("post", async move {
+                    let res = async {
+                        if attachments.len() > MAX_ATTACHMENTS_PER_POST {
+                            return Err(NearsayError::validation("attachments", format!("must be at most {MAX_ATTACHMENTS_PER_POST}")));
                         }
-                    }
-                };
-                if let Ok((post_id, blurb)) = db.insert_post(author_id, &pos, &body).await {
+                        
+                        let author_id = match jwt {
+                            None => None,
+                            Some(jwt) => Some(Auth::<Anyone>::from_jwt(&mut db, &key, &jwt).await?.payload.uid),
+                        };
+                        
+                        let now = current_time_ms() as i64;
+                        let lifetime = PostLifetime::new(EventWindow::new(starts_at, ends_at, category, now)?, lifetime_secs)?;
+                        
+                        let (post_id, blurb) = db.insert_post(author_id.as_deref(), &pos, &body, category, lifetime, &attachments).await?;
+                        
+                        // events that haven't started yet show up on the next view shift after they do
+                        let on_map = match lifetime {
+                            PostLifetime::Event(event) => event.is_visible(now, db.config().event_lead_minutes as i64 * 60 * 1000),
+                            _ => true,
+                        };
+                        if on_map {
+                            broadcast_at(&client_socket, pos, "new-post", true,
+                                & json! ({
+                                    "id": post_id,
+                                    "pos": &pos as &[f64],
+                                    "blurb": blurb,
+                                    "category": category,
+                                    "tags": parse_hashtags(&body),
+                                    "startsAt": starts_at,
+                                    "endsAt": ends_at,
+                                })
+                            );
+                        }
+                        
+                        Ok(json!({ "id": post_id, "blurb": blurb }))
+                    }.await;
                     
-                    broadcast_at(&client_socket, pos, "new-post", true,
-                        & json! ({
+                    ack_result(ack, res);
+                }).instrument(span)
+            }
+        }
+    );
+    
+    // only the author can edit, so anonymous posts can't be edited
+    client_socket.on(
+        "edit-post",
+        clone_into_closure_mut! {
+            (db, key)
+            |client_socket: SocketRef, Data(EditPostData { jwt, post_id, body }), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "edit-post");
+                observe_socket_event("edit-post", async move {
+                    let res = async {
+                        if body.trim().is_empty() { return Err(NearsayError::validation("body", "must not be empty")) }
+                        
+                        let uid = Auth::<Anyone>::from_jwt(&mut db, &key, &jwt).await?.payload.uid;
+                        
+                        let (post, blurb) = db.edit_post(&post_id, &uid, &body).await?;
+                        
+                        broadcast_at(&client_socket, post.pos, "post-update", true, &json!({
                             "id": post_id,
-                            "pos": &pos as &[f64],
+                            "body": post.body,
                             "blurb": blurb,
-                        })
-                    );
-                }
+                        }));
+                        
+                        Ok(())
+                    }.await;
+                    
+                    ack_result(ack, res);
+                }).instrument(span)
+            }
+        }
+    );
+    
+    client_socket.on(
+        "save-post",
+        clone_into_closure_mut! {
+            (db, key)
+            |client_socket: SocketRef, io: SocketIo, Data(SavePostData { jwt, post_id }), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "save-post");
+                observe_socket_event("save-post", async move {
+                    let res = async {
+                        let uid = Auth::<Members>::from_jwt(&mut db, &key, &jwt).await?.payload.uid;
+                        
+                        if let Some((Some(author_id), saves)) = db.save_post(&uid, &post_id).await? {
+                            if author_id != uid {
+                                notify_post_saved(&io, &mut db, &author_id, &post_id, saves).await;
+                            }
+                        }
+                        
+                        Ok(())
+                    }.await;
+                    
+                    ack_result(ack, res);
+                }).instrument(span)
+            }
+        }
+    );
+    
+    client_socket.on(
+        "unsave-post",
+        clone_into_closure_mut! {
+            (db, key)
+            |client_socket: SocketRef, Data(SavePostData { jwt, post_id }), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "unsave-post");
+                observe_socket_event("unsave-post", async move {
+                    let res = async {
+                        let uid = Auth::<Members>::from_jwt(&mut db, &key, &jwt).await?.payload.uid;
+                        db.unsave_post(&uid, &post_id).await
+                    }.await;
+                    
+                    ack_result(ack, res);
+                }).instrument(span)
             }
         }
     );
@@ -446,44 +705,119 @@ pub fn on_socket_connect(client_socket: SocketRef, db: &NearsayDB, key: &Hmac<Sh
         "delete-post",
         clone_into_closure_mut! {
             (db, key)
-            |client_socket: SocketRef, Data(DeletePostData {jwt, post_id}), ack: AckSender| async move {
-                
-                let Ok(JWTPayload {uid, ..}) = authenticate_jwt(&key, &jwt) else { return ack.send(&401).unwrap(); };
-                
-                let Ok(Some(post)) = db.get::<Post>("posts", &post_id).await else { return ack.send(&404).unwrap(); };
-                
-                if post.authorId == Some(uid) {
+            |client_socket: SocketRef, Data(DeletePostData {jwt, post_id}), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "delete-post");
+                observe_socket_event("delete-post", async move {
+                    let res = async {
+                        let payload = Auth::<Anyone>::from_jwt(&mut db, &key, &jwt).await?.payload;
+                        
+                        let post = db.get::<Post>("posts", &post_id).await?.ok_or(NearsayError::NotFound("post"))?;
+                        
+                        // moderators can delete any post
+                        if post.authorId != Some(payload.uid) && payload.role != Role::Moderator {
+                            return Err(NearsayError::Forbidden);
+                        }
+                        
+                        db.delete_post(&post_id, post.category).await?;
+                        
+                        broadcast_at(&client_socket, post.pos, "post-delete", true, &post_id);
+                        
+                        Ok(())
+                    }.await;
                     
-                    if db.delete_post(&post_id).await.is_err() {
-                        return ack.send(&500).unwrap();
-                    };
+                    ack_result(ack, res);
+                }).instrument(span)
+            }
+        }
+    );
+
+    // comment on a post, or reply to one of its comments. acks the new comment
+    client_socket.on(
+        "comment",
+        clone_into_closure_mut! {
+            (db, key)
+            |client_socket: SocketRef, Data(NewCommentData { jwt, post_id, parent_id, body }), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "comment");
+                observe_socket_event("comment", async move {
+                    let res = async {
+                        if body.trim().is_empty() { return Err(NearsayError::validation("body", "must not be empty")) }
+                        
+                        let (author_id, author_is_member) = match jwt {
+                            None => (None, false),
+                            Some(jwt) => {
+                                let payload = Auth::<Anyone>::from_jwt(&mut db, &key, &jwt).await?.payload;
+                                (Some(payload.uid), payload.role != Role::Guest)
+                            },
+                        };
+                        
+                        let (comment, pos) = db.insert_comment(author_id.as_deref(), author_is_member, &post_id, parent_id.as_deref(), &body).await?;
+                        
+                        broadcast_at(&client_socket, pos, "new-comment", false, &comment);
+                        
+                        Ok(comment)
+                    }.await;
                     
-                    broadcast_at(&client_socket, post.pos, "post-delete", true, &post_id);
-                    ack.send(&()).unwrap();
-                }
-                else {
-                    ack.send(&401).unwrap();
-                }
-                
+                    ack_result(ack, res);
+                }).instrument(span)
+            }
+        }
+    );
+    
+    client_socket.on(
+        "delete-comment",
+        clone_into_closure_mut! {
+            (db, key)
+            |client_socket: SocketRef, Data(DeleteCommentData { jwt, comment_id }), ack: AckSender| {
+                let span = socket_event_span(&client_socket, "delete-comment");
+                observe_socket_event("delete-comment", async move {
+                    let res = async {
+                        let payload = Auth::<Anyone>::from_jwt(&mut db, &key, &jwt).await?.payload;
+                        
+                        let comment = db.get::<Comment>("comments", &comment_id).await?
+                            .filter(|comment| !comment.deleted)
+                            .ok_or(NearsayError::NotFound("comment"))?;
+                        
+                        // moderators can delete any comment
+                        if comment.authorId != Some(payload.uid) && payload.role != Role::Moderator {
+                            return Err(NearsayError::Forbidden);
+                        }
+                        
+                        if let Some(pos) = db.delete_comment(&comment).await? {
+                            broadcast_at(&client_socket, pos, "comment-delete", true, &json!({
+                                "id": comment._id,
+                                "post_id": comment.postId,
+                            }));
+                        }
+                        
+                        Ok(())
+                    }.await;
+                    
+                    ack_result(ack, res);
+                }).instrument(span)
             }
         }
     );
 
     client_socket.on(
         "chat",
-        clone_into_closure! {
-            (key)
-            |client_socket: SocketRef, Data(ChatData { jwt, msg, pos })| async move {
-                let Ok( JWTPayload{ uid } ) = authenticate_jwt(&key, &jwt)
-                else { return };
-
-                broadcast_at(&client_socket, pos, "chat", false,
-                    &json!({
-                        "id": uid,
-                        "msg": msg
-                    })
-                );
+        clone_into_closure_mut! {
+            (db, key)
+            |client_socket: SocketRef, Data(ChatData { jwt, msg, pos })| {
+                let span = socket_event_span(&client_socket, "chat");
+                observe_socket_event("chat", async move {
+                    let uid = match Auth::<Anyone>::from_jwt(&mut db, &key, &jwt).await {
+                        Ok(auth) => auth.payload.uid,
+                        Err(nearsay_err) => return warn!(error = %nearsay_err, "chat failed"),
+                    };
 
+                    broadcast_at(&client_socket, pos, "chat", false,
+                        &json!({
+                            "id": uid,
+                            "msg": msg
+                        })
+                    );
+
+                }).instrument(span)
             }
         }
     );
@@ -491,39 +825,116 @@ pub fn on_socket_connect(client_socket: SocketRef, db: &NearsayDB, key: &Hmac<Sh
     
     client_socket.on_disconnect(clone_into_closure_mut!(
         (db)
-        |client_socket: SocketRef| async move {
-            let socket_id = client_socket.id.as_str();
-            
-            if let Ok(Some(uid)) = db.get_uid_from_socket(socket_id).await {
-                if let Ok(Some(((x, y), _))) = db.get_cache_pos_and_avatar(&uid).await {
-                    db.delete_user_from_cache(Some(&uid), socket_id).await.unwrap();
-                    broadcast_at(&client_socket, [x, y], "user-leave", false, &uid );
+        |client_socket: SocketRef| {
+            let span = socket_event_span(&client_socket, "disconnect");
+            async move {
+                let socket_id = client_socket.id.as_str();
+                info!("socket disconnected");
+                metrics::CONNECTED_SOCKETS.dec();
+                
+                if let Ok(Some(uid)) = db.get_uid_from_socket(socket_id).await {
+                    record_uid(&uid);
+                    if let Ok(Some(((x, y), _))) = db.get_cache_pos_and_avatar(&uid).await {
+                        if let Err(e) = db.delete_user_from_cache(Some(&uid), socket_id).await {
+                            return warn!(error = %e, "couldn't remove user from cache");
+                        }
+                        broadcast_at(&client_socket, [x, y], "user-leave", false, &uid );
+                    }
                 }
-            }
+            }.instrument(span)
         }
     ));
 }
 
+/// sends `post-saved` to the author of a post if they asked for it and are on the map.
+/// the save already happened, so failing to notify is only logged
+async fn notify_post_saved(io: &SocketIo, db: &mut NearsayDB, author_id: &str, post_id: &str, saves: usize) {
+    let res = async {
+        let Some(author) = db.get::<User>("users", author_id).await? else { return Ok(()) };
+        if !author.notifyOnSave { return Ok(()) }
+        
+        let Some(author_socket) = db.get_socket_of_user(author_id).await?
+            .and_then(|socket_id| socket_id.parse().ok())
+            .and_then(|sid| io.get_socket(sid))
+        else { return Ok(()) };
+        
+        if let Err(e) = author_socket.emit("post-saved", &json!({ "id": post_id, "saves": saves })) {
+            warn!(error = %e, "couldn't emit post-saved");
+        }
+        Ok::<_, NearsayError>(())
+    }.await;
+    
+    if let Err(e) = res {
+        warn!(author_id, error = %e, "couldn't notify author of save");
+    }
+}
+
+/// tells every socket the server is going away, takes their users off the map, then disconnects them
+pub async fn notify_shutdown(io: &SocketIo, db: &mut NearsayDB) {
+    if let Err(e) = io.emit("server-shutdown", &()) {
+        warn!(error = %e, "couldn't notify sockets of shutdown");
+    }
+    
+    let sockets = io.sockets().unwrap_or_default();
+    info!(sockets = sockets.len(), "removing users of connected sockets");
+    
+    for client_socket in sockets {
+        let socket_id = client_socket.id.as_str();
+        
+        let res = async {
+            let uid = db.get_uid_from_socket(socket_id).await?;
+            db.delete_user_from_cache(uid.as_deref(), socket_id).await
+        }.await;
+        if let Err(e) = res {
+            warn!(socket_id, error = %e, "couldn't remove user of socket");
+        }
+    }
+    
+    if let Err(errs) = io.disconnect() {
+        warn!(errors = errs.len(), "couldn't disconnect every socket");
+    }
+}
+
 fn broadcast_at<T: Sized + Serialize>(io: &SocketRef, pos: [f64; 2], event: &str, include_self: bool, data: &T) {
     broadcast_at_multiple(io, &[pos], event, include_self, data);
 }
 
 fn broadcast_at_multiple<T: Sized + Serialize>(io: &SocketRef, pts: &[[f64; 2]], event: &str, include_self: bool, data: &T) {
-    // println!("\n start broadcasting", );
+    let targets = io.within(room_name(0, -WORLD_MAX_BOUND, -WORLD_MAX_BOUND)).to(tile_rooms_at(pts));
     
+    if let Err(e) = targets.emit(event, data) {
+        warn!(event, error = %e, "couldn't broadcast");
+    }
+    
+    if include_self {
+        if let Err(e) = io.emit(event, data) {
+            warn!(event, error = %e, "couldn't send to self");
+        }
+    }
+}
+
+/// like `broadcast_at`, for events that don't come from a client
+fn broadcast_from_server<T: Sized + Serialize>(io: &SocketIo, pos: [f64; 2], event: &str, data: &T) {
+    let targets = io.to(room_name(0, -WORLD_MAX_BOUND, -WORLD_MAX_BOUND)).to(tile_rooms_at(&[pos]));
+    
+    if let Err(e) = targets.emit(event, data) {
+        warn!(event, error = %e, "couldn't broadcast");
+    }
+}
+
+/// the room of the tile containing each pt, on every tile layer below the whole world
+fn tile_rooms_at(pts: &[[f64; 2]]) -> Vec<String> {
+    let mut rooms = vec![];
     
-    let mut targets = io.within(room_name(0, -(WORLD_MAX_BOUND as f64), -(WORLD_MAX_BOUND as f64)));
-    // println!("broadcasting {} to {}", event, room_name(0, area.left, area.bottom));
-        
     for [x, y] in pts {
         
         // println!("at pt {:?}", [x, y]);
         
         let mut area = Rect {
-            left: -(WORLD_MAX_BOUND as f64), // use WORLD_MAX_BOUND instead of WORLD_BOUND_X/Y bc tiles are square
-            right: WORLD_MAX_BOUND as f64, 
-            top: WORLD_MAX_BOUND as f64, 
-            bottom: -(WORLD_MAX_BOUND as f64)
+            left: -WORLD_MAX_BOUND, // use WORLD_MAX_BOUND instead of WORLD_BOUND_X/Y bc tiles are square
+            right: WORLD_MAX_BOUND, 
+            top: WORLD_MAX_BOUND, 
+            bottom: -WORLD_MAX_BOUND
         };
         
         for tile_layer in 1..=MAX_TILE_LAYER {
@@ -537,14 +948,27 @@ fn broadcast_at_multiple<T: Sized + Serialize>(io: &SocketRef, pts: &[[f64; 2]],
             if *y >= mid_y { area.bottom = mid_y; }
             else { area.top = mid_y; }
             
-            targets = targets.to(room_name(tile_layer, area.left, area.bottom));
-            // println!("broadcasting {} to {}", event, room_name(tile_layer, area.left, area.bottom));
+            rooms.push(room_name(tile_layer, area.left, area.bottom));
         }
     }
     
-    targets.emit(event, data).unwrap();
+    rooms
+}
+
+/// sends `post-delete` around every post the sweeper deletes, so clients see posts expire without moving the map
+pub fn broadcast_expired_posts(io: &SocketIo, db: &NearsayDB) {
+    let io = io.clone();
+    let mut expired_posts = db.subscribe_expired_posts();
     
-    if include_self { io.emit(event, data).unwrap(); }
+    tokio::spawn(async move {
+        loop {
+            match expired_posts.recv().await {
+                Ok(post) => broadcast_from_server(&io, post.pos, "post-delete", &post.id),
+                Err(RecvError::Lagged(missed)) => warn!(missed, "fell behind on expired posts, some weren't broadcast"),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
 }
 
 const SPLIT: &str = " : ";
@@ -568,7 +992,9 @@ pub fn join_rooms(client_socket: &SocketRef, tile_layer: usize, aligned_rect: &R
             );
             
             // println!("joined {}", room);
-            client_socket.join(room).unwrap();
+            if let Err(e) = client_socket.join(room) {
+                warn!(error = %e, "couldn't join room");
+            }
         }
     }
 }
@@ -579,4 +1005,156 @@ fn room_name(zoom_level: usize, left: f64, bottom: f64) -> String {
 
 fn to_5_decimals(x: f64) -> f64 {
     (x * 100000.0).round() / 100000.0
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::{to_bytes, Body}, http::Request, Router};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::{broadcast_expired_posts, notify_shutdown, room_name, tile_rooms_at};
+    use crate::{app, area::MAX_TILE_LAYER, auth::{guest_access_token, JWTKey}, db::{ExpiredPost, NearsayDB}, stand_ins::{cache_token_gen, failing_config, redis_config}};
+
+    /// a socket.io client speaking engine.io long-polling to the router
+    struct PollingClient {
+        router: Router,
+        sid: String,
+    }
+    impl PollingClient {
+        async fn connect(router: Router) -> Self {
+            let open = Self::request(&router, Request::get("/socket.io/?EIO=4&transport=polling").body(Body::empty()).unwrap()).await;
+            let handshake: Value = serde_json::from_str(open.strip_prefix('0').unwrap()).unwrap();
+            
+            let client = Self { router, sid: handshake["sid"].as_str().unwrap().to_string() };
+            client.send("40").await;
+            assert!(client.recv().await.starts_with("40"));
+            client
+        }
+        
+        async fn request(router: &Router, req: Request<Body>) -> String {
+            let res = router.clone().oneshot(req).await.unwrap();
+            String::from_utf8(to_bytes(res.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap()
+        }
+        
+        fn uri(&self) -> String {
+            format!("/socket.io/?EIO=4&transport=polling&sid={}", self.sid)
+        }
+        
+        async fn send(&self, packet: &str) {
+            Self::request(&self.router, Request::post(self.uri()).body(Body::from(packet.to_string())).unwrap()).await;
+        }
+        
+        async fn recv(&self) -> String {
+            Self::request(&self.router, Request::get(self.uri()).body(Body::empty()).unwrap()).await
+        }
+        
+        /// emits `event` with an ack id, and returns what was acked
+        async fn emit_with_ack(&self, ack_id: usize, event: &str, data: Value) -> Value {
+            self.send(&format!("42{ack_id}{}", json!([event, data]))).await;
+            
+            let ack = self.recv().await;
+            let ack = ack.strip_prefix(&format!("43{ack_id}")).unwrap_or_else(|| panic!("expected ack, got {ack:?}"));
+            serde_json::from_str::<Value>(ack).unwrap()[0].take()
+        }
+    }
+
+    #[tokio::test]
+    async fn handlers_ack_errors_when_redis_and_mongo_fail() {
+        let config = failing_config().await;
+        let db = NearsayDB::connect(&config).await.unwrap();
+        let (router, _) = app(&db, &JWTKey::new(&config).unwrap());
+        let client = PollingClient::connect(router).await;
+        
+        let view = json!({ "zoom": 4, "tile_layer": 2, "view": [{ "top": 90.0, "bottom": 0.0, "left": 0.0, "right": 90.0 }, null] });
+        
+        let ack = client.emit_with_ack(0, "view-shift", view.clone()).await;
+        assert_eq!("upstream_db", ack["code"]);
+        
+        let ack = client.emit_with_ack(1, "enter-world-as-guest", json!({ "pos": [0.0, 0.0], "avatar": 0 })).await;
+        assert_eq!("upstream_cache", ack["code"]);
+        
+        let ack = client.emit_with_ack(2, "sign-in", json!({ "username": "user", "password": "password" })).await;
+        assert_eq!("upstream_db", ack["code"]);
+        
+        // bad input is still rejected before touching the cache or db
+        let ack = client.emit_with_ack(3, "view-shift", json!({ "zoom": 100, "tile_layer": 2, "view": [null, null] })).await;
+        assert_eq!(json!({ "code": "validation", "message": "invalid zoom: must be within 3..=18", "details": { "field": "zoom" } }), ack);
+        
+        // the same socket keeps working after every failure
+        let ack = client.emit_with_ack(4, "view-shift", view).await;
+        assert_eq!("upstream_db", ack["code"]);
+        
+        let ack = client.emit_with_ack(5, "edit-post", json!({ "jwt": "jwt", "post_id": "post", "body": " " })).await;
+        assert_eq!("validation", ack["code"]);
+        
+        let ack = client.emit_with_ack(6, "edit-post", json!({ "jwt": "jwt", "post_id": "post", "body": "body" })).await;
+        assert_eq!("unauthorized", ack["code"]);
+        
+        let ack = client.emit_with_ack(7, "save-post", json!({ "jwt": "jwt", "post_id": "post" })).await;
+        assert_eq!("unauthorized", ack["code"]);
+        
+        let post = json!({ "pos": [0.0, 0.0], "body": "body", "starts_at": null, "ends_at": null, "lifetime_secs": 1 });
+        let ack = client.emit_with_ack(8, "post", post).await;
+        assert_eq!(json!({ "code": "validation", "message": "invalid lifetime_secs: must be within 3600..=2592000", "details": { "field": "lifetime_secs" } }), ack);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis, see `stand_ins::redis_config`"]
+    async fn guests_are_forbidden_from_member_events() {
+        let config = redis_config(11).await;
+        let db = NearsayDB::connect(&config).await.unwrap();
+        let key = JWTKey::new(&config).unwrap();
+        let (router, _) = app(&db, &key);
+        let client = PollingClient::connect(router).await;
+        
+        cache_token_gen(&config, "guest", 0).await;
+        let ack = client.emit_with_ack(0, "sign-in-from-jwt", json!({ "jwt": guest_access_token(&key, "guest"), "pos": null })).await;
+        assert_eq!("forbidden", ack["code"]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_notifies_and_disconnects_sockets() {
+        let config = failing_config().await;
+        let mut db = NearsayDB::connect(&config).await.unwrap();
+        let (router, io) = app(&db, &JWTKey::new(&config).unwrap());
+        let client = PollingClient::connect(router).await;
+        
+        // cleaning up presence fails against the failing redis, which mustn't stop the shutdown
+        notify_shutdown(&io, &mut db).await;
+        
+        let packets = client.recv().await;
+        assert!(packets.contains(r#"42["server-shutdown""#), "expected shutdown notice, got {packets:?}");
+        assert!(io.sockets().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_posts_are_deleted_for_clients_viewing_them() {
+        let config = failing_config().await;
+        let db = NearsayDB::connect(&config).await.unwrap();
+        let (router, io) = app(&db, &JWTKey::new(&config).unwrap());
+        broadcast_expired_posts(&io, &db);
+        let client = PollingClient::connect(router).await;
+        
+        // the view's rooms are joined before the failing db is queried
+        let view = json!({ "zoom": 4, "tile_layer": 2, "view": [{ "top": 90.0, "bottom": 0.0, "left": 0.0, "right": 90.0 }, null] });
+        client.emit_with_ack(0, "view-shift", view).await;
+        
+        db.send_expired_post(ExpiredPost { id: "elsewhere".to_string(), pos: [-100.0, -50.0] });
+        db.send_expired_post(ExpiredPost { id: "in-view".to_string(), pos: [10.0, 10.0] });
+        
+        let packets = client.recv().await;
+        assert!(packets.contains(r#"42["post-delete","in-view"]"#), "expected post-delete, got {packets:?}");
+        assert!(!packets.contains("elsewhere"));
+    }
+
+    #[test]
+    fn tile_rooms_narrow_down_to_the_pt() {
+        let rooms = tile_rooms_at(&[[10.0, -10.0]]);
+        
+        assert_eq!(rooms.len(), MAX_TILE_LAYER);
+        assert_eq!(rooms[0], room_name(1, 0.0, -180.0));
+        assert_eq!(rooms[1], room_name(2, 0.0, -90.0));
+        assert_eq!(tile_rooms_at(&[[10.0, -10.0], [-10.0, 10.0]]).len(), 2 * MAX_TILE_LAYER);
+    }
+}
@@ -0,0 +1,114 @@
+//! stand-ins for redis and mongodb that fail every request, for testing that errors are handled instead of panicking.
+//! also a config for the tests that need a real redis
+
+use std::env;
+
+use tokio::{io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}};
+
+use crate::config::Config;
+
+/// starts a server that accepts redis connections, then replies to every command with an error.
+/// returns its url
+pub async fn failing_redis() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("redis://{}", listener.local_addr().unwrap());
+    
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(reply_with_errors(stream));
+        }
+    });
+    
+    url
+}
+
+/// reads each command and replies with an error
+async fn reply_with_errors(stream: TcpStream) -> std::io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+    
+    while read_command(&mut read).await?.is_some() {
+        write.write_all(b"-ERR injected failure\r\n").await?;
+    }
+    Ok(())
+}
+
+/// reads a RESP command (an array of bulk strings), or `None` once the connection is closed
+async fn read_command(read: &mut (impl AsyncBufRead + Unpin)) -> std::io::Result<Option<Vec<Vec<u8>>>> {
+    let mut line = String::new();
+    
+    loop {
+        line.clear();
+        if read.read_line(&mut line).await? == 0 { return Ok(None) }
+        
+        let Some(arg_count) = line.trim_end().strip_prefix('*') else { continue };
+        
+        let mut args = vec![];
+        for _ in 0..arg_count.parse().unwrap_or(0) {
+            line.clear();
+            read.read_line(&mut line).await?;
+            
+            // read the arg and its trailing \r\n
+            let len: usize = line.trim_end().trim_start_matches('$').parse().unwrap_or(0);
+            let mut arg = vec![0; len + 2];
+            read.read_exact(&mut arg).await?;
+            arg.truncate(len);
+            args.push(arg);
+        }
+        
+        return Ok(Some(args))
+    }
+}
+
+/// nothing listens on port 1, so every operation fails once server selection times out
+pub const UNREACHABLE_MONGO_URI: &str = "mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100&connectTimeoutMS=100";
+
+/// a valid config whose caches and db fail every request
+pub async fn failing_config() -> Config {
+    let redis_url = failing_redis().await;
+    
+    Config {
+        jwt_secret: "secret".to_string(),
+        posts_redis_url: redis_url.clone(),
+        users_redis_url: redis_url,
+        mongo_uri: UNREACHABLE_MONGO_URI.to_string(),
+        ..Config::default()
+    }
+}
+
+/// a config whose caches are database `db` of the redis at `TEST_REDIS_URL` (`redis://127.0.0.1:6379` by default), 
+/// emptied first, and whose db fails every request. 
+/// each test gets its own database so they can run in parallel; tests using this are `#[ignore]`d, run them with `cargo test -- --ignored`
+pub async fn redis_config(db: u8) -> Config {
+    let server_url = env::var("TEST_REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    let redis_url = format!("{}/{db}", server_url.trim_end_matches('/'));
+    
+    let mut redis = redis::Client::open(redis_url.as_str()).unwrap().get_multiplexed_async_connection().await.unwrap();
+    let _: () = redis::cmd("FLUSHDB").query_async(&mut redis).await.unwrap();
+    
+    Config {
+        posts_redis_url: redis_url.clone(),
+        users_redis_url: redis_url,
+        ..failing_config().await
+    }
+}
+
+/// `redis_config`, with a real mongodb at `TEST_MONGO_URI` (`mongodb://127.0.0.1:27017` by default) too, 
+/// in a database named after `db` that's dropped first
+pub async fn mongo_config(db: u8) -> Config {
+    let config = Config {
+        mongo_uri: env::var("TEST_MONGO_URI").unwrap_or_else(|_| "mongodb://127.0.0.1:27017".to_string()),
+        mongo_db_name: format!("nearsay-test-{db}"),
+        ..redis_config(db).await
+    };
+    
+    mongodb::Client::with_uri_str(&config.mongo_uri).await.unwrap().database(&config.mongo_db_name).drop().await.unwrap();
+    
+    config
+}
+
+/// caches `gen` as `uid`'s token generation, like checking one of their tokens does, so it's checked without mongodb
+pub async fn cache_token_gen(config: &Config, uid: &str, gen: u64) {
+    let mut redis = redis::Client::open(config.users_redis_url.as_str()).unwrap().get_multiplexed_async_connection().await.unwrap();
+    let _: () = redis::AsyncCommands::set(&mut redis, format!("tokengen:{uid}"), gen).await.unwrap();
+}
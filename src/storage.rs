@@ -0,0 +1,109 @@
+use std::{io::ErrorKind, path::PathBuf};
+
+use nearsay_server::NearsayError;
+use tracing::error;
+
+use crate::config::{Config, StorageKind};
+
+/// where local attachments are served from
+pub const LOCAL_URL_PREFIX: &str = "/media";
+
+/// where attachment files are kept
+#[derive(Clone, Debug)]
+pub enum Storage {
+    /// files in `dir`, served by the http server under `LOCAL_URL_PREFIX`
+    Local { dir: PathBuf },
+    /// objects in an s3-compatible bucket, served from `public_url`
+    #[cfg(feature = "s3")]
+    S3 { bucket: Box<s3::Bucket>, public_url: String },
+}
+
+fn storage_err(e: impl std::fmt::Display) -> NearsayError {
+    error!(error = %e, "attachment storage error");
+    NearsayError::Internal
+}
+
+impl Storage {
+    pub fn new(config: &Config) -> Result<Self, NearsayError> {
+        match config.storage {
+            StorageKind::Local => Ok(Storage::Local { dir: PathBuf::from(&config.attachments_dir) }),
+
+            #[cfg(feature = "s3")]
+            StorageKind::S3 => {
+                let region = s3::Region::Custom { region: config.s3_region.clone(), endpoint: config.s3_endpoint.clone() };
+                let credentials = s3::creds::Credentials::new(Some(&config.s3_access_key), Some(&config.s3_secret_key), None, None, None).map_err(storage_err)?;
+
+                Ok(Storage::S3 {
+                    bucket: s3::Bucket::new(&config.s3_bucket, region, credentials).map_err(storage_err)?.with_path_style(),
+                    public_url: config.s3_public_url.trim_end_matches('/').to_string(),
+                })
+            },
+
+            // `Config::validate` rejects this
+            #[cfg(not(feature = "s3"))]
+            StorageKind::S3 => Err(storage_err("built without the s3 feature")),
+        }
+    }
+
+    pub async fn put(&self, key: &str, bytes: &[u8], content_type: &str) -> Result<(), NearsayError> {
+        match self {
+            Storage::Local { dir } => {
+                let _ = content_type;   // guessed from the extension when served
+                tokio::fs::create_dir_all(dir).await.map_err(storage_err)?;
+                tokio::fs::write(dir.join(key), bytes).await.map_err(storage_err)
+            },
+            #[cfg(feature = "s3")]
+            Storage::S3 { bucket, .. } => {
+                bucket.put_object_with_content_type(key, bytes, content_type).await.map_err(storage_err)?;
+                Ok(())
+            },
+        }
+    }
+
+    /// deleting something that doesn't exist isn't an error
+    pub async fn delete(&self, key: &str) -> Result<(), NearsayError> {
+        match self {
+            Storage::Local { dir } => match tokio::fs::remove_file(dir.join(key)).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(storage_err(e)),
+                _ => Ok(()),
+            },
+            #[cfg(feature = "s3")]
+            Storage::S3 { bucket, .. } => {
+                bucket.delete_object(key).await.map_err(storage_err)?;
+                Ok(())
+            },
+        }
+    }
+
+    pub fn url(&self, key: &str) -> String {
+        match self {
+            Storage::Local { .. } => format!("{LOCAL_URL_PREFIX}/{key}"),
+            #[cfg(feature = "s3")]
+            Storage::S3 { public_url, .. } => format!("{public_url}/{key}"),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+
+    use super::Storage;
+
+    #[tokio::test]
+    async fn local_put_and_delete() {
+        let dir = std::env::temp_dir().join(format!("nearsay-storage-test-{}", std::process::id()));
+        let storage = Storage::new(&Config { attachments_dir: dir.to_string_lossy().to_string(), ..Config::default() }).unwrap();
+
+        storage.put("a.png", b"png", "image/png").await.unwrap();
+        assert_eq!(b"png".to_vec(), std::fs::read(dir.join("a.png")).unwrap());
+        assert_eq!("/media/a.png", storage.url("a.png"));
+
+        storage.delete("a.png").await.unwrap();
+        assert!(!dir.join("a.png").exists());
+        storage.delete("a.png").await.unwrap();
+
+        std::fs::remove_dir(dir).unwrap();
+    }
+}
@@ -1,8 +1,14 @@
-use mongodb::bson::{doc, Document};
+use std::{fmt, str::FromStr};
+
+use mongodb::bson::{doc, document::ValueAccessError, Document};
+use nearsay_server::NearsayError;
 use serde::{Deserialize, Serialize};
 
+use crate::config::Config;
+
+#[allow(clippy::upper_case_acronyms)]
 pub trait POI {
-    fn get_poi_projection() -> Document;
+    fn get_poi_projection(config: &Config) -> Document;
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -13,38 +19,588 @@ pub struct Post {
 
     pub authorId: Option<String>,
     pub body: String,
+    #[serde(default)]
+    pub category: Category,
+    /// hashtags in `body`, see `parse_hashtags`
+    #[serde(default)]
+    pub tags: Vec<String>,
     pub likes: usize,
     pub dislikes: usize,
     pub views: usize,
-    pub expiry: usize,
+    /// ms since epoch. 0 for posts from before this was recorded
+    #[serde(default)]
+    pub createdAt: i64,
+    /// number of comments that haven't been deleted
+    #[serde(default)]
+    pub comments: usize,
+    /// number of users who saved it
+    #[serde(default)]
+    pub saves: usize,
+    /// ms since epoch, only set on event posts. see `EventWindow`
+    #[serde(default)]
+    pub startsAt: Option<i64>,
+    #[serde(default)]
+    pub endsAt: Option<i64>,
+    /// ms since epoch. the post is deleted once this passes
+    pub expiry: i64,
+    /// previous bodies, oldest first
+    #[serde(default)]
+    pub edits: Vec<PostEdit>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[allow(non_snake_case)]
+pub struct PostEdit {
+    /// the body before this edit
+    pub body: String,
+    /// ms since epoch
+    pub editedAt: i64,
 }
 impl POI for Post {
-    fn get_poi_projection() -> Document {
+    fn get_poi_projection(config: &Config) -> Document {
         doc! {
             "$project": {
                 "pos": 1,
-                "blurb": { "$substrCP": [ "$body", 0, BLURB_LENGTH as i32 ]},
+                "blurb": { "$substrCP": [ "$body", 0, config.blurb_length as i32 ]},
             }
         }
     }
 }
 
-pub const BLURB_LENGTH: usize = 25;
-pub fn get_blurb_from_body(post_body: &str) -> String {
-    if post_body.len() <= BLURB_LENGTH { post_body.to_string() } 
-    else { post_body[..BLURB_LENGTH].to_string() }
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Category {
+    #[default]
+    General,
+    Event,
+    Question,
+    Alert,
+    LostAndFound,
+}
+impl Category {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::General => "general",
+            Category::Event => "event",
+            Category::Question => "question",
+            Category::Alert => "alert",
+            Category::LostAndFound => "lost-and-found",
+        }
+    }
+}
+
+pub const DAY_MS: i64 = 24 * 60 * 60 * 1000;
+
+/// longest an event can last
+const MAX_EVENT_LENGTH_MS: i64 = 31 * DAY_MS;
+/// furthest ahead an event can be posted
+const MAX_EVENT_LEAD_MS: i64 = 365 * DAY_MS;
+
+/// when the event of an event post happens. 
+/// the post is only on the map from shortly before `starts_at` (`Config::event_lead_minutes`) until `ends_at`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EventWindow {
+    /// ms since epoch
+    pub starts_at: i64,
+    pub ends_at: i64,
+}
+impl EventWindow {
+    /// the window of a new post, or None if it isn't timed. only event posts can be timed
+    pub fn new(starts_at: Option<i64>, ends_at: Option<i64>, category: Category, now: i64) -> Result<Option<Self>, NearsayError> {
+        let (starts_at, ends_at) = match (starts_at, ends_at) {
+            (None, None) => return Ok(None),
+            (Some(starts_at), Some(ends_at)) => (starts_at, ends_at),
+            (None, Some(_)) => return Err(NearsayError::validation("starts_at", "must be set with ends_at")),
+            (Some(_), None) => return Err(NearsayError::validation("ends_at", "must be set with starts_at")),
+        };
+        
+        if category != Category::Event { 
+            return Err(NearsayError::validation("category", "must be event for posts with a start and end"));
+        }
+        if ends_at <= now { 
+            return Err(NearsayError::validation("ends_at", "must be in the future"));
+        }
+        if !(starts_at < ends_at && ends_at - starts_at <= MAX_EVENT_LENGTH_MS) {
+            return Err(NearsayError::validation("ends_at", format!("must be after starts_at, by at most {} days", MAX_EVENT_LENGTH_MS / DAY_MS)));
+        }
+        if starts_at - now > MAX_EVENT_LEAD_MS {
+            return Err(NearsayError::validation("starts_at", format!("must be within {} days", MAX_EVENT_LEAD_MS / DAY_MS)));
+        }
+        
+        Ok(Some(Self { starts_at, ends_at }))
+    }
+    
+    pub fn is_visible(&self, now: i64, lead_ms: i64) -> bool {
+        self.starts_at - lead_ms <= now && now < self.ends_at
+    }
+}
+
+/// how long a new post lasts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PostLifetime {
+    /// `Config::post_expiry_days`
+    Default,
+    /// chosen by the author, has to be within `Config::min_post_lifetime_secs..=max_post_lifetime_secs`
+    Secs(u64),
+    /// until the event ends
+    Event(EventWindow),
+}
+impl PostLifetime {
+    pub fn new(event: Option<EventWindow>, lifetime_secs: Option<u64>) -> Result<Self, NearsayError> {
+        match (event, lifetime_secs) {
+            (None, None) => Ok(PostLifetime::Default),
+            (None, Some(secs)) => Ok(PostLifetime::Secs(secs)),
+            (Some(event), None) => Ok(PostLifetime::Event(event)),
+            (Some(_), Some(_)) => Err(NearsayError::validation("lifetime_secs", "can't be set on event posts")),
+        }
+    }
+}
+
+/// which posts to show on the map. posts have to match every field that's set
+#[derive(Deserialize, Debug, Default)]
+pub struct PostFilter {
+    pub category: Option<Category>,
+    /// posts have to have all of these
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+impl PostFilter {
+    /// the `$match` conditions of this filter
+    pub fn as_mongo_filter(&self) -> Document {
+        let mut filter = doc! {};
+        match self.category {
+            // posts from before categories existed are general
+            Some(Category::General) => { filter.insert("category", doc! { "$in": ["general", null] }); },
+            Some(category) => { filter.insert("category", category.as_str()); },
+            None => {},
+        }
+        if !self.tags.is_empty() {
+            let tags: Vec<String> = self.tags.iter().map(|tag| tag.to_lowercase()).collect();
+            filter.insert("tags", doc! { "$all": tags });
+        }
+        filter
+    }
+}
+
+/// results per page of searches and feeds if `limit` isn't given
+const DEFAULT_PAGE_LIMIT: usize = 20;
+const MAX_PAGE_LIMIT: usize = 50;
+/// meters, if `radius` isn't given
+const DEFAULT_NEARBY_RADIUS: f64 = 5_000.0;
+const MAX_NEARBY_RADIUS: f64 = 100_000.0;
+const MAX_SEARCH_QUERY_LENGTH: usize = 200;
+
+/// returns (radius, limit), with defaults filled in
+fn validate_nearby(lat: f64, lng: f64, radius: Option<f64>, limit: Option<usize>) -> Result<(f64, usize), NearsayError> {
+    if !(-90.0..=90.0).contains(&lat) { return Err(NearsayError::validation("lat", "must be within -90..=90")) }
+    if !(-180.0..=180.0).contains(&lng) { return Err(NearsayError::validation("lng", "must be within -180..=180")) }
+    
+    let radius = radius.unwrap_or(DEFAULT_NEARBY_RADIUS);
+    if !(radius > 0.0 && radius <= MAX_NEARBY_RADIUS) {
+        return Err(NearsayError::validation("radius", format!("must be more than 0 and at most {MAX_NEARBY_RADIUS} meters")));
+    }
+    
+    let limit = limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if !(1..=MAX_PAGE_LIMIT).contains(&limit) {
+        return Err(NearsayError::validation("limit", format!("must be within 1..={MAX_PAGE_LIMIT}")));
+    }
+    
+    Ok((radius, limit))
+}
+
+/// text search over posts within `radius` meters of (`lng`, `lat`)
+#[derive(Deserialize, Debug)]
+pub struct SearchQuery {
+    pub q: String,
+    pub lat: f64,
+    pub lng: f64,
+    pub radius: Option<f64>,
+    pub limit: Option<usize>,
+    /// `next` of the previous page
+    pub after: Option<String>,
+}
+impl SearchQuery {
+    /// returns (radius, limit, after), with defaults filled in
+    pub fn validate(&self) -> Result<(f64, usize, Option<SearchCursor>), NearsayError> {
+        if self.q.trim().is_empty() || self.q.chars().count() > MAX_SEARCH_QUERY_LENGTH {
+            return Err(NearsayError::validation("q", format!("must be 1 to {MAX_SEARCH_QUERY_LENGTH} characters")));
+        }
+        let (radius, limit) = validate_nearby(self.lat, self.lng, self.radius, self.limit)?;
+        
+        let after = match &self.after {
+            None => None,
+            Some(after) => Some(after.parse().map_err(|_| NearsayError::validation("after", "must be the `next` of a previous page"))?),
+        };
+        
+        Ok((radius, limit, after))
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchResult {
+    pub id: String,
+    pub pos: [f64; 2],
+    pub blurb: String,
+    pub category: Category,
+    /// meters from the query point
+    pub distance: f64,
+    /// text relevance, higher is better
+    pub score: f64,
+}
+
+/// where a page of search results continues from. results are ordered by score (highest first), then id
+#[derive(Debug, PartialEq, Clone)]
+pub struct SearchCursor {
+    pub score: f64,
+    pub id: String,
+}
+impl fmt::Display for SearchCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.score, self.id)
+    }
+}
+impl FromStr for SearchCursor {
+    type Err = ();
+    
+    /// ids never contain a `.`, so the score is everything before the last one
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (score, id) = s.rsplit_once('.').ok_or(())?;
+        if id.is_empty() { return Err(()) }
+        
+        let score: f64 = score.parse().map_err(|_| ())?;
+        if !score.is_finite() { return Err(()) }
+        
+        Ok(Self { score, id: id.to_string() })
+    }
+}
+
+/// posts within `radius` meters of (`lng`, `lat`), ranked by `Config`'s feed weights
+#[derive(Deserialize, Debug)]
+pub struct FeedQuery {
+    pub lat: f64,
+    pub lng: f64,
+    pub radius: Option<f64>,
+    pub limit: Option<usize>,
+    /// `next` of the previous page
+    pub after: Option<String>,
+}
+impl FeedQuery {
+    /// returns (radius, limit, after), with defaults filled in
+    pub fn validate(&self) -> Result<(f64, usize, Option<FeedCursor>), NearsayError> {
+        let (radius, limit) = validate_nearby(self.lat, self.lng, self.radius, self.limit)?;
+        
+        let after = match &self.after {
+            None => None,
+            Some(after) => Some(after.parse().map_err(|_| NearsayError::validation("after", "must be the `next` of a previous page"))?),
+        };
+        
+        Ok((radius, limit, after))
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[allow(non_snake_case)]
+pub struct FeedItem {
+    pub id: String,
+    pub pos: [f64; 2],
+    pub blurb: String,
+    pub category: Category,
+    pub likes: usize,
+    pub dislikes: usize,
+    pub views: usize,
+    pub comments: usize,
+    pub createdAt: i64,
+    /// meters from the query point
+    pub distance: f64,
+    /// rank in the feed, higher is better
+    pub score: f64,
+}
+
+/// where a page of the feed continues from. 
+/// 
+/// scores depend on the posts' ages, so they're all computed as of `at` (when the first page was requested) to keep pages from overlapping
+#[derive(Debug, PartialEq, Clone)]
+pub struct FeedCursor {
+    /// ms since epoch
+    pub at: i64,
+    pub score: f64,
+    pub id: String,
+}
+impl fmt::Display for FeedCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.at, SearchCursor { score: self.score, id: self.id.clone() })
+    }
+}
+impl FromStr for FeedCursor {
+    type Err = ();
+    
+    /// `at` is an integer, so it ends at the first `.`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (at, rest) = s.split_once('.').ok_or(())?;
+        let at = at.parse().map_err(|_| ())?;
+        let SearchCursor { score, id } = rest.parse()?;
+        
+        Ok(Self { at, score, id })
+    }
+}
+
+/// a post keeps at most this many hashtags
+pub const MAX_TAGS: usize = 10;
+const MAX_TAG_LENGTH: usize = 50;
+
+/// lowercased `#hashtags` in `body`, without the `#`, deduplicated, in order of first appearance.
+/// a hashtag is a `#` followed by letters, digits, and underscores
+pub fn parse_hashtags(body: &str) -> Vec<String> {
+    let mut tags: Vec<String> = vec![];
+    
+    for (i, _) in body.match_indices('#') {
+        // `#` in the middle of a word (e.g. "c#") doesn't start a hashtag
+        if body[..i].chars().next_back().is_some_and(|c| c.is_alphanumeric() || c == '_') { continue }
+        
+        let tag: String = body[i + 1..].chars().take_while(|c| c.is_alphanumeric() || *c == '_').collect::<String>().to_lowercase();
+        
+        if !tag.is_empty() && tag.chars().count() <= MAX_TAG_LENGTH && !tags.contains(&tag) {
+            tags.push(tag);
+            if tags.len() == MAX_TAGS { break }
+        }
+    }
+    
+    tags
+}
+
+/// an uploaded image. it's deleted along with its post, or by the cleanup if it's never posted
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[allow(non_snake_case)]
+pub struct Attachment {
+    pub _id: String,
+    /// who uploaded it. only they can post it
+    pub uid: String,
+    /// `None` until it's posted
+    pub postId: Option<String>,
+
+    pub contentType: String,
+    pub width: u32,
+    pub height: u32,
+    /// storage keys of the image and its thumbnail
+    pub key: String,
+    pub thumbnailKey: String,
+    pub url: String,
+    pub thumbnailUrl: String,
+    /// ms since epoch
+    pub uploadedAt: i64,
+}
+
+/// what anyone viewing a post sees of its attachments. who uploaded them and where they're stored stays private
+#[derive(Serialize, Debug)]
+#[allow(non_snake_case)]
+pub struct PublicAttachment {
+    pub url: String,
+    pub thumbnailUrl: String,
+    pub width: u32,
+    pub height: u32,
+}
+impl From<&Attachment> for PublicAttachment {
+    fn from(attachment: &Attachment) -> Self {
+        Self {
+            url: attachment.url.clone(),
+            thumbnailUrl: attachment.thumbnailUrl.clone(),
+            width: attachment.width,
+            height: attachment.height,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[allow(non_snake_case)]
+pub struct Comment {
+    pub _id: String,
+    pub postId: String,
+    /// the comment this replies to, `None` if it's a top-level comment
+    pub parentId: Option<String>,
+
+    pub authorId: Option<String>,
+    pub body: String,
+    /// ms since epoch
+    pub createdAt: i64,
+    /// number of direct replies
+    pub replies: usize,
+    /// deleted comments with replies are kept, without author or body, so the thread stays intact
+    #[serde(default)]
+    pub deleted: bool,
 }
 
+/// ms added to a post's expiry by each view of it
+pub const VIEW_LIFETIME_WEIGHT: i64 = DAY_MS;
+
+/// ms added to a post's expiry by the first comment of each member on it
+pub const COMMENT_LIFETIME_WEIGHT: i64 = DAY_MS;
+
+/// ms added to a post's expiry by each user who saved it
+pub const SAVE_LIFETIME_WEIGHT: i64 = 2 * DAY_MS;
+
+/// saved posts per page if `limit` isn't given
+pub const DEFAULT_SAVED_LIMIT: usize = 20;
+pub const MAX_SAVED_LIMIT: usize = 100;
 
+/// a post a user saved, see `NearsayDB::get_saved_posts`
 #[derive(Serialize, Deserialize, Debug)]
+#[allow(non_snake_case)]
+pub struct SavedPost {
+    /// ms since epoch
+    pub savedAt: i64,
+    #[serde(flatten)]
+    pub post: PostSummary,
+}
+
+/// where a page of saved posts continues from. they're ordered by when they were saved, then post id, newest first
+#[derive(Debug, PartialEq, Clone)]
+pub struct SavedCursor {
+    pub saved_at: i64,
+    pub post_id: String,
+}
+impl From<&SavedPost> for SavedCursor {
+    fn from(saved: &SavedPost) -> Self {
+        Self { saved_at: saved.savedAt, post_id: saved.post.id.clone() }
+    }
+}
+impl fmt::Display for SavedCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.saved_at, self.post_id)
+    }
+}
+impl FromStr for SavedCursor {
+    type Err = ();
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (saved_at, post_id) = s.split_once('.').ok_or(())?;
+        if post_id.is_empty() { return Err(()) }
+        
+        Ok(Self { saved_at: saved_at.parse().map_err(|_| ())?, post_id: post_id.to_string() })
+    }
+}
+
+/// where a page of comments continues from. comments are ordered by creation time, then id
+#[derive(Debug, PartialEq, Clone)]
+pub struct CommentCursor {
+    pub created_at: i64,
+    pub id: String,
+}
+impl From<&Comment> for CommentCursor {
+    fn from(comment: &Comment) -> Self {
+        Self { created_at: comment.createdAt, id: comment._id.clone() }
+    }
+}
+impl fmt::Display for CommentCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.created_at, self.id)
+    }
+}
+impl FromStr for CommentCursor {
+    type Err = ();
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (created_at, id) = s.split_once('.').ok_or(())?;
+        if id.is_empty() { return Err(()) }
+        
+        Ok(Self { created_at: created_at.parse().map_err(|_| ())?, id: id.to_string() })
+    }
+}
+
+/// first `blurb_length` characters of `post_body`
+pub fn get_blurb_from_body(post_body: &str, blurb_length: usize) -> String {
+    post_body.chars().take(blurb_length).collect()
+}
+
+
+#[derive(Serialize, Deserialize, Debug)]
+#[allow(non_snake_case)]
 pub struct User {
     pub _id: String,
     pub username: String,
     pub avatar: usize,
     pub hash: String,
+    #[serde(default)]
+    pub role: Role,
+    /// ms since epoch. 0 for accounts from before this was recorded
+    #[serde(default)]
+    pub joinedAt: i64,
+    /// ms since epoch of when the user last entered or left the map
+    #[serde(default)]
+    pub lastSeen: i64,
+    #[serde(default)]
+    pub privacy: Privacy,
+    /// whether to get a `post-saved` event when someone saves one of their posts
+    #[serde(default)]
+    pub notifyOnSave: bool,
+}
+
+/// what a user hides from others on their profile
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[allow(non_snake_case)]
+pub struct Privacy {
+    #[serde(default)]
+    pub hidePosts: bool,
+    #[serde(default)]
+    pub hideLastSeen: bool,
+}
+
+/// most posts shown on a profile
+pub const MAX_PROFILE_POSTS: usize = 50;
+
+/// a user's active posts and stats, as seen by `viewer`
+#[derive(Serialize, Debug)]
+#[allow(non_snake_case)]
+pub struct Profile {
+    pub id: String,
+    pub username: String,
+    pub avatar: usize,
+    pub joinedAt: i64,
+    /// None if hidden
+    pub lastSeen: Option<i64>,
+    /// total likes of their active posts
+    pub likesReceived: usize,
+    /// most recent first. None if hidden
+    pub posts: Option<Vec<PostSummary>>,
+    /// only shown to the user themselves
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub privacy: Option<Privacy>,
+}
+
+/// a post in a list, like on a profile or in saved posts
+#[derive(Serialize, Deserialize, Debug)]
+#[allow(non_snake_case)]
+pub struct PostSummary {
+    #[serde(rename(deserialize = "_id"))]
+    pub id: String,
+    pub pos: [f64; 2],
+    #[serde(default)]
+    pub category: Category,
+    pub blurb: String,
+    pub likes: usize,
+    pub dislikes: usize,
+    pub views: usize,
+    #[serde(default)]
+    pub comments: usize,
+    #[serde(default)]
+    pub saves: usize,
+    #[serde(default)]
+    pub createdAt: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// not signed up, only exists in the users cache
+    Guest,
+    /// accounts without a stored role are members
+    #[default]
+    Member,
+    Moderator,
 }
 impl POI for User {
-    fn get_poi_projection() -> Document {
+    fn get_poi_projection(_config: &Config) -> Document {
         doc! {
             "$project": {
                 "pos": 1,
@@ -65,13 +621,15 @@ pub struct Vote {
     pub kind: VoteKind
 }
 
-impl From<Document> for Vote {
-    fn from(document: Document) -> Self {
-        Self {
-            post_id: document.get_str("post_id").unwrap().to_string(),   // rename _id -> post_id
-            uid: document.get_str("uid").unwrap().to_string(),
-            kind: VoteKind::from_str(document.get_str("kind").unwrap()),             // convert to `VoteKind`
-        }
+impl TryFrom<Document> for Vote {
+    type Error = ValueAccessError;
+    
+    fn try_from(document: Document) -> Result<Self, Self::Error> {
+        Ok(Self {
+            post_id: document.get_str("postId")?.to_string(),    // rename postId -> post_id
+            uid: document.get_str("uid")?.to_string(),
+            kind: VoteKind::from_str(document.get_str("kind")?),             // convert to `VoteKind`
+        })
     }
 }
 
@@ -79,12 +637,12 @@ impl From<Document> for Vote {
 pub enum VoteKind { Like, Dislike, None }
 
 impl VoteKind {
-    /// number of days added/subtracted from post expiry as a result of this vote
-    pub fn get_lifetime_weight(&self) -> i32 {
+    /// ms added/subtracted from post expiry as a result of this vote
+    pub fn get_lifetime_weight(&self) -> i64 {
         match self {
             VoteKind::None => 0,
-            VoteKind::Like => 2,
-            VoteKind::Dislike => -1
+            VoteKind::Like => 2 * DAY_MS,
+            VoteKind::Dislike => -DAY_MS
         }
     }
 
@@ -103,4 +661,131 @@ impl VoteKind {
             _ => VoteKind::None
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{doc, from_document};
+
+    use nearsay_server::NearsayError;
+
+    use super::{parse_hashtags, Attachment, Category, CommentCursor, EventWindow, FeedCursor, PostLifetime, PublicAttachment, SavedCursor, SavedPost, SearchCursor, MAX_TAGS};
+
+    #[test]
+    fn comment_cursor_round_trip() {
+        let cursor = CommentCursor { created_at: 1700000000000, id: "a-b_c".to_string() };
+        assert_eq!(Ok(cursor.clone()), cursor.to_string().parse());
+        
+        for bad in ["", "1700000000000", "1700000000000.", "abc.id", ".id"] {
+            assert_eq!(Err(()), bad.parse::<CommentCursor>(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn saved_cursor_round_trip() {
+        let cursor = SavedCursor { saved_at: 1700000000000, post_id: "a-b_c".to_string() };
+        assert_eq!(Ok(cursor.clone()), cursor.to_string().parse());
+        
+        for bad in ["", "1700000000000", "1700000000000.", "abc.id", ".id"] {
+            assert_eq!(Err(()), bad.parse::<SavedCursor>(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn search_cursor_round_trip() {
+        for score in [0.75, 1.0, 12.345678901234567] {
+            let cursor = SearchCursor { score, id: "a-b_c".to_string() };
+            assert_eq!(Ok(cursor.clone()), cursor.to_string().parse());
+        }
+        
+        for bad in ["", "15", "1.5.", "high.id", "NaN.id", "inf.id"] {
+            assert_eq!(Err(()), bad.parse::<SearchCursor>(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn feed_cursor_round_trip() {
+        for score in [-3.25, 0.0, 12.345678901234567] {
+            let cursor = FeedCursor { at: 1700000000000, score, id: "a-b_c".to_string() };
+            assert_eq!(Ok(cursor.clone()), cursor.to_string().parse());
+        }
+        
+        for bad in ["", "1700000000000", "1700000000000.1.", "1.id", "now.1.id", "1700000000000.NaN.id"] {
+            assert_eq!(Err(()), bad.parse::<FeedCursor>(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn saved_post_from_lookup() {
+        let saved: SavedPost = from_document(doc! {
+            "_id": "post", "pos": [1.5, 2.5], "category": "event", "blurb": "hi",
+            "likes": 3, "dislikes": 0, "views": 10, "savedAt": 1700000000000_i64,
+        }).unwrap();
+        
+        assert_eq!(1700000000000, saved.savedAt);
+        assert_eq!("post", saved.post.id);
+        assert_eq!(Category::Event, saved.post.category);
+        assert_eq!(0, saved.post.saves);
+    }
+
+    #[test]
+    fn event_windows() {
+        const HOUR: i64 = 60 * 60 * 1000;
+        let now = 1700000000000;
+        
+        assert!(matches!(EventWindow::new(None, None, Category::Event, now), Ok(None)));
+        
+        let window = EventWindow::new(Some(now + 2 * HOUR), Some(now + 5 * HOUR), Category::Event, now).unwrap().unwrap();
+        assert!(!window.is_visible(now, HOUR));
+        assert!(window.is_visible(now + HOUR, HOUR));
+        assert!(window.is_visible(now + 4 * HOUR, HOUR));
+        assert!(!window.is_visible(now + 5 * HOUR, HOUR));
+        
+        for (starts_at, ends_at, category, field) in [
+            (Some(now), None, Category::Event, "ends_at"),
+            (Some(now), Some(now + HOUR), Category::General, "category"),
+            (Some(now - 2 * HOUR), Some(now - HOUR), Category::Event, "ends_at"),
+            (Some(now + 2 * HOUR), Some(now + HOUR), Category::Event, "ends_at"),
+            (Some(now + 400 * 24 * HOUR), Some(now + 401 * 24 * HOUR), Category::Event, "starts_at"),
+        ] {
+            match EventWindow::new(starts_at, ends_at, category, now) {
+                Err(NearsayError::Validation { field: f, .. }) => assert_eq!(field, f),
+                other => panic!("expected a validation error on {field}, got {other:?}"),
+            }
+        }
+        
+        assert!(matches!(PostLifetime::new(Some(window), None), Ok(PostLifetime::Event(_))));
+        assert!(matches!(PostLifetime::new(Some(window), Some(3600)), Err(NearsayError::Validation { field: "lifetime_secs", .. })));
+    }
+
+    #[test]
+    fn hashtags() {
+        assert_eq!(vec!["lost", "cat_2", "café"], parse_hashtags("#Lost my #cat_2! near the #café, #lost again"));
+        assert_eq!(Vec::<String>::new(), parse_hashtags("c# and ## and # alone"));
+        
+        let many: String = (0..20).map(|i| format!("#tag{i} ")).collect();
+        assert_eq!(MAX_TAGS, parse_hashtags(&many).len());
+    }
+
+    #[test]
+    fn public_attachment_hides_uploader_and_keys() {
+        let attachment = Attachment {
+            _id: "a".to_string(),
+            uid: "uploader".to_string(),
+            postId: Some("post".to_string()),
+            contentType: "image/png".to_string(),
+            width: 640,
+            height: 480,
+            key: "a.png".to_string(),
+            thumbnailKey: "a-thumb.png".to_string(),
+            url: "/files/a.png".to_string(),
+            thumbnailUrl: "/files/a-thumb.png".to_string(),
+            uploadedAt: 0,
+        };
+        
+        assert_eq!(
+            serde_json::json!({ "url": "/files/a.png", "thumbnailUrl": "/files/a-thumb.png", "width": 640, "height": 480 }),
+            serde_json::to_value(PublicAttachment::from(&attachment)).unwrap()
+        );
+    }
+}